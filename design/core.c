@@ -1,33 +1,396 @@
 
-#define INTERRUPT(vector) void vector(void)
+#include <string.h>
 
-typedef struct void (*TaskFn)(void *this);
+#include "core.h"
 
-typedef struct _Continuation {
-    TaskFn function;
-    void *context;
-} Continuation;
-
-typedef struct _TaskStateCore {
-    Continuation continuesWith;
-} TaskStateCore;
+typedef struct _ReadyQueue {
+    Continuation ready[READY_QUEUE_SIZE];
+    volatile unsigned head;
+    volatile unsigned count;
+} ReadyQueue;
 
 static struct {
+    ReadyQueue queues[PRIORITY_LEVELS];
+    /* pending timers, the earliest deadline first */
+    Timer *timers;
+    volatile uint32_t ticks;
 } system_state;
 
-void Continuation_init(Continuation *this, TaskFn function, void *context) {
+void Continuation_init(Continuation *this, TaskFn function, void *context, uint8_t priority) {
     this->function = function;
     this->context = context;
+    this->priority = priority;
 }
 
 void Continuation_invoke(Continuation this) {
     this.function(this.context);
 }
 
+void Continuation_schedule(Continuation this) {
+    ReadyQueue *queue;
+    bool full;
+
+    if (this.priority >= PRIORITY_LEVELS) {
+        this.priority = PRIORITY_LEVELS - 1;
+    }
+    queue = &system_state.queues[this.priority];
+
+    CRITICAL_ENTER();
+    full = queue->count == READY_QUEUE_SIZE;
+    if (!full) {
+        unsigned tail = (queue->head + queue->count) % READY_QUEUE_SIZE;
+        queue->ready[tail] = this;
+        queue->count++;
+    }
+    CRITICAL_EXIT();
+
+    if (full) {
+        schedule_failed();
+    }
+}
+
+Continuation Continuation_take(Continuation *this) {
+    Continuation taken;
+
+    CRITICAL_ENTER();
+    taken = *this;
+#ifdef CONTINUATION_DEBUG
+    this->function = NULL;
+#endif
+    CRITICAL_EXIT();
+
+#ifdef CONTINUATION_DEBUG
+    if (taken.function == NULL) {
+        CONTINUATION_TRAP();
+    }
+#endif
+    return taken;
+}
+
+void Scheduler_run(void) {
+    for (;;) {
+        Continuation next;
+        ReadyQueue *queue;
+        int priority;
+
+        CRITICAL_ENTER();
+        for (priority = PRIORITY_LEVELS - 1; priority >= 0; priority--) {
+            if (system_state.queues[priority].count > 0) {
+                break;
+            }
+        }
+        if (priority < 0) {
+            CRITICAL_EXIT();
+            return;
+        }
+        queue = &system_state.queues[priority];
+        next = queue->ready[queue->head];
+        queue->head = (queue->head + 1) % READY_QUEUE_SIZE;
+        queue->count--;
+        CRITICAL_EXIT();
+
+        Continuation_invoke(next);
+    }
+}
+
+void Task_cancel(TaskStateCore *task) {
+    TaskFn cancel = task->cancel;
+
+    task->cancel = NULL;
+    task->cancelled = true;
+    if (cancel != NULL) {
+        cancel(task);
+    }
+}
+
+/* Pops the first waiter of a list, or returns NULL. */
+static Waiter *Waiter_pop(Waiter **list) {
+    Waiter *first = *list;
+
+    if (first != NULL) {
+        *list = first->next;
+    }
+    return first;
+}
+
+static void Waiter_push(Waiter **list, Waiter *waiter) {
+    waiter->next = NULL;
+    while (*list != NULL) {
+        list = &(*list)->next;
+    }
+    *list = waiter;
+}
+
+static bool Waiter_remove(Waiter **list, Waiter *waiter) {
+    while (*list != NULL) {
+        if (*list == waiter) {
+            *list = waiter->next;
+            return true;
+        }
+        list = &(*list)->next;
+    }
+    return false;
+}
+
+/* Stores a value or hands it to a receiver. Returns the receiver to resume,
+   or `core` itself when the value was buffered, or NULL when full. Must be
+   called in a critical section. */
+static void *Channel_put(ChannelCore *core, void *buffer, size_t size, unsigned capacity, const void *value) {
+    ChannelWaiter *receiver = (ChannelWaiter *)Waiter_pop(&core->receivers);
+
+    if (receiver != NULL) {
+        memcpy(receiver->value, value, size);
+        return receiver;
+    }
+    if (core->count < capacity) {
+        unsigned tail = (core->head + core->count) % capacity;
+        memcpy((char *)buffer + tail * size, value, size);
+        core->count++;
+        return core;
+    }
+    return NULL;
+}
+
+void Channel_send(ChannelCore *core, void *buffer, size_t size, unsigned capacity,
+                  ChannelWaiter *waiter, const void *value, Continuation resume) {
+    void *put;
+
+    CRITICAL_ENTER();
+    put = Channel_put(core, buffer, size, capacity, value);
+    if (put == NULL) {
+        waiter->waiter.resume = resume;
+        waiter->value = (void *)value;
+        Waiter_push(&core->senders, &waiter->waiter);
+    }
+    CRITICAL_EXIT();
+
+    if (put == NULL) {
+        return;
+    }
+    if (put != core) {
+        Continuation_schedule(((ChannelWaiter *)put)->waiter.resume);
+    }
+    Continuation_schedule(resume);
+}
+
+void Channel_recv(ChannelCore *core, void *buffer, size_t size, unsigned capacity,
+                  ChannelWaiter *waiter, void *into, Continuation resume) {
+    ChannelWaiter *sender = NULL;
+    bool received = false;
+
+    CRITICAL_ENTER();
+    if (core->count > 0) {
+        memcpy(into, (char *)buffer + core->head * size, size);
+        core->head = (core->head + 1) % capacity;
+        core->count--;
+        received = true;
+
+        sender = (ChannelWaiter *)Waiter_pop(&core->senders);
+        if (sender != NULL) {
+            Channel_put(core, buffer, size, capacity, sender->value);
+        }
+    } else {
+        waiter->waiter.resume = resume;
+        waiter->value = into;
+        Waiter_push(&core->receivers, &waiter->waiter);
+    }
+    CRITICAL_EXIT();
+
+    if (!received) {
+        return;
+    }
+    if (sender != NULL) {
+        Continuation_schedule(sender->waiter.resume);
+    }
+    Continuation_schedule(resume);
+}
+
+bool Channel_try_send(ChannelCore *core, void *buffer, size_t size, unsigned capacity, const void *value) {
+    void *put;
+
+    CRITICAL_ENTER();
+    put = Channel_put(core, buffer, size, capacity, value);
+    CRITICAL_EXIT();
+
+    if (put != NULL && put != core) {
+        Continuation_schedule(((ChannelWaiter *)put)->waiter.resume);
+    }
+    return put != NULL;
+}
+
+void Channel_cancel(ChannelCore *core, ChannelWaiter *waiter) {
+    CRITICAL_ENTER();
+    if (!Waiter_remove(&core->senders, &waiter->waiter)) {
+        Waiter_remove(&core->receivers, &waiter->waiter);
+    }
+    CRITICAL_EXIT();
+}
+
+void Mutex_lock(MutexCore *this, Waiter *waiter, Continuation resume) {
+    bool locked;
+
+    CRITICAL_ENTER();
+    locked = !this->locked;
+    if (locked) {
+        this->locked = true;
+    } else {
+        waiter->resume = resume;
+        Waiter_push(&this->waiters, waiter);
+    }
+    CRITICAL_EXIT();
+
+    if (locked) {
+        Continuation_schedule(resume);
+    }
+}
+
+bool Mutex_try_lock(MutexCore *this) {
+    bool locked;
+
+    CRITICAL_ENTER();
+    locked = !this->locked;
+    this->locked = true;
+    CRITICAL_EXIT();
+
+    return locked;
+}
+
+void Mutex_unlock(MutexCore *this) {
+    Waiter *next;
+
+    CRITICAL_ENTER();
+    next = Waiter_pop(&this->waiters);
+    this->locked = next != NULL;
+    CRITICAL_EXIT();
+
+    if (next != NULL) {
+        Continuation_schedule(next->resume);
+    }
+}
+
+void Mutex_cancel(MutexCore *this, Waiter *waiter) {
+    CRITICAL_ENTER();
+    Waiter_remove(&this->waiters, waiter);
+    CRITICAL_EXIT();
+}
+
+void Semaphore_acquire(Semaphore *this, Waiter *waiter, Continuation resume) {
+    bool acquired;
+
+    CRITICAL_ENTER();
+    acquired = this->count > 0;
+    if (acquired) {
+        this->count--;
+    } else {
+        waiter->resume = resume;
+        Waiter_push(&this->waiters, waiter);
+    }
+    CRITICAL_EXIT();
+
+    if (acquired) {
+        Continuation_schedule(resume);
+    }
+}
+
+bool Semaphore_try_acquire(Semaphore *this) {
+    bool acquired;
+
+    CRITICAL_ENTER();
+    acquired = this->count > 0;
+    if (acquired) {
+        this->count--;
+    }
+    CRITICAL_EXIT();
+
+    return acquired;
+}
+
+void Semaphore_release(Semaphore *this) {
+    Waiter *next;
+
+    CRITICAL_ENTER();
+    next = Waiter_pop(&this->waiters);
+    if (next == NULL) {
+        this->count++;
+    }
+    CRITICAL_EXIT();
+
+    if (next != NULL) {
+        Continuation_schedule(next->resume);
+    }
+}
+
+void Semaphore_cancel(Semaphore *this, Waiter *waiter) {
+    CRITICAL_ENTER();
+    Waiter_remove(&this->waiters, waiter);
+    CRITICAL_EXIT();
+}
+
+/* Whether tick `a` comes before tick `b`, allowing for the tick counter to
+   wrap around. */
+static bool Timer_before(uint32_t a, uint32_t b) {
+    return (int32_t)(a - b) < 0;
+}
+
+void Timer_sleep(Timer *this, uint32_t ms, Continuation resume) {
+    uint32_t ticks = (ms + TICK_MS - 1) / TICK_MS;
+    Timer **list;
+
+    this->resume = resume;
+
+    CRITICAL_ENTER();
+    this->deadline = system_state.ticks + ticks;
+    list = &system_state.timers;
+    while (*list != NULL && !Timer_before(this->deadline, (*list)->deadline)) {
+        list = &(*list)->next;
+    }
+    this->next = *list;
+    *list = this;
+    CRITICAL_EXIT();
+}
+
+void Timer_tick(void) {
+    uint32_t now;
+
+    CRITICAL_ENTER();
+    now = ++system_state.ticks;
+    CRITICAL_EXIT();
+
+    for (;;) {
+        Timer *expired = NULL;
+
+        CRITICAL_ENTER();
+        if (system_state.timers != NULL && !Timer_before(now, system_state.timers->deadline)) {
+            expired = system_state.timers;
+            system_state.timers = expired->next;
+        }
+        CRITICAL_EXIT();
+
+        if (expired == NULL) {
+            return;
+        }
+        Continuation_schedule(expired->resume);
+    }
+}
+
+void Timer_cancel(Timer *this) {
+    Timer **list;
+
+    CRITICAL_ENTER();
+    for (list = &system_state.timers; *list != NULL; list = &(*list)->next) {
+        if (*list == this) {
+            *list = this->next;
+            break;
+        }
+    }
+    CRITICAL_EXIT();
+}
+
 int main() {
     init();
 
     for(;;) {
+        Scheduler_run();
         idle();
     }
 }
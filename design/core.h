@@ -0,0 +1,211 @@
+#ifndef ASYNCLANG_CORE_H
+#define ASYNCLANG_CORE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#define INTERRUPT(vector) void vector(void)
+
+/* Guards state shared between interrupts and the main loop. Define these to
+   disable and restore interrupts on the target. */
+#ifndef CRITICAL_ENTER
+#define CRITICAL_ENTER()
+#endif
+#ifndef CRITICAL_EXIT
+#define CRITICAL_EXIT()
+#endif
+
+/* Bracket the `atomic` blocks of the program, which update globals shared
+   with interrupt handlers. They default to the runtime's own guards, and
+   like those must restore the previous state so that they can nest. */
+#ifndef ATOMIC_ENTER
+#define ATOMIC_ENTER() CRITICAL_ENTER()
+#endif
+#ifndef ATOMIC_EXIT
+#define ATOMIC_EXIT() CRITICAL_EXIT()
+#endif
+
+/* The number of continuations that can wait in each ready queue. */
+#ifndef READY_QUEUE_SIZE
+#define READY_QUEUE_SIZE 16
+#endif
+
+/* The number of task priorities, each with a ready queue of its own.
+   Priority 0 is the lowest. */
+#ifndef PRIORITY_LEVELS
+#define PRIORITY_LEVELS 4
+#endif
+
+/* The period of the tick interrupt that calls `Timer_tick`, which is the
+   resolution of `sleep_ms`. */
+#ifndef TICK_MS
+#define TICK_MS 1
+#endif
+
+/* Define CONTINUATION_DEBUG to trap when a stored continuation is invoked
+   a second time, which would resume its task at a point it has already
+   passed, or before one was ever stored. CONTINUATION_TRAP stops there, by
+   default spinning so that a debugger can be attached. */
+#ifdef CONTINUATION_DEBUG
+#ifndef CONTINUATION_TRAP
+#define CONTINUATION_TRAP() for (;;) {}
+#endif
+#endif
+
+typedef void (*TaskFn)(void *this);
+
+typedef struct _Continuation {
+    TaskFn function;
+    void *context;
+    /* the priority of the task resumed, which picks its ready queue */
+    uint8_t priority;
+} Continuation;
+
+typedef struct _TaskStateCore {
+    Continuation continuesWith;
+    /* stops whatever the task is suspended on and runs its cleanup, NULL
+       once the task has completed */
+    TaskFn cancel;
+    /* set once the task is cancelled, so that late completions of C
+       operations it was awaiting are ignored */
+    bool cancelled;
+    uint8_t priority;
+} TaskStateCore;
+
+void Continuation_init(Continuation *this, TaskFn function, void *context, uint8_t priority);
+void Continuation_invoke(Continuation this);
+/* Queues a continuation to be invoked from the main loop, which is how
+   interrupts with deferred dispatch resume tasks. */
+void Continuation_schedule(Continuation this);
+/* Takes the continuation out of the variable it is stored in, in order to
+   invoke it. With CONTINUATION_DEBUG the variable is left empty, and taking
+   from an empty one traps. */
+Continuation Continuation_take(Continuation *this);
+/* Invokes every queued continuation, including those queued meanwhile,
+   always taking the next one from the highest priority queue. */
+void Scheduler_run(void);
+/* Cancels a task at the await point it is suspended on. Its nested tasks
+   are cancelled first, then its `defer` blocks run, and its continuation is
+   never invoked. A task state can be passed by its `core` member. */
+void Task_cancel(TaskStateCore *task);
+
+/* A suspended task in the wait list of a channel or lock. Waiters live in
+   the state of the waiting task and are linked in FIFO order. */
+typedef struct _Waiter {
+    Continuation resume;
+    struct _Waiter *next;
+} Waiter;
+
+/* A task suspended on a full or empty channel. */
+typedef struct _ChannelWaiter {
+    Waiter waiter;
+    /* the value a sender offers, or where a receiver wants it stored */
+    void *value;
+} ChannelWaiter;
+
+typedef struct _ChannelCore {
+    unsigned head;
+    unsigned count;
+    Waiter *senders;
+    Waiter *receivers;
+} ChannelCore;
+
+/* A bounded FIFO of `capacity` values of `type`. */
+#define CHANNEL(type, capacity) struct { ChannelCore core; type buffer[capacity]; }
+/* The leading arguments of every `Channel_` function for a given channel. */
+#define CHANNEL_ARGS(channel) &(channel).core, (channel).buffer, \
+    sizeof (channel).buffer[0], sizeof (channel).buffer / sizeof (channel).buffer[0]
+
+/* Copies `value` into the channel, handing it straight to a waiting receiver
+   if there is one, and schedules `resume` once it is stored. When the
+   channel is full the sender waits in `waiter` until a receiver makes room.
+   `resume` is never invoked on the spot, so a task sending in a loop does
+   not nest deeper on the C stack with each pass. */
+void Channel_send(ChannelCore *core, void *buffer, size_t size, unsigned capacity,
+                  ChannelWaiter *waiter, const void *value, Continuation resume);
+/* Takes the oldest value out of the channel into `into` and schedules
+   `resume`, waiting in `waiter` while the channel is empty. */
+void Channel_recv(ChannelCore *core, void *buffer, size_t size, unsigned capacity,
+                  ChannelWaiter *waiter, void *into, Continuation resume);
+/* Sends without waiting, which is safe in interrupts. Returns false, and
+   drops the value, when the channel is full. */
+bool Channel_try_send(ChannelCore *core, void *buffer, size_t size, unsigned capacity, const void *value);
+/* Removes a cancelled task from the channel's waiters. */
+void Channel_cancel(ChannelCore *core, ChannelWaiter *waiter);
+
+typedef struct _MutexCore {
+    bool locked;
+    Waiter *waiters;
+} MutexCore;
+
+/* A value of `type` shared between tasks, which may only be used while
+   holding the lock. Mutexes start out unlocked. */
+#define MUTEX(type) struct { MutexCore core; type value; }
+
+/* Takes the lock and schedules `resume`, waiting in `waiter` while another
+   task holds it. */
+void Mutex_lock(MutexCore *this, Waiter *waiter, Continuation resume);
+bool Mutex_try_lock(MutexCore *this);
+/* Hands the lock to the first waiting task, which is scheduled, or leaves
+   it unlocked. */
+void Mutex_unlock(MutexCore *this);
+void Mutex_cancel(MutexCore *this, Waiter *waiter);
+
+/* A counting semaphore, which starts at the count it is declared with. */
+typedef struct _Semaphore {
+    unsigned count;
+    Waiter *waiters;
+} Semaphore;
+
+/* Takes one unit and schedules `resume`, waiting in `waiter` while the
+   count is zero. */
+void Semaphore_acquire(Semaphore *this, Waiter *waiter, Continuation resume);
+bool Semaphore_try_acquire(Semaphore *this);
+/* Gives a unit to the first waiting task, which is scheduled, or adds it to
+   the count. Safe in interrupts. */
+void Semaphore_release(Semaphore *this);
+void Semaphore_cancel(Semaphore *this, Waiter *waiter);
+
+/* A sleeping task, linked into the runtime's timer list in order of
+   expiry. Timers live in the state of the sleeping task. */
+typedef struct _Timer {
+    Continuation resume;
+    /* the tick at which the timer expires */
+    uint32_t deadline;
+    struct _Timer *next;
+} Timer;
+
+/* Schedules `resume` after `ms` milliseconds, rounded up to whole ticks
+   and counted from the last tick. A timer always waits for at least the
+   next tick, so that it can be cancelled until it expires. */
+void Timer_sleep(Timer *this, uint32_t ms, Continuation resume);
+/* Advances time by one tick and schedules every expired timer. Must be
+   called from exactly one interrupt, every TICK_MS milliseconds. */
+void Timer_tick(void);
+/* Removes a timer whose task was cancelled. */
+void Timer_cancel(Timer *this);
+
+typedef enum _TimeoutStatus {
+    Ok,
+    TimedOut,
+} TimeoutStatus;
+
+/* The result of a `timeout` around a task returning `type`. `value` is only
+   set when `status` is `Ok`. */
+#define TIMEOUT(type) struct { TimeoutStatus status; type value; }
+/* The result of a `timeout` around a task that returns nothing. */
+#define TIMEOUT_UNIT struct { TimeoutStatus status; }
+
+void init(void);
+void idle(void);
+/* called when a task cannot be spawned because its pool is full */
+void spawn_failed(const char *task);
+/* called when a `box` cannot be awaited because the pool of `task` is full;
+   the awaiting task is then cancelled, so whatever awaits it in turn stays
+   suspended unless this resets or restarts the program */
+void box_failed(const char *task);
+/* called when a continuation cannot be scheduled because the queue is full */
+void schedule_failed(void);
+
+#endif
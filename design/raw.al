@@ -1,16 +1,25 @@
 
+extern "hal/timer.h" fn init_timerX(period_ms: u32);
+extern "hal/uart.h" async println(text: str);
+
 global timerx_continuation: Continuation;
 
-fn init() {}
+fn init() {
+    spawn periodic(500);
+}
 
 fn idle() {}
 
-fn delay(continuation: Continuation, period_ms: u32) {
-    timerx_continuation = continuation;
-    init_timerX(period_ms);
+fn delay(period_ms: u32) {
+    suspend |k| {
+        atomic {
+            timerx_continuation = k;
+        }
+        init_timerX(period_ms);
+    }
 }
 
-async periodic(period_ms: u32) {
+pub async periodic(period_ms: u32) {
     loop {
         await delay(period_ms);
         await println("Hi!");
@@ -48,6 +48,44 @@ impl<'a> FormatAst<'a> {
         }
     }
 
+    fn signature(
+        &mut self, async: bool, generator: bool, priority: Option<u8>, name: &str, params: &[VarDecl], returns: &TypeRef,
+    ) {
+        if let Some(priority) = priority {
+            write!(self.writer, "async(priority = {}) ", priority).unwrap();
+        } else if async {
+            write!(self.writer, "async ").unwrap();
+        } else {
+            write!(self.writer, "fn ").unwrap();
+        }
+        if generator {
+            write!(self.writer, "gen ").unwrap();
+        }
+        write!(self.writer, "{}(", name).unwrap();
+        self.comma_separated(
+            params,
+            |s, i| s.accept_var_decl(i),
+        );
+        write!(self.writer, ")").unwrap();
+
+        let is_none = match returns {
+            &TypeRef::Tuple { ref type_refs } => type_refs.is_empty(),
+            _ => false,
+        };
+
+        if is_none == false {
+            write!(self.writer, " -> ").unwrap();
+            self.accept_type_ref(returns);
+        }
+    }
+
+    fn extern_prefix(&mut self, header: &Option<String>) {
+        write!(self.writer, "extern ").unwrap();
+        if let &Some(ref header) = header {
+            write!(self.writer, "\"{}\" ", header).unwrap();
+        }
+    }
+
     fn code_block(&mut self, prefix: &str, statements: &[Statement]) {
         write!(self.writer, "{}{{", prefix).unwrap();
 
@@ -92,6 +130,25 @@ impl<'a> VisitorMut<()> for FormatAst<'a> {
                 );
                 write!(self.writer, ")").unwrap();
             }
+            &TypeRef::Array { ref element, ref length } => {
+                write!(self.writer, "[").unwrap();
+                self.accept_type_ref(element);
+                write!(self.writer, "; ").unwrap();
+                self.accept_expression(length);
+                write!(self.writer, "]").unwrap();
+            }
+            &TypeRef::Channel { ref element, ref capacity } => {
+                write!(self.writer, "Channel<").unwrap();
+                self.accept_type_ref(element);
+                write!(self.writer, ", ").unwrap();
+                self.accept_expression(capacity);
+                write!(self.writer, ">").unwrap();
+            }
+            &TypeRef::Semaphore { ref initial } => {
+                write!(self.writer, "Semaphore<").unwrap();
+                self.accept_expression(initial);
+                write!(self.writer, ">").unwrap();
+            }
         }
     }
 
@@ -136,6 +193,9 @@ impl<'a> VisitorMut<()> for FormatAst<'a> {
             &Expression::Identifier(ref identifier) => {
                 write!(self.writer, "{}", identifier).unwrap();
             }
+            &Expression::Path(ref segments) => {
+                write!(self.writer, "{}", segments.join("::")).unwrap();
+            }
             &Expression::BinOp { ref left, ref operator, ref right } => {
                 self.accept_expression(&*left);
                 write!(self.writer, " ").unwrap();
@@ -157,12 +217,36 @@ impl<'a> VisitorMut<()> for FormatAst<'a> {
                 self.accept_expression(&*structure);
                 write!(self.writer, ".{}", member).unwrap();
             }
+            &Expression::Closure { ref body } => {
+                self.code_block("|| ", body);
+            }
+            &Expression::Join { ref tasks } => {
+                write!(self.writer, "join(").unwrap();
+                self.comma_separated(
+                    tasks,
+                    |s, i| s.accept_expression(i),
+                );
+                write!(self.writer, ")").unwrap();
+            }
+            &Expression::Boxed { ref task, ref slots } => {
+                write!(self.writer, "box(").unwrap();
+                self.accept_expression(&*slots);
+                write!(self.writer, ") ").unwrap();
+                self.accept_expression(&*task);
+            }
         };
     }
 
     fn accept_statement(&mut self, x: &Statement) -> () {
         self.indent();
         match x {
+            &Statement::Let { ref var, ref expr } => {
+                write!(self.writer, "let ").unwrap();
+                self.accept_var_decl(var);
+                write!(self.writer, " = ").unwrap();
+                self.accept_expression(expr);
+                write!(self.writer, ";").unwrap();
+            }
             &Statement::FnCall { ref target, ref args } => {
                 self.accept_expression(&*target);
                 write!(self.writer, "(").unwrap();
@@ -183,46 +267,111 @@ impl<'a> VisitorMut<()> for FormatAst<'a> {
                 self.accept_expression(expr);
                 write!(self.writer, ";").unwrap();
             }
+            &Statement::LetAwait { ref var, ref expr } => {
+                write!(self.writer, "let ").unwrap();
+                self.accept_var_decl(var);
+                write!(self.writer, " = await ").unwrap();
+                self.accept_expression(expr);
+                write!(self.writer, ";").unwrap();
+            }
             &Statement::Loop(ref statements) => {
                 self.code_block("loop ", statements);
             }
+            &Statement::Spawn { ref task, priority } => {
+                match priority {
+                    Some(priority) => write!(self.writer, "spawn(priority = {}) ", priority).unwrap(),
+                    None => write!(self.writer, "spawn ").unwrap(),
+                }
+                self.accept_expression(task);
+                write!(self.writer, ";").unwrap();
+            }
+            &Statement::Defer(ref statements) => {
+                self.code_block("defer ", statements);
+            }
+            &Statement::Atomic(ref statements) => {
+                self.code_block("atomic ", statements);
+            }
+            &Statement::Yield(ref expr) => {
+                write!(self.writer, "yield ").unwrap();
+                self.accept_expression(expr);
+                write!(self.writer, ";").unwrap();
+            }
+            &Statement::ForAwait { ref var, ref stream, ref body } => {
+                write!(self.writer, "for await ").unwrap();
+                self.accept_var_decl(var);
+                write!(self.writer, " in ").unwrap();
+                self.accept_expression(stream);
+                self.code_block(" ", body);
+            }
+            &Statement::Suspend { ref continuation, ref body } => {
+                self.code_block(&format!("suspend |{}| ", continuation), body);
+            }
+            &Statement::Select(ref arms) => {
+                writeln!(self.writer, "select {{").unwrap();
+                self.indented(|s| {
+                    for arm in arms.iter() {
+                        s.indent();
+                        if let Some(ref binding) = arm.binding {
+                            s.accept_var_decl(binding);
+                            write!(s.writer, " = ").unwrap();
+                        }
+                        write!(s.writer, "await ").unwrap();
+                        s.accept_expression(&arm.task);
+                        s.code_block(" => ", &arm.body);
+                        writeln!(s.writer).unwrap();
+                    }
+                });
+                self.indent();
+                write!(self.writer, "}}").unwrap();
+            }
+            &Statement::Return(ref expr) => {
+                write!(self.writer, "return ").unwrap();
+                self.accept_expression(expr);
+                write!(self.writer, ";").unwrap();
+            }
         }
     }
 
     fn accept_top_level_node(&mut self, x: &TopLevelNode) -> () {
         match x {
+            &TopLevelNode::Import { ref path } => {
+                write!(self.writer, "import {};", path.join("::")).unwrap();
+            }
             &TopLevelNode::GlobalDecl(ref vardecl) => {
                 write!(self.writer, "global ").unwrap();
                 self.accept_var_decl(vardecl);
             }
-            &TopLevelNode::FnDecl { ref name, ref params, ref returns, ref body, async } => {
-                if async {
-                    write!(self.writer, "async ").unwrap();
-                } else {
-                    write!(self.writer, "fn ").unwrap();
+            &TopLevelNode::ConstDecl { ref name, ref type_ref, ref expr, public } => {
+                if public {
+                    write!(self.writer, "pub ").unwrap();
                 }
-                write!(self.writer, "{}(", name).unwrap();
-                self.comma_separated(
-                    params,
-                    |s, i| s.accept_var_decl(i),
-                );
-                write!(self.writer, ") ").unwrap();
-
-                let is_none = match returns {
-                    &TypeRef::Tuple { ref type_refs } => type_refs.is_empty(),
-                    _ => false,
-                };
-
-                if is_none == false {
-                    write!(self.writer, " ->").unwrap();
-                    self.accept_type_ref(returns);
+                write!(self.writer, "const {}: ", name).unwrap();
+                self.accept_type_ref(type_ref);
+                write!(self.writer, " = ").unwrap();
+                self.accept_expression(expr);
+                write!(self.writer, ";").unwrap();
+            }
+            &TopLevelNode::FnDecl { ref name, ref params, ref returns, ref body, async, public, priority, generator } => {
+                if public {
+                    write!(self.writer, "pub ").unwrap();
                 }
-
-                self.code_block("", body);
+                self.signature(async, generator, priority, name, params, returns);
+                self.code_block(" ", body);
             }
             &TopLevelNode::InterruptDecl { ref name, ref body } => {
                 self.code_block(&format!("interrupt {} ", name), body);
             }
+            &TopLevelNode::ExternFnDecl { ref name, ref params, ref returns, async, ref header } => {
+                self.extern_prefix(header);
+                self.signature(async, false, None, name, params, returns);
+                write!(self.writer, ";").unwrap();
+            }
+            &TopLevelNode::ExternGlobalDecl { ref var, ref header } => {
+                self.extern_prefix(header);
+                write!(self.writer, "global ").unwrap();
+                self.accept_var_decl(var);
+                write!(self.writer, ";").unwrap();
+            }
         }
     }
 }
@@ -3,3 +3,4 @@ pub mod visitable;
 pub mod visitor;
 pub mod format;
 pub mod nodes;
+pub mod walk;
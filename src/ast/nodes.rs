@@ -1,5 +1,5 @@
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TypeRef {
     Named {
         name: String,
@@ -8,15 +8,31 @@ pub enum TypeRef {
     Tuple {
         type_refs: Vec<TypeRef>,
     },
+    /// A fixed size array whose length is a constant expression.
+    Array {
+        element: Box<TypeRef>,
+        length: Box<Expression>,
+    },
+    /// A bounded channel of messages, written `Channel<T, N>`, whose
+    /// capacity is a constant expression.
+    Channel {
+        element: Box<TypeRef>,
+        capacity: Box<Expression>,
+    },
+    /// A counting semaphore, written `Semaphore<N>`, whose initial count is
+    /// a constant expression.
+    Semaphore {
+        initial: Box<Expression>,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct VarDecl {
     pub name: String,
     pub type_ref: TypeRef,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Boolean(bool),
     Integer(i64),
@@ -24,7 +40,7 @@ pub enum Literal {
     String(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operator {
     Divide,
     Multiply,
@@ -45,10 +61,12 @@ pub enum Operator {
     LogicalOr,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Literal(Literal),
     Identifier(String),
+    /// An item of an imported module, e.g. `uart::read`.
+    Path(Vec<String>),
     MemberOf {
         structure: Box<Expression>,
         member: String,
@@ -62,10 +80,39 @@ pub enum Expression {
         target: Box<Expression>,
         args: Vec<Expression>,
     },
+    /// A callback usable as a `Continuation`, which may read and assign the
+    /// locals of the enclosing async function.
+    Closure {
+        body: Vec<Statement>,
+    },
+    /// Starts every task at once when awaited, completing when all have.
+    Join {
+        tasks: Vec<Expression>,
+    },
+    /// Awaits an async call with its state taken from a pool of `slots`
+    /// states rather than embedded in the caller's, e.g. `box(4) walk(n)`,
+    /// which lets an async function await itself.
+    Boxed {
+        task: Box<Expression>,
+        slots: Box<Expression>,
+    },
 }
 
-#[derive(Debug)]
+/// One arm of a `select`: the task it starts, an optional local to store
+/// the task's result in, and the statements to run if it completes first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectArm {
+    pub binding: Option<VarDecl>,
+    pub task: Expression,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
+    Let {
+        var: VarDecl,
+        expr: Expression,
+    },
     Assignment {
         target: Expression,
         expr: Expression,
@@ -75,21 +122,92 @@ pub enum Statement {
         args: Vec<Expression>,
     },
     Await(Expression),
+    /// Awaits an expression and stores the value it completes with, e.g. the
+    /// result of an async function or a message received from a channel.
+    LetAwait {
+        var: VarDecl,
+        expr: Expression,
+    },
     Loop(Vec<Statement>),
+    /// Starts the task of every arm, continues with the arm whose task
+    /// completes first and cancels the rest.
+    Select(Vec<SelectArm>),
+    /// Cleanup that runs when the task ends, whether it completes or is
+    /// cancelled at an await point after reaching the `defer`.
+    Defer(Vec<Statement>),
+    /// Starts an async function as a detached task in a statically
+    /// allocated task pool, optionally at a given scheduling priority.
+    Spawn {
+        task: Expression,
+        priority: Option<u8>,
+    },
+    /// Hands a value to the consumer of an `async gen` and suspends until it
+    /// asks for the next one.
+    Yield(Expression),
+    /// Runs the body once for each value yielded by the generator call
+    /// `stream`, until the generator completes.
+    ForAwait {
+        var: VarDecl,
+        stream: Expression,
+        body: Vec<Statement>,
+    },
+    /// Hands the rest of the current task to `body` as the `Continuation`
+    /// named `continuation`, then suspends until it is invoked, e.g.
+    /// `suspend |k| { timerx_continuation = k; }`.
+    Suspend {
+        continuation: String,
+        body: Vec<Statement>,
+    },
+    /// Completes the function with a value, e.g. `return total;`. It can
+    /// only be the last statement of a function body.
+    Return(Expression),
+    /// Runs the body with interrupts disabled, so that globals shared with
+    /// interrupt handlers can be updated without one running in between.
+    Atomic(Vec<Statement>),
 }
 
 #[derive(Debug)]
 pub enum TopLevelNode {
+    Import {
+        path: Vec<String>,
+    },
     GlobalDecl(VarDecl),
+    ConstDecl {
+        name: String,
+        type_ref: TypeRef,
+        expr: Expression,
+        public: bool,
+    },
     FnDecl {
         name: String,
         params: Vec<VarDecl>,
         returns: TypeRef,
         body: Vec<Statement>,
         async: bool,
+        public: bool,
+        /// The scheduling priority of the function's task, which otherwise
+        /// inherits the priority of whatever started it.
+        priority: Option<u8>,
+        /// Whether this is an `async gen`, which yields a stream of `returns`
+        /// values instead of returning one.
+        generator: bool,
     },
     InterruptDecl {
         name: String,
         body: Vec<Statement>,
     },
+    /// A function implemented in existing C code. An `extern async` function
+    /// follows the `Continuation` convention: it receives the continuation to
+    /// resume as its first argument, ahead of the declared parameters.
+    ExternFnDecl {
+        name: String,
+        params: Vec<VarDecl>,
+        returns: TypeRef,
+        async: bool,
+        header: Option<String>,
+    },
+    ExternGlobalDecl {
+        var: VarDecl,
+        header: Option<String>,
+    },
 }
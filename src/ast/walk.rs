@@ -0,0 +1,113 @@
+use super::nodes::*;
+
+/// Calls `f` for every statement in `body`, including those nested inside
+/// loops, `select` arms, `defer`, `suspend` and `atomic` blocks, in source
+/// order. Closure bodies
+/// are not entered, since they run separately from the function that
+/// contains them.
+pub fn statements<'a, F: FnMut(&'a Statement)>(body: &'a [Statement], f: &mut F) {
+    for statement in body.iter() {
+        f(statement);
+        match statement {
+            &Statement::Loop(ref nested) | &Statement::Defer(ref nested) | &Statement::Atomic(ref nested) => {
+                statements(nested, f)
+            }
+            &Statement::Suspend { ref body, .. } => statements(body, f),
+            &Statement::ForAwait { ref body, .. } => statements(body, f),
+            &Statement::Select(ref arms) => {
+                for arm in arms.iter() {
+                    statements(&arm.body, f);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Calls `f` for every expression in `body`, outermost first. As with
+/// `statements`, closure bodies are not entered.
+pub fn expressions<'a, F: FnMut(&'a Expression)>(body: &'a [Statement], f: &mut F) {
+    statements(body, &mut |statement| {
+        for expr in statement_expressions(statement) {
+            subexpressions(expr, f);
+        }
+    });
+}
+
+/// Calls `f` for `expr` and each expression it contains.
+pub fn subexpressions<'a, F: FnMut(&'a Expression)>(expr: &'a Expression, f: &mut F) {
+    f(expr);
+    match expr {
+        &Expression::Literal(..) | &Expression::Identifier(..) | &Expression::Path(..) => {}
+        &Expression::Closure { .. } => {}
+        &Expression::MemberOf { ref structure, .. } => subexpressions(structure, f),
+        &Expression::BinOp { ref left, ref right, .. } => {
+            subexpressions(left, f);
+            subexpressions(right, f);
+        }
+        &Expression::FnCall { ref target, ref args } => {
+            subexpressions(target, f);
+            for arg in args.iter() {
+                subexpressions(arg, f);
+            }
+        }
+        &Expression::Join { ref tasks } => {
+            for task in tasks.iter() {
+                subexpressions(task, f);
+            }
+        }
+        &Expression::Boxed { ref task, .. } => subexpressions(task, f),
+    }
+}
+
+/// The expressions that appear directly in a statement.
+pub fn statement_expressions(statement: &Statement) -> Vec<&Expression> {
+    match statement {
+        &Statement::Let { ref expr, .. } => vec![expr],
+        &Statement::Assignment { ref target, ref expr } => vec![target, expr],
+        &Statement::FnCall { ref target, ref args } => {
+            let mut result = vec![target];
+            result.extend(args.iter());
+            result
+        }
+        &Statement::Await(ref expr) | &Statement::LetAwait { ref expr, .. } => vec![expr],
+        &Statement::Spawn { task: ref expr, .. } => vec![expr],
+        &Statement::Yield(ref expr) | &Statement::ForAwait { stream: ref expr, .. } => vec![expr],
+        &Statement::Return(ref expr) => vec![expr],
+        &Statement::Loop(..) | &Statement::Defer(..) | &Statement::Suspend { .. } | &Statement::Atomic(..) => vec![],
+        &Statement::Select(ref arms) => arms.iter().map(|arm| &arm.task).collect(),
+    }
+}
+
+/// The variables introduced in `body` by `let` statements, `for await`
+/// loops and `select` arms that store a result, in source order.
+pub fn let_bindings(body: &[Statement]) -> Vec<&VarDecl> {
+    let mut result = Vec::new();
+    statements(body, &mut |statement| {
+        match statement {
+            &Statement::Let { ref var, .. } | &Statement::LetAwait { ref var, .. } => result.push(var),
+            &Statement::ForAwait { ref var, .. } => result.push(var),
+            &Statement::Select(ref arms) => {
+                result.extend(arms.iter().filter_map(|arm| arm.binding.as_ref()));
+            }
+            _ => {}
+        }
+    });
+    result
+}
+
+/// The generator call a `for await` loop consumes.
+pub fn streamed(statement: &Statement) -> Option<&Expression> {
+    match statement {
+        &Statement::ForAwait { ref stream, .. } => Some(stream),
+        _ => None,
+    }
+}
+
+/// The expression a statement suspends on, if it is an `await`.
+pub fn awaited(statement: &Statement) -> Option<&Expression> {
+    match statement {
+        &Statement::Await(ref expr) | &Statement::LetAwait { ref expr, .. } => Some(expr),
+        _ => None,
+    }
+}
@@ -85,6 +85,9 @@ impl<'a> Builder<'a> {
 
         for statement in statements.iter() {
             match statement {
+                let_statement @ &ast::nodes::Statement::Let { .. } => {
+                    self.get_block_mut(result.end_block).statements.push(let_statement);
+                },
                 assignment @ &ast::nodes::Statement::Assignment { .. } => {
                     self.get_block_mut(result.end_block).statements.push(assignment);
                 },
@@ -110,7 +113,59 @@ impl<'a> Builder<'a> {
                     self.result.graph.add_edge(nested_result.end_block, loop_repeat, Edge::Jump);
                     nested_result.assert_resolved();
                 },
-                await_expr @ &ast::nodes::Statement::Await(..) => {
+                spawn @ &ast::nodes::Statement::Spawn { .. } => {
+                    self.get_block_mut(result.end_block).statements.push(spawn);
+                },
+                defer @ &ast::nodes::Statement::Defer(..) => {
+                    self.get_block_mut(result.end_block).statements.push(defer);
+                },
+                atomic @ &ast::nodes::Statement::Atomic(..) => {
+                    self.get_block_mut(result.end_block).statements.push(atomic);
+                },
+                select @ &ast::nodes::Statement::Select(..) => {
+                    let arms = match select {
+                        &ast::nodes::Statement::Select(ref arms) => arms,
+                        _ => unreachable!(),
+                    };
+
+                    self.get_block_mut(result.end_block).statements.push(select);
+                    let select_end = self.make_block_with_description("select end");
+                    for (i, arm) in arms.iter().enumerate() {
+                        let mut arm_result = self.build_inner(&arm.body);
+                        self.result.graph.add_edge(result.end_block, arm_result.start_block, Edge::SelectArm(i));
+                        self.result.graph.add_edge(arm_result.end_block, select_end, Edge::Jump);
+                        result.unresolved_exits.extend(arm_result.unresolved_exits.drain(..));
+                    }
+                    result.end_block = select_end;
+                },
+                yield_statement @ &ast::nodes::Statement::Yield(..) => {
+                    self.get_block_mut(result.end_block).statements.push(yield_statement);
+                    let next_block = self.make_block();
+                    self.result.graph.add_edge(result.end_block, next_block, Edge::Yield);
+                    result.end_block = next_block;
+                },
+                for_await @ &ast::nodes::Statement::ForAwait { .. } => {
+                    let body = match for_await {
+                        &ast::nodes::Statement::ForAwait { ref body, .. } => body,
+                        _ => unreachable!(),
+                    };
+
+                    self.get_block_mut(result.end_block).statements.push(for_await);
+                    let loop_end = self.make_block_with_description("for await end");
+                    let mut body_result = self.build_inner(body);
+                    self.result.graph.add_edge(result.end_block, body_result.start_block, Edge::StreamItem);
+                    self.result.graph.add_edge(result.end_block, loop_end, Edge::StreamEnd);
+                    self.result.graph.add_edge(body_result.end_block, body_result.start_block, Edge::StreamItem);
+                    self.result.graph.add_edge(body_result.end_block, loop_end, Edge::StreamEnd);
+                    result.unresolved_exits.extend(body_result.unresolved_exits.drain(..));
+                    result.end_block = loop_end;
+                },
+                return_statement @ &ast::nodes::Statement::Return(..) => {
+                    self.get_block_mut(result.end_block).statements.push(return_statement);
+                },
+                await_expr @ &ast::nodes::Statement::Await(..) |
+                await_expr @ &ast::nodes::Statement::LetAwait { .. } |
+                await_expr @ &ast::nodes::Statement::Suspend { .. } => {
                     self.get_block_mut(result.end_block).statements.push(await_expr);
                     let next_block = self.make_block();
                     self.result.graph.add_edge(result.end_block, next_block, Edge::Await);
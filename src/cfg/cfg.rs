@@ -1,11 +1,11 @@
-use petgraph::{dot::Dot, stable_graph::NodeIndex, Direction};
+use petgraph::{dot::Dot, stable_graph::NodeIndex, visit::{depth_first_search, DfsEvent, EdgeRef}, Direction};
 
 use std::io::prelude::*;
 use std::path::Path;
 use std::io::Error;
 use std::fs::File;
 
-use super::graph::GraphType;
+use super::graph::{Edge, GraphType};
 
 pub struct ControlFlowGraph<'a> {
     pub entry_node: NodeIndex,
@@ -34,16 +34,19 @@ impl<'a> ControlFlowGraph<'a> {
                 let empty = self.graph[idx].statements.is_empty();
 
                 if empty {
-                    let incoming = self.graph.neighbors_directed(idx, Direction::Incoming)
+                    let incoming = self.graph.edges_directed(idx, Direction::Incoming)
+                        .map(|edge| (edge.source(), *edge.weight()))
                         .collect::<Vec<_>>();
                     let outgoing = self.graph.neighbors_directed(idx, Direction::Outgoing)
                         .collect::<Vec<_>>();
 
-                    if incoming.len() > 0 && outgoing.len() == 1 {
+                    // A block a task resumes in stays separate even when it is
+                    // empty, so that it can store what the task awaited.
+                    let resumes = incoming.iter().any(|&(_, kind)| kind == Edge::Await);
+
+                    if incoming.len() > 0 && outgoing.len() == 1 && resumes == false {
                         let destination_idx = outgoing[0];
-                        for source_idx in incoming {
-                            let edge_idx = self.graph.find_edge(source_idx, idx).unwrap();
-                            let kind = self.graph[edge_idx];
+                        for (source_idx, kind) in incoming {
                             self.graph.add_edge(source_idx, destination_idx, kind);
                         }
                         self.graph.remove_node(idx);
@@ -58,4 +61,26 @@ impl<'a> ControlFlowGraph<'a> {
             }
         }
     }
+
+    /// The block that suspends on an await and resumes the task at `idx`, if
+    /// `idx` is where a task resumes. Once tidied, such a block has no other
+    /// predecessor.
+    pub fn awaiting_block(&self, idx: NodeIndex) -> Option<NodeIndex> {
+        let incoming = self.graph.edges_directed(idx, Direction::Incoming).collect::<Vec<_>>();
+        let awaiting = incoming.iter().find(|edge| *edge.weight() == Edge::Await)?.source();
+        assert!(incoming.len() == 1, "a block a task resumes in is only reached by resuming");
+        Some(awaiting)
+    }
+
+    /// The jumps that lead back to the start of a loop, as pairs of the
+    /// blocks they leave and enter.
+    pub fn back_edges(&self) -> Vec<(NodeIndex, NodeIndex)> {
+        let mut result = Vec::new();
+        depth_first_search(&self.graph, Some(self.entry_node), |event| {
+            if let DfsEvent::BackEdge(from, to) = event {
+                result.push((from, to));
+            }
+        });
+        result
+    }
 }
@@ -55,6 +55,15 @@ pub enum Edge {
     IfFalse,
     Break,
     Continue,
+    /// Taken when the task of the given `select` arm completes first.
+    SelectArm(usize),
+    /// Suspends a generator at a `yield` until its consumer asks for the
+    /// next value.
+    Yield,
+    /// Taken by a `for await` loop when the generator yields a value.
+    StreamItem,
+    /// Taken by a `for await` loop when the generator completes.
+    StreamEnd,
 }
 
 pub type GraphType<'a> = StableGraph<Block<'a>, Edge>;
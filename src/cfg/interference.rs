@@ -0,0 +1,61 @@
+use std::collections::HashSet;
+
+use super::cfg::ControlFlowGraph;
+use super::liveness::{self, Liveness};
+
+/// Which variables of a control flow graph are live at the same time, and so
+/// cannot share storage. Like `Liveness`, it tracks variables by name.
+pub struct Interference<'a> {
+    edges: HashSet<(&'a str, &'a str)>,
+}
+
+impl<'a> Interference<'a> {
+    /// Walks each block backwards from its live-out set, making every
+    /// variable a statement assigns interfere with those live after it. The
+    /// variables a statement reads count as live while it assigns, so that
+    /// a value is never copied onto storage it overlaps. Everything live on
+    /// entry to the function, i.e. the parameters, is assigned at once.
+    pub fn analyze(cfg: &ControlFlowGraph<'a>, liveness: &Liveness<'a>) -> Self {
+        let mut result = Interference { edges: HashSet::new() };
+
+        for idx in cfg.graph.node_indices() {
+            let mut live = liveness.live_out(idx).clone();
+            for statement in cfg.graph[idx].statements.iter().rev() {
+                let mut uses = HashSet::new();
+                let mut defs = HashSet::new();
+                liveness::transfer(statement, &mut uses, &mut defs);
+
+                for &def in defs.iter() {
+                    for &other in live.iter().chain(uses.iter()) {
+                        result.add(def, other);
+                    }
+                }
+                live.retain(|name| defs.contains(name) == false);
+                live.extend(uses);
+            }
+        }
+
+        let parameters = liveness.live_in(cfg.entry_node);
+        for &a in parameters.iter() {
+            for &b in parameters.iter() {
+                result.add(a, b);
+            }
+        }
+
+        result
+    }
+
+    pub fn interferes(&self, a: &'a str, b: &'a str) -> bool {
+        self.edges.contains(&ordered(a, b))
+    }
+
+    fn add(&mut self, a: &'a str, b: &'a str) {
+        if a != b {
+            self.edges.insert(ordered(a, b));
+        }
+    }
+}
+
+fn ordered<'a>(a: &'a str, b: &'a str) -> (&'a str, &'a str) {
+    if a < b { (a, b) } else { (b, a) }
+}
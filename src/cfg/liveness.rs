@@ -0,0 +1,121 @@
+use petgraph::{stable_graph::NodeIndex, visit::EdgeRef};
+
+use std::collections::{HashMap, HashSet};
+
+use super::cfg::ControlFlowGraph;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+/// The variables live on entry to and on exit from each block of a control
+/// flow graph, i.e. those whose current value may still be read before they
+/// are assigned again.
+///
+/// Variables are tracked by name, and any identifier counts, so the sets may
+/// also hold globals and functions.
+pub struct Liveness<'a> {
+    live_in: HashMap<NodeIndex, HashSet<&'a str>>,
+    live_out: HashMap<NodeIndex, HashSet<&'a str>>,
+}
+
+impl<'a> Liveness<'a> {
+    /// Solves the classic backward dataflow equations
+    ///
+    /// ```text
+    /// live_out(b) = union of live_in(s) for every successor s of b
+    /// live_in(b)  = uses(b) + (live_out(b) - defs(b))
+    /// ```
+    ///
+    /// until they no longer change.
+    pub fn analyze(cfg: &ControlFlowGraph<'a>) -> Self {
+        let mut transfers = HashMap::new();
+        let mut live_in = HashMap::new();
+        let mut live_out = HashMap::new();
+        for idx in cfg.graph.node_indices() {
+            let mut uses = HashSet::new();
+            let mut defs = HashSet::new();
+            for statement in cfg.graph[idx].statements.iter() {
+                transfer(statement, &mut uses, &mut defs);
+            }
+
+            live_in.insert(idx, uses.clone());
+            live_out.insert(idx, HashSet::new());
+            transfers.insert(idx, (uses, defs));
+        }
+
+        // Visiting the blocks in reverse lets most changes propagate within
+        // a single pass, since the builder adds blocks roughly in order.
+        let blocks = cfg.graph.node_indices().collect::<Vec<_>>();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &idx in blocks.iter().rev() {
+                let out = cfg.graph.edges(idx)
+                    .flat_map(|edge| live_in[&edge.target()].iter().cloned())
+                    .collect::<HashSet<_>>();
+
+                let (ref uses, ref defs) = transfers[&idx];
+                let entry = uses.iter()
+                    .cloned()
+                    .chain(out.iter().filter(|name| defs.contains(*name) == false).cloned())
+                    .collect::<HashSet<_>>();
+
+                if entry.len() != live_in[&idx].len() || out.len() != live_out[&idx].len() {
+                    changed = true;
+                    live_in.insert(idx, entry);
+                    live_out.insert(idx, out);
+                }
+            }
+        }
+
+        Liveness { live_in, live_out }
+    }
+
+    pub fn live_in(&self, block: NodeIndex) -> &HashSet<&'a str> {
+        &self.live_in[&block]
+    }
+
+    pub fn live_out(&self, block: NodeIndex) -> &HashSet<&'a str> {
+        &self.live_out[&block]
+    }
+}
+
+/// Adds the variables a statement reads before the block assigns them to
+/// `uses`, and those it assigns to `defs`. The bodies of `suspend` and
+/// `atomic` blocks run as part of the statement, while those of `defer`,
+/// `select` arms and `for await` loops run elsewhere. Values stored by an
+/// await are assigned across the edge that resumes the task, so they count
+/// as assigned by the block that suspends.
+pub fn transfer<'a>(statement: &'a Statement, uses: &mut HashSet<&'a str>, defs: &mut HashSet<&'a str>) {
+    {
+        let mut read = |expr: &'a Expression| walk::subexpressions(expr, &mut |expr| {
+            if let &Expression::Identifier(ref name) = expr {
+                if defs.contains(name.as_str()) == false {
+                    uses.insert(name);
+                }
+            }
+        });
+        match statement {
+            &Statement::Assignment { target: Expression::Identifier(..), ref expr } => read(expr),
+            _ => {
+                for expr in walk::statement_expressions(statement) {
+                    read(expr);
+                }
+            }
+        }
+    }
+
+    match statement {
+        &Statement::Let { ref var, .. } | &Statement::LetAwait { ref var, .. } => {
+            defs.insert(&var.name);
+        }
+        &Statement::Assignment { target: Expression::Identifier(ref name), .. } => {
+            defs.insert(name);
+        }
+        &Statement::Suspend { ref body, .. } | &Statement::Atomic(ref body) | &Statement::Loop(ref body) => {
+            for statement in body.iter() {
+                transfer(statement, uses, defs);
+            }
+        }
+        _ => {}
+    }
+}
@@ -1,5 +1,5 @@
-
 pub mod graph;
 pub mod cfg;
 pub mod builder;
-
+pub mod liveness;
+pub mod interference;
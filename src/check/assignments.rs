@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+
+use petgraph::{Direction, stable_graph::NodeIndex};
+
+use super::Diagnostic;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+use super::super::cfg::builder::Builder;
+use super::super::cfg::liveness;
+
+/// Checks that every local a function reads has been assigned on every path
+/// that reaches the read. Blocks that join several paths, such as the end of
+/// a `select`, only see the locals each of the paths assigns.
+///
+/// The bindings of `select` arms and `for await` loops are assigned whenever
+/// the body they belong to runs, so only `let` bindings are followed.
+pub fn check(nodes: &[TopLevelNode]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for node in nodes.iter() {
+        let (name, body) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, .. } => (name, body),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body),
+            _ => continue,
+        };
+
+        let mut tracked = HashSet::new();
+        walk::statements(body, &mut |statement| {
+            if let &Statement::Let { ref var, .. } | &Statement::LetAwait { ref var, .. } = statement {
+                tracked.insert(var.name.as_str());
+            }
+        });
+
+        for local in unassigned_reads(name, body, &tracked) {
+            diagnostics.push(Diagnostic::error(format!(
+                "`{}` is read in `{}` but is not assigned on every path that reaches it", local, name,
+            )).with_help("declare it before the paths split, or read it only where it is declared".into()));
+        }
+    }
+
+    diagnostics
+}
+
+/// The tracked locals of `body` that may be read before they are assigned,
+/// in the order they are first found.
+fn unassigned_reads<'a>(name: &'a str, body: &'a [Statement], tracked: &HashSet<&'a str>) -> Vec<&'a str> {
+    let mut cfg = Builder::new(name).build(body);
+    cfg.tidy_graph();
+
+    // A forward must-analysis: a block starts out assuming every local is
+    // assigned, and keeps only those assigned on all of its incoming paths.
+    let mut assigned: HashMap<NodeIndex, HashSet<&'a str>> = HashMap::new();
+    for idx in cfg.graph.node_indices() {
+        let entry = if idx == cfg.entry_node { HashSet::new() } else { tracked.clone() };
+        assigned.insert(idx, entry);
+    }
+
+    let blocks = cfg.graph.node_indices().collect::<Vec<_>>();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &idx in blocks.iter() {
+            let mut state = assigned[&idx].clone();
+            for statement in cfg.graph[idx].statements.iter() {
+                liveness::transfer(statement, &mut HashSet::new(), &mut state);
+            }
+
+            for next in cfg.graph.neighbors_directed(idx, Direction::Outgoing) {
+                let existing = assigned.get_mut(&next).unwrap();
+                let merged = existing.intersection(&state).cloned().collect::<HashSet<_>>();
+                if merged.len() != existing.len() {
+                    *existing = merged;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    for &idx in blocks.iter() {
+        let mut state = assigned[&idx].clone();
+        for statement in cfg.graph[idx].statements.iter() {
+            // Reads of locals already in `state` are not counted as uses.
+            let mut uses = HashSet::new();
+            liveness::transfer(statement, &mut uses, &mut state);
+            let mut uses = uses.into_iter().filter(|local| tracked.contains(local)).collect::<Vec<_>>();
+            uses.sort();
+            for local in uses {
+                if result.contains(&local) == false {
+                    result.push(local);
+                }
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    fn select_arm(task: &str, body: Vec<Statement>) -> SelectArm {
+        SelectArm { binding: None, task: call(task, vec![]), body }
+    }
+
+    #[test]
+    fn locals_assigned_on_only_some_paths_cannot_be_read() {
+        let errors = errors(vec![
+            async_fn("h", vec![], ty("u32"), vec![Statement::Return(int(1))]),
+            async_fn("k", vec![], ty("u32"), vec![Statement::Return(int(2))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Select(vec![
+                    select_arm("h", vec![let_await("a", ty("u32"), call("h", vec![]))]),
+                    select_arm("k", vec![let_await("b", ty("u32"), call("k", vec![]))]),
+                ]),
+                let_("sum", ty("u32"), add(ident("a"), int(1))),
+            ]),
+        ]);
+        assert!(mentions(&errors, "`a` is read in `main` but is not assigned on every path that reaches it"), "{:?}", errors);
+    }
+
+    #[test]
+    fn locals_assigned_on_every_path_can_be_read() {
+        let errors = errors(vec![
+            async_fn("h", vec![], ty("u32"), vec![Statement::Return(int(1))]),
+            async_fn("main", vec![], unit(), vec![
+                let_await("a", ty("u32"), call("h", vec![])),
+                Statement::Loop(vec![
+                    let_("b", ty("u32"), add(ident("a"), int(1))),
+                    Statement::Assignment { target: ident("a"), expr: ident("b") },
+                ]),
+            ]),
+        ]);
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+}
@@ -0,0 +1,162 @@
+use super::Diagnostic;
+use super::symbols::SymbolTable;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+pub const SEND: &str = "send";
+pub const RECV: &str = "recv";
+pub const TRY_SEND: &str = "try_send";
+
+/// A call to one of the built-in methods of a channel, e.g. `ch.send(x)`.
+pub struct ChannelCall<'a, 'e> {
+    /// The global the channel is stored in.
+    pub channel: &'a VarDecl,
+    pub element: &'a TypeRef,
+    pub method: &'e str,
+    pub args: &'e [Expression],
+}
+
+/// Recognises a call whose target is a method of a global channel.
+pub fn channel_call<'a, 'e>(target: &'e Expression, args: &'e [Expression], symbols: &SymbolTable<'a>) -> Option<ChannelCall<'a, 'e>> {
+    let (name, method) = match target {
+        &Expression::MemberOf { ref structure, ref member } => match **structure {
+            Expression::Identifier(ref name) => (name, member),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let channel = match symbols.globals.get(name.as_str()) {
+        Some(global) if global.external == false => global.var,
+        _ => return None,
+    };
+    match channel.type_ref {
+        TypeRef::Channel { ref element, .. } => Some(ChannelCall { channel, element, method, args }),
+        _ => None,
+    }
+}
+
+/// The channel operation an `await` suspends on, if any.
+pub fn awaited_channel_call<'a, 'e>(statement: &'e Statement, symbols: &SymbolTable<'a>) -> Option<ChannelCall<'a, 'e>> {
+    match walk::awaited(statement) {
+        Some(&Expression::FnCall { ref target, ref args }) => channel_call(target, args, symbols),
+        _ => None,
+    }
+}
+
+/// Checks that channels are only stored in globals, and that tasks await
+/// `send` and `recv` while other code uses `try_send`.
+pub fn check(nodes: &[TopLevelNode], symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for node in nodes.iter() {
+        match node {
+            &TopLevelNode::GlobalDecl(ref var) => {
+                if let TypeRef::Channel { ref element, .. } = var.type_ref {
+                    if contains_channel(element) || is_array(element) {
+                        diagnostics.push(Diagnostic::error(format!(
+                            "channel `{}` must carry plain values, not arrays or channels", var.name,
+                        )));
+                    }
+                } else if contains_channel(&var.type_ref) {
+                    diagnostics.push(misplaced(&var.name));
+                }
+            }
+            &TopLevelNode::ExternGlobalDecl { ref var, .. } if contains_channel(&var.type_ref) => {
+                diagnostics.push(Diagnostic::error(format!("channel `{}` cannot be implemented in C", var.name))
+                    .with_help("declare it as a `global` instead".into()));
+            }
+            &TopLevelNode::ConstDecl { ref name, ref type_ref, .. } if contains_channel(type_ref) => {
+                diagnostics.push(misplaced(name));
+            }
+            &TopLevelNode::FnDecl { ref params, ref returns, .. } |
+            &TopLevelNode::ExternFnDecl { ref params, ref returns, .. } => {
+                for param in params.iter().filter(|p| contains_channel(&p.type_ref)) {
+                    diagnostics.push(misplaced(&param.name));
+                }
+                if contains_channel(returns) {
+                    diagnostics.push(Diagnostic::error("channels cannot be returned".into()));
+                }
+            }
+            _ => {}
+        }
+
+        let (name, body, async) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async, .. } => (name, body, async),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body, false),
+            _ => continue,
+        };
+
+        for local in walk::let_bindings(body) {
+            if contains_channel(&local.type_ref) {
+                diagnostics.push(misplaced(&local.name));
+            }
+        }
+
+        let mut awaited = Vec::new();
+        walk::statements(body, &mut |statement| {
+            if let Some(call) = awaited_channel_call(statement, symbols) {
+                awaited.push(walk::awaited(statement).unwrap());
+                if call.method == TRY_SEND {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}.try_send` is awaited in `{}` but never suspends", call.channel.name, name,
+                    )).with_help(format!("await `{}.send` to wait for room in the channel", call.channel.name)));
+                } else if async == false {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}.{}` is awaited in `{}`, which is not async", call.channel.name, call.method, name,
+                    )).with_help(format!("use `{}.try_send`, which does not suspend", call.channel.name)));
+                }
+            }
+        });
+
+        walk::expressions(body, &mut |expr| {
+            let call = match expr {
+                &Expression::FnCall { ref target, ref args } => channel_call(target, args, symbols),
+                _ => None,
+            };
+            if let Some(call) = call {
+                let is_awaited = awaited.iter().any(|a| ::std::ptr::eq(*a, expr));
+                if is_awaited == false && (call.method == SEND || call.method == RECV) {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}.{}` in `{}` must be awaited", call.channel.name, call.method, name,
+                    )));
+                }
+            }
+        });
+        walk::statements(body, &mut |statement| {
+            if let &Statement::FnCall { ref target, ref args } = statement {
+                if let Some(call) = channel_call(target, args, symbols) {
+                    if call.method == SEND || call.method == RECV {
+                        diagnostics.push(Diagnostic::error(format!(
+                            "`{}.{}` in `{}` must be awaited", call.channel.name, call.method, name,
+                        )));
+                    }
+                }
+            }
+        });
+    }
+
+    diagnostics
+}
+
+fn misplaced(name: &str) -> Diagnostic {
+    Diagnostic::error(format!("`{}` holds a channel, but channels can only be globals", name))
+        .with_help("declare the channel as a `global` and refer to it by name".into())
+}
+
+fn contains_channel(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Channel { .. } => true,
+        &TypeRef::Array { ref element, .. } => contains_channel(element),
+        &TypeRef::Tuple { ref type_refs } => type_refs.iter().any(contains_channel),
+        &TypeRef::Named { ref type_params, .. } => type_params.iter().any(contains_channel),
+        &TypeRef::Semaphore { .. } => false,
+    }
+}
+
+pub fn is_array(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Array { .. } => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,92 @@
+use super::Diagnostic;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+/// Finds every closure in a function body, in source order.
+pub fn closures(body: &[Statement]) -> Vec<&Expression> {
+    let mut result = Vec::new();
+    walk::expressions(body, &mut |expr| {
+        if let &Expression::Closure { .. } = expr {
+            result.push(expr);
+        }
+    });
+    result
+}
+
+/// The locals of the enclosing function that a closure body refers to.
+pub fn captures<'a>(closure_body: &[Statement], enclosing_locals: &[&'a VarDecl]) -> Vec<&'a VarDecl> {
+    let own_locals = walk::let_bindings(closure_body);
+    let mut result: Vec<&'a VarDecl> = Vec::new();
+
+    walk::expressions(closure_body, &mut |expr| {
+        if let &Expression::Identifier(ref name) = expr {
+            if own_locals.iter().any(|local| &local.name == name) {
+                return;
+            }
+            if result.iter().any(|local| &local.name == name) {
+                return;
+            }
+            if let Some(local) = enclosing_locals.iter().find(|local| &local.name == name) {
+                result.push(local);
+            }
+        }
+    });
+
+    result
+}
+
+/// Checks that closures only capture locals that outlive them, which are
+/// those kept in the state of an async function.
+pub fn check(nodes: &[TopLevelNode]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for node in nodes.iter() {
+        let (name, params, body, async, help) = match node {
+            &TopLevelNode::FnDecl { ref name, ref params, ref body, async, .. } => {
+                let help = format!("make `{}` async so its locals are kept in its task state", name);
+                (name, &params[..], body, async, help)
+            }
+            &TopLevelNode::InterruptDecl { ref name, ref body } => {
+                let help = "store the captured values in globals instead".into();
+                (name, &[][..], body, false, help)
+            }
+            _ => continue,
+        };
+
+        let mut locals = params.iter().collect::<Vec<_>>();
+        locals.extend(walk::let_bindings(body));
+
+        for closure in closures(body) {
+            let closure_body = match closure {
+                &Expression::Closure { ref body } => body,
+                _ => unreachable!(),
+            };
+
+            let mut awaits = false;
+            walk::statements(closure_body, &mut |statement| {
+                if walk::awaited(statement).is_some() {
+                    awaits = true;
+                }
+            });
+            if awaits {
+                diagnostics.push(Diagnostic::error(format!("a closure in `{}` awaits", name))
+                    .with_help("closures run as callbacks and cannot suspend; call an async function instead".into()));
+            }
+
+            if closures(closure_body).is_empty() == false {
+                diagnostics.push(Diagnostic::error(format!("a closure in `{}` contains another closure", name)));
+            }
+
+            let captures = captures(closure_body, &locals);
+            if async == false && captures.is_empty() == false {
+                let names = captures.iter().map(|c| format!("`{}`", c.name)).collect::<Vec<_>>();
+                diagnostics.push(Diagnostic::error(format!(
+                    "a closure in `{}` captures {}, which do not outlive the call",
+                    name, names.join(", "),
+                )).with_help(help.clone()));
+            }
+        }
+    }
+
+    diagnostics
+}
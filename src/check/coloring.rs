@@ -0,0 +1,164 @@
+use super::Diagnostic;
+use super::symbols::SymbolTable;
+use super::timers;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+/// Checks that only async functions suspend on calls, `join` or `select`,
+/// that every call to an async function, or to a plain function that
+/// suspends, is awaited or spawned, and that nothing else is awaited.
+/// Interrupts and plain functions may still await a `Continuation`, which
+/// invokes it.
+///
+/// Suspending on the built-in channels, locks, timers and generators is
+/// checked alongside the rest of their rules.
+pub fn check(nodes: &[TopLevelNode], symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for node in nodes.iter() {
+        let (name, body, async, interrupt) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async, .. } => (name, body, async, false),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body, false, true),
+            _ => continue,
+        };
+
+        let mut consumed: Vec<&Expression> = Vec::new();
+        walk::statements(body, &mut |statement| {
+            let suspends_on = match statement {
+                &Statement::Await(ref expr) | &Statement::LetAwait { ref expr, .. } => {
+                    let expr = match expr {
+                        &Expression::Boxed { ref task, .. } => &**task,
+                        expr => expr,
+                    };
+                    consumed.push(expr);
+                    match expr {
+                        &Expression::FnCall { ref target, .. } => match symbols.function(target) {
+                            Some(callee) if callee.async || callee.takes_implicit_continuation() => {
+                                Some(format!("`{}`", callee.c_name))
+                            }
+                            Some(callee) => {
+                                diagnostics.push(Diagnostic::error(format!(
+                                    "`{}` is awaited in `{}`, but is not async", callee.c_name, name,
+                                )).with_help(format!("call it without `await`, as `{}(...)`", callee.c_name)));
+                                None
+                            }
+                            None => None,
+                        },
+                        &Expression::Join { ref tasks } => {
+                            consumed.extend(tasks.iter());
+                            Some("a `join`".into())
+                        }
+                        _ => None,
+                    }
+                }
+                &Statement::Select(ref arms) => {
+                    consumed.extend(arms.iter().map(|arm| &arm.task));
+                    Some("a `select`".into())
+                }
+                &Statement::Spawn { ref task, .. } => {
+                    consumed.push(task);
+                    None
+                }
+                _ => None,
+            };
+            if let Some((_, task)) = walk::awaited(statement).and_then(|expr| timers::timeout_call(expr, symbols)) {
+                consumed.push(task);
+            }
+            if let Some(stream) = walk::streamed(statement) {
+                consumed.push(stream);
+            }
+
+            match suspends_on {
+                Some(ref awaited) if interrupt => {
+                    diagnostics.push(Diagnostic::error(format!("interrupt `{}` awaits {}", name, awaited))
+                        .with_help("interrupts cannot suspend; `spawn` a task that awaits it instead".into()));
+                }
+                Some(ref awaited) if async == false => {
+                    diagnostics.push(Diagnostic::error(format!("`{}` awaits {}, but is not async", name, awaited))
+                        .with_help(format!("declare it as `async {}(...)`, and await it wherever it is called", name)));
+                }
+                _ => {}
+            }
+        });
+
+        let mut calls: Vec<(&Expression, bool)> = Vec::new();
+        walk::expressions(body, &mut |expr| {
+            if let &Expression::FnCall { ref target, .. } = expr {
+                calls.push((&**target, consumed.iter().any(|c| ::std::ptr::eq(*c, expr))));
+            }
+        });
+        walk::statements(body, &mut |statement| {
+            if let &Statement::FnCall { ref target, .. } = statement {
+                calls.push((target, false));
+            }
+        });
+
+        for (target, is_consumed) in calls {
+            let callee = match symbols.function(target) {
+                Some(callee) if is_consumed == false && callee.generator == false => callee,
+                _ => continue,
+            };
+
+            if callee.suspends {
+                diagnostics.push(Diagnostic::error(format!(
+                    "`{}` suspends and must be awaited in `{}`", callee.c_name, name,
+                )).with_help(format!("write `await {}(...)`, which resumes once it has completed", callee.c_name)));
+            } else if callee.async {
+                let help = if async {
+                    format!("write `await {}(...)` to wait for it, or `spawn {}(...)` to run it alongside", callee.c_name, callee.c_name)
+                } else if interrupt {
+                    format!("write `spawn {}(...)` to run it as a task", callee.c_name)
+                } else {
+                    format!("write `spawn {}(...)` to run it as a task, or make `{}` async and await it", callee.c_name, name)
+                };
+                diagnostics.push(Diagnostic::error(format!(
+                    "async `{}` is called in `{}` without being awaited or spawned", callee.c_name, name,
+                )).with_help(help));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    #[test]
+    fn only_async_functions_suspend_on_tasks() {
+        let errors = errors(vec![
+            async_fn("read", vec![], unit(), vec![]),
+            plain_fn("log", vec![], unit(), vec![]),
+            plain_fn("poll", vec![], unit(), vec![Statement::Await(call("read", vec![]))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Await(call("read", vec![])),
+                Statement::Await(call("log", vec![])),
+            ]),
+            TopLevelNode::InterruptDecl { name: "uart_rx".into(), body: vec![Statement::Await(call("read", vec![]))] },
+        ]);
+        assert!(mentions(&errors, "`poll` awaits `read`, but is not async"), "{:?}", errors);
+        assert!(mentions(&errors, "`log` is awaited in `main`, but is not async"), "{:?}", errors);
+        assert!(mentions(&errors, "interrupt `uart_rx` awaits `read`"), "{:?}", errors);
+        assert!(mentions(&errors, "`main` awaits") == false, "{:?}", errors);
+    }
+
+    #[test]
+    fn async_calls_must_be_awaited_or_spawned() {
+        let (errors, _) = diagnostics(vec![
+            async_fn("read", vec![], unit(), vec![]),
+            async_fn("main", vec![], unit(), vec![
+                call_statement("read", vec![]),
+                Statement::Await(call("read", vec![])),
+                Statement::Spawn { task: call("read", vec![]), priority: None },
+            ]),
+            TopLevelNode::InterruptDecl { name: "uart_rx".into(), body: vec![call_statement("read", vec![])] },
+        ]);
+        let unawaited = errors.iter().filter(|error| error.contains("without being awaited or spawned")).collect::<Vec<_>>();
+        assert_eq!(unawaited, vec![
+            "async `read` is called in `main` without being awaited or spawned",
+            "async `read` is called in `uart_rx` without being awaited or spawned",
+        ], "{:?}", errors);
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::Diagnostic;
+use super::symbols::SymbolTable;
+use super::super::ast::format::FormatAst;
+use super::super::ast::nodes::*;
+use super::super::ast::visitable::Visitable;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConstValue {
+    Integer(i64),
+    Boolean(bool),
+    Float(f64),
+}
+
+impl ConstValue {
+    pub fn to_c_literal(&self) -> String {
+        match self {
+            &ConstValue::Integer(i) if i < 0 => format!("({})", i),
+            &ConstValue::Integer(i) => format!("{}", i),
+            &ConstValue::Boolean(b) => format!("{}", b),
+            &ConstValue::Float(f) => format!("{:?}", f),
+        }
+    }
+}
+
+impl fmt::Display for ConstValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &ConstValue::Integer(i) => write!(f, "{}", i),
+            &ConstValue::Boolean(b) => write!(f, "{}", b),
+            &ConstValue::Float(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+/// Evaluates every `const` declared in a module, recording the values in the
+/// symbol table, and checks that every array length and channel capacity is
+/// a valid constant.
+pub fn evaluate_constants<'a>(nodes: &'a [TopLevelNode], symbols: &mut SymbolTable<'a>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let values = {
+        let mut evaluator = Evaluator {
+            symbols,
+            declarations: HashMap::new(),
+            values: HashMap::new(),
+            in_progress: Vec::new(),
+            failed: HashSet::new(),
+        };
+
+        for node in nodes.iter() {
+            if let &TopLevelNode::ConstDecl { ref name, ref type_ref, ref expr, .. } = node {
+                evaluator.declarations.insert(name, (type_ref, expr));
+            }
+        }
+
+        for node in nodes.iter() {
+            if let &TopLevelNode::ConstDecl { ref name, .. } = node {
+                match evaluator.constant(name) {
+                    Err(ref message) if message.is_empty() == false => {
+                        diagnostics.push(Diagnostic::error(message.clone()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        evaluator.values
+    };
+
+    for (name, value) in values {
+        if let Some(constant) = symbols.constants.get_mut(name) {
+            constant.value = Some(value);
+        }
+    }
+
+    for node in nodes.iter() {
+        let var_decls = match node {
+            &TopLevelNode::GlobalDecl(ref var) => vec![var],
+            &TopLevelNode::FnDecl { ref params, .. } => params.iter().collect(),
+            _ => vec![],
+        };
+
+        for var in var_decls {
+            if let Err(message) = array_lengths(&var.type_ref, symbols) {
+                diagnostics.push(Diagnostic::error(format!("invalid constant in the type of `{}`: {}", var.name, message)));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// Evaluates a constant expression that may refer to already evaluated constants.
+pub fn evaluate(expr: &Expression, symbols: &SymbolTable) -> Result<ConstValue, String> {
+    Evaluator {
+        symbols,
+        declarations: HashMap::new(),
+        values: HashMap::new(),
+        in_progress: Vec::new(),
+        failed: HashSet::new(),
+    }.expression(expr)
+}
+
+pub fn array_length(length: &Expression, symbols: &SymbolTable) -> Result<u64, String> {
+    match evaluate(length, symbols)? {
+        ConstValue::Integer(n) if n >= 0 => Ok(n as u64),
+        ConstValue::Integer(n) => Err(format!("{} is negative", n)),
+        _ => Err("it must be an integer".into()),
+    }
+}
+
+fn array_lengths(type_ref: &TypeRef, symbols: &SymbolTable) -> Result<(), String> {
+    match type_ref {
+        &TypeRef::Array { ref element, ref length } => {
+            array_length(length, symbols)?;
+            array_lengths(element, symbols)
+        }
+        &TypeRef::Channel { ref element, ref capacity } => {
+            if array_length(capacity, symbols)? == 0 {
+                return Err("a channel needs room for at least one message".into());
+            }
+            array_lengths(element, symbols)
+        }
+        &TypeRef::Semaphore { ref initial } => {
+            // the count is an `unsigned`, which C only promises 16 bits of
+            if array_length(initial, symbols)? > u16::max_value() as u64 {
+                return Err(format!("a semaphore counts to at most {}", u16::max_value()));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+struct Evaluator<'a: 'b, 'b> {
+    symbols: &'b SymbolTable<'a>,
+    declarations: HashMap<&'a str, (&'a TypeRef, &'a Expression)>,
+    values: HashMap<&'a str, ConstValue>,
+    in_progress: Vec<&'a str>,
+    /// Constants whose evaluation has already been reported as failing.
+    failed: HashSet<&'a str>,
+}
+
+impl<'a, 'b> Evaluator<'a, 'b> {
+    /// Evaluates a constant declared in this module. An empty error message
+    /// means the failure has already been reported.
+    fn constant(&mut self, name: &'a str) -> Result<ConstValue, String> {
+        if let Some(value) = self.values.get(name) {
+            return Ok(*value);
+        }
+
+        if self.failed.contains(name) {
+            return Err(String::new());
+        }
+
+        if let Some(position) = self.in_progress.iter().position(|n| *n == name) {
+            let mut cycle = self.in_progress[position..].to_vec();
+            cycle.push(name);
+            return Err(format!("constant `{}` depends on itself: {}", name, cycle.join(" -> ")));
+        }
+
+        let (type_ref, expr) = self.declarations[name];
+        self.in_progress.push(name);
+        let result = self.expression(expr)
+            .and_then(|value| fit(value, type_ref))
+            .map_err(|message| {
+                if message.is_empty() || message.starts_with("constant ") {
+                    message
+                } else {
+                    format!("constant `{}`: {}", name, message)
+                }
+            });
+        self.in_progress.pop();
+
+        match result {
+            Ok(value) => {
+                self.values.insert(name, value);
+                Ok(value)
+            }
+            Err(message) => {
+                self.failed.insert(name);
+                Err(message)
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) -> Result<ConstValue, String> {
+        match expr {
+            &Expression::Literal(Literal::Integer(i)) => Ok(ConstValue::Integer(i)),
+            &Expression::Literal(Literal::Boolean(b)) => Ok(ConstValue::Boolean(b)),
+            &Expression::Literal(Literal::Float(f)) => Ok(ConstValue::Float(f)),
+            &Expression::Literal(Literal::String(..)) => Err("strings are not supported in constant expressions".into()),
+            &Expression::Identifier(ref name) => {
+                let declaration = self.declarations.keys().find(|n| **n == name.as_str()).cloned();
+                match declaration {
+                    Some(name) => self.constant(name),
+                    None => self.imported(expr),
+                }
+            }
+            &Expression::Path(..) => self.imported(expr),
+            &Expression::BinOp { ref left, ref operator, ref right } => {
+                let left = self.expression(left)?;
+                let right = self.expression(right)?;
+                binary_operation(left, operator, right)
+            }
+            &Expression::MemberOf { .. } | &Expression::FnCall { .. } |
+            &Expression::Closure { .. } | &Expression::Join { .. } | &Expression::Boxed { .. } => {
+                Err("only literals, operators and other constants are allowed in constant expressions".into())
+            }
+        }
+    }
+
+    fn imported(&self, expr: &Expression) -> Result<ConstValue, String> {
+        match self.symbols.constant(expr).and_then(|c| c.value) {
+            Some(value) => Ok(value),
+            None => Err(format!("`{}` is not a constant", display(expr))),
+        }
+    }
+}
+
+fn binary_operation(left: ConstValue, operator: &Operator, right: ConstValue) -> Result<ConstValue, String> {
+    use self::ConstValue::*;
+
+    let overflow = || format!("overflow evaluating `{} {} {}`", left, display(operator), right);
+    // integers are evaluated as `i64`, so the upper half of `u64` is out of
+    // reach; say so rather than report an overflow the C type would not have
+    let integer = |wide: i128| {
+        if wide > i64::max_value() as i128 && wide <= u64::max_value() as i128 {
+            Err(format!(
+                "`{} {} {}` is {}, but constants cannot exceed {}, even for `u64`",
+                left, display(operator), right, wide, i64::max_value(),
+            ))
+        } else if wide > i64::max_value() as i128 || wide < i64::min_value() as i128 {
+            Err(overflow())
+        } else {
+            Ok(Integer(wide as i64))
+        }
+    };
+
+    let result = match (left, right) {
+        (Integer(l), Integer(r)) => match operator {
+            &Operator::Add => integer(l as i128 + r as i128)?,
+            &Operator::Subtract => integer(l as i128 - r as i128)?,
+            &Operator::Multiply => integer(l as i128 * r as i128)?,
+            &Operator::Divide => {
+                if r == 0 {
+                    return Err("division by zero".into());
+                }
+                integer(l as i128 / r as i128)?
+            }
+            &Operator::ShiftLeft => {
+                if r < 0 || r >= 64 {
+                    return Err(format!("shift amount {} is out of range", r));
+                }
+                integer((l as i128) << r)?
+            }
+            &Operator::ShiftRight => {
+                if r < 0 || r >= 64 {
+                    return Err(format!("shift amount {} is out of range", r));
+                }
+                Integer(l >> r)
+            }
+            &Operator::BitwiseAnd => Integer(l & r),
+            &Operator::BitwiseXor => Integer(l ^ r),
+            &Operator::BitwiseOr => Integer(l | r),
+            &Operator::LessThan => Boolean(l < r),
+            &Operator::LessThanEqual => Boolean(l <= r),
+            &Operator::GreaterThan => Boolean(l > r),
+            &Operator::GreaterThanEqual => Boolean(l >= r),
+            &Operator::Equal => Boolean(l == r),
+            &Operator::NotEqual => Boolean(l != r),
+            &Operator::LogicalAnd | &Operator::LogicalOr => {
+                return Err(format!("`{}` requires boolean operands", display(operator)));
+            }
+        },
+        (Float(l), Float(r)) => {
+            let result = match operator {
+                &Operator::Add => Float(l + r),
+                &Operator::Subtract => Float(l - r),
+                &Operator::Multiply => Float(l * r),
+                &Operator::Divide => {
+                    if r == 0.0 {
+                        return Err("division by zero".into());
+                    }
+                    Float(l / r)
+                }
+                &Operator::LessThan => Boolean(l < r),
+                &Operator::LessThanEqual => Boolean(l <= r),
+                &Operator::GreaterThan => Boolean(l > r),
+                &Operator::GreaterThanEqual => Boolean(l >= r),
+                &Operator::Equal => Boolean(l == r),
+                &Operator::NotEqual => Boolean(l != r),
+                _ => return Err(format!("`{}` cannot be applied to floats", display(operator))),
+            };
+            match result {
+                Float(f) if f.is_finite() == false => return Err(overflow()),
+                result => result,
+            }
+        }
+        (Boolean(l), Boolean(r)) => match operator {
+            &Operator::LogicalAnd => Boolean(l && r),
+            &Operator::LogicalOr => Boolean(l || r),
+            &Operator::BitwiseAnd => Boolean(l & r),
+            &Operator::BitwiseXor => Boolean(l ^ r),
+            &Operator::BitwiseOr => Boolean(l | r),
+            &Operator::Equal => Boolean(l == r),
+            &Operator::NotEqual => Boolean(l != r),
+            _ => return Err(format!("`{}` cannot be applied to booleans", display(operator))),
+        },
+        _ => return Err(format!("mismatched operand types for `{}`", display(operator))),
+    };
+
+    Ok(result)
+}
+
+/// Checks that a value can be represented by the declared type of a constant.
+fn fit(value: ConstValue, type_ref: &TypeRef) -> Result<ConstValue, String> {
+    let type_name = match type_ref {
+        &TypeRef::Named { ref name, .. } => name.as_str(),
+        _ => return Err("constants must have a scalar type".into()),
+    };
+
+    let range = match type_name {
+        "u8" => Some((0, u8::max_value() as i64)),
+        "u16" => Some((0, u16::max_value() as i64)),
+        "u32" => Some((0, u32::max_value() as i64)),
+        "u64" => Some((0, i64::max_value())),
+        "i8" => Some((i8::min_value() as i64, i8::max_value() as i64)),
+        "i16" => Some((i16::min_value() as i64, i16::max_value() as i64)),
+        "i32" => Some((i32::min_value() as i64, i32::max_value() as i64)),
+        "i64" => Some((i64::min_value(), i64::max_value())),
+        _ => None,
+    };
+
+    match (value, type_name, range) {
+        (ConstValue::Integer(i), _, Some((min, max))) => {
+            if i < min || i > max {
+                Err(format!("value {} overflows `{}`", i, type_name))
+            } else {
+                Ok(value)
+            }
+        }
+        (ConstValue::Float(f), "f32", _) if f.abs() > ::std::f32::MAX as f64 => {
+            Err(format!("value {} overflows `f32`", f))
+        }
+        (ConstValue::Float(..), "f32", _) | (ConstValue::Float(..), "f64", _) => Ok(value),
+        (ConstValue::Boolean(..), "bool", _) => Ok(value),
+        _ => Err(format!("{} cannot be stored in `{}`", value, type_name)),
+    }
+}
+
+/// Formats an AST node as source code for use in diagnostics.
+pub fn display<V: Visitable>(node: &V) -> String {
+    let mut buffer = Vec::new();
+    {
+        let mut format = FormatAst::new(&mut buffer);
+        node.visit_mut(&mut format);
+    }
+    String::from_utf8_lossy(&buffer).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate_constants, ConstValue};
+    use super::super::symbols::SymbolTable;
+    use super::super::super::ast::nodes::*;
+    use super::super::super::module::Module;
+    use super::super::super::testing::*;
+
+    fn constant(name: &str, type_ref: TypeRef, expr: Expression) -> TopLevelNode {
+        TopLevelNode::ConstDecl { name: name.into(), type_ref, expr, public: false }
+    }
+
+    fn binop(left: Expression, operator: Operator, right: Expression) -> Expression {
+        Expression::BinOp { left: Box::new(left), operator, right: Box::new(right) }
+    }
+
+    #[test]
+    fn overflow_and_division_by_zero_are_reported() {
+        let errors = errors(vec![
+            constant("HUGE", ty("i64"), binop(int(i64::max_value()), Operator::Multiply, int(3))),
+            constant("RATE", ty("u32"), binop(int(1000), Operator::Divide, int(0))),
+        ]);
+        assert!(mentions(&errors, "constant `HUGE`: overflow evaluating `9223372036854775807 * 3`"), "{:?}", errors);
+        assert!(mentions(&errors, "constant `RATE`: division by zero"), "{:?}", errors);
+    }
+
+    #[test]
+    fn values_beyond_i64_are_rejected_even_for_u64() {
+        let errors = errors(vec![
+            constant("TOP_BIT", ty("u64"), binop(int(1), Operator::ShiftLeft, int(63))),
+        ]);
+        assert_eq!(errors, vec![
+            "constant `TOP_BIT`: `1 << 63` is 9223372036854775808, but constants cannot exceed 9223372036854775807, even for `u64`".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn cyclic_constants_are_reported_once() {
+        let errors = errors(vec![
+            constant("A", ty("u32"), add(ident("B"), int(1))),
+            constant("B", ty("u32"), ident("A")),
+        ]);
+        assert_eq!(errors, vec!["constant `A` depends on itself: A -> B -> A".to_string()]);
+    }
+
+    #[test]
+    fn values_must_fit_the_declared_type() {
+        let errors = errors(vec![
+            constant("SMALL", ty("u8"), int(256)),
+            constant("NEGATIVE", ty("u32"), binop(int(0), Operator::Subtract, int(1))),
+            constant("FLAG", ty("bool"), int(1)),
+            constant("EDGE", ty("i8"), int(-128)),
+        ]);
+        assert!(mentions(&errors, "constant `SMALL`: value 256 overflows `u8`"), "{:?}", errors);
+        assert!(mentions(&errors, "constant `NEGATIVE`: value -1 overflows `u32`"), "{:?}", errors);
+        assert!(mentions(&errors, "constant `FLAG`: 1 cannot be stored in `bool`"), "{:?}", errors);
+        assert!(mentions(&errors, "`EDGE`") == false, "{:?}", errors);
+    }
+
+    #[test]
+    fn pub_constants_of_imported_modules_are_reached_through_their_alias() {
+        let util = Module { path: vec!["util".into()], nodes: vec![
+            TopLevelNode::ConstDecl { name: "PERIOD".into(), type_ref: ty("u32"), expr: int(10), public: true },
+            constant("SECRET", ty("u32"), int(3)),
+        ] };
+        let root = Module::root(vec![
+            constant("TWICE", ty("u32"), binop(Expression::Path(vec!["util".into(), "PERIOD".into()]), Operator::Multiply, int(2))),
+            constant("HIDDEN", ty("u32"), Expression::Path(vec!["util".into(), "SECRET".into()])),
+        ]);
+        let (mut util_symbols, _) = SymbolTable::build(&util);
+        assert!(evaluate_constants(&util.nodes, &mut util_symbols).is_empty());
+        let (mut symbols, _) = SymbolTable::build(&root);
+        assert!(symbols.import(&util, &util_symbols).is_empty());
+
+        let messages = evaluate_constants(&root.nodes, &mut symbols).into_iter().map(|d| d.message).collect::<Vec<_>>();
+        assert_eq!(symbols.constants["TWICE"].value, Some(ConstValue::Integer(20)));
+        assert_eq!(messages, vec!["constant `HIDDEN`: `util::SECRET` is not a constant".to_string()]);
+    }
+}
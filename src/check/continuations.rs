@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+
+use petgraph::{Direction, stable_graph::NodeIndex};
+
+use super::Diagnostic;
+use super::symbols::SymbolTable;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+use super::super::cfg::builder::Builder;
+
+/// The built-in type of the rest of a suspended task, which resumes it when
+/// awaited. Each continuation must be invoked exactly once.
+pub const CONTINUATION: &str = "Continuation";
+
+pub fn is_continuation(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Named { ref name, .. } => name == CONTINUATION,
+        _ => false,
+    }
+}
+
+/// The continuation a plain function hands out with a `suspend` at the top
+/// level of its body, which makes the function complete asynchronously.
+pub fn suspended(body: &[Statement]) -> Option<&str> {
+    body.iter()
+        .filter_map(|statement| match statement {
+            &Statement::Suspend { ref continuation, .. } => Some(continuation.as_str()),
+            _ => None,
+        })
+        .next()
+}
+
+/// The variable may hold a continuation that has not been invoked yet.
+const HELD: u8 = 1;
+/// The variable may hold a continuation that has already been invoked or
+/// handed on.
+const SPENT: u8 = 2;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Owner {
+    Param,
+    Local,
+    Global,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+enum Misuse {
+    Reused,
+    Overwritten,
+    Dropped,
+}
+
+/// Checks that `Continuation` is used as a plain type, and follows every
+/// continuation stored in a parameter, local or global through the control
+/// flow graph of each function, warning where one may be invoked twice or
+/// lost without being invoked.
+pub fn check(nodes: &[TopLevelNode], symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for node in nodes.iter() {
+        let declared: Vec<&VarDecl> = match node {
+            &TopLevelNode::GlobalDecl(ref var) | &TopLevelNode::ExternGlobalDecl { ref var, .. } => vec![var],
+            &TopLevelNode::FnDecl { ref params, ref body, .. } => {
+                params.iter().chain(walk::let_bindings(body)).collect()
+            }
+            &TopLevelNode::ExternFnDecl { ref params, .. } => params.iter().collect(),
+            &TopLevelNode::InterruptDecl { ref body, .. } => walk::let_bindings(body),
+            _ => vec![],
+        };
+        for var in declared {
+            if let &TypeRef::Named { ref type_params, .. } = &var.type_ref {
+                if is_continuation(&var.type_ref) && type_params.is_empty() == false {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}` is declared as a `{}`, which takes no type parameters", var.name, CONTINUATION,
+                    )));
+                }
+            }
+        }
+
+        let (name, params, body, async, interrupt) = match node {
+            &TopLevelNode::FnDecl { ref name, ref params, ref body, async, .. } => (name, &params[..], body, async, false),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, &[][..], body, false, true),
+            _ => continue,
+        };
+        diagnostics.extend(check_suspends(name, body, async, interrupt));
+
+        let mut tracked: Vec<(&str, Owner)> = Vec::new();
+        for param in params.iter().filter(|p| is_continuation(&p.type_ref)) {
+            tracked.push((&param.name, Owner::Param));
+        }
+        let mut locals = walk::let_bindings(body).into_iter()
+            .filter(|l| is_continuation(&l.type_ref))
+            .map(|l| l.name.as_str())
+            .collect::<Vec<_>>();
+        walk::statements(body, &mut |statement| {
+            if let &Statement::Suspend { ref continuation, .. } = statement {
+                locals.push(continuation);
+            }
+        });
+        for local in locals {
+            if tracked.iter().any(|&(name, _)| name == local) == false {
+                tracked.push((local, Owner::Local));
+            }
+        }
+        let mut globals = symbols.globals.iter()
+            .filter(|&(_, global)| is_continuation(&global.var.type_ref))
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        globals.sort();
+        for global in globals {
+            if tracked.iter().any(|&(name, _)| name == global) == false {
+                tracked.push((global, Owner::Global));
+            }
+        }
+        if tracked.is_empty() {
+            continue;
+        }
+
+        let linearity = Linearity { tracked };
+        for (var, misuse) in linearity.misuses(name, body) {
+            let var = linearity.tracked[var].0;
+            diagnostics.push(match misuse {
+                Misuse::Reused => {
+                    Diagnostic::warning(format!("`{}` may be invoked more than once in `{}`", var, name))
+                        .with_help("a continuation resumes its task exactly once; a second invocation runs code the task has already passed".into())
+                }
+                Misuse::Overwritten => {
+                    Diagnostic::warning(format!("`{}` in `{}` may be overwritten before it is invoked", var, name))
+                        .with_help("the task waiting on the continuation it held never resumes".into())
+                }
+                Misuse::Dropped => {
+                    Diagnostic::warning(format!("`{}` in `{}` may be dropped without being invoked", var, name))
+                        .with_help(format!("await `{}`, or store it where an interrupt will, so that its task resumes", var))
+                }
+            });
+        }
+    }
+
+    diagnostics
+}
+
+/// A forward dataflow over the CFG of one function, tracking for each
+/// continuation variable whether it may be held or spent.
+struct Linearity<'a> {
+    tracked: Vec<(&'a str, Owner)>,
+}
+
+impl<'a> Linearity<'a> {
+    /// The misuses of tracked variables in `body`, by variable index.
+    fn misuses(&self, name: &str, body: &[Statement]) -> Vec<(usize, Misuse)> {
+        let cfg = Builder::new(name).build(body);
+
+        let entry = self.tracked.iter()
+            .map(|&(_, owner)| if owner == Owner::Param { HELD } else { 0 })
+            .collect::<Vec<_>>();
+        let mut states: HashMap<NodeIndex, Vec<u8>> = HashMap::new();
+        states.insert(cfg.entry_node, entry);
+
+        let mut pending = vec![cfg.entry_node];
+        while let Some(idx) = pending.pop() {
+            let mut state = states[&idx].clone();
+            for statement in cfg.graph[idx].statements.iter() {
+                self.transfer(statement, &mut state, &mut Vec::new());
+            }
+
+            for next in cfg.graph.neighbors_directed(idx, Direction::Outgoing) {
+                let changed = match states.get_mut(&next) {
+                    Some(existing) => {
+                        let merged = existing.iter().zip(state.iter()).map(|(a, b)| a | b).collect::<Vec<_>>();
+                        let changed = merged != *existing;
+                        *existing = merged;
+                        changed
+                    }
+                    None => true,
+                };
+                if changed {
+                    states.entry(next).or_insert_with(|| state.clone());
+                    pending.push(next);
+                }
+            }
+        }
+
+        let mut misuses = Vec::new();
+        for (&idx, state) in states.iter() {
+            let mut state = state.clone();
+            for statement in cfg.graph[idx].statements.iter() {
+                self.transfer(statement, &mut state, &mut misuses);
+            }
+        }
+        if let Some(state) = cfg.exit_node.and_then(|exit| states.get(&exit)) {
+            for (i, &(_, owner)) in self.tracked.iter().enumerate() {
+                if owner != Owner::Global && state[i] & HELD != 0 {
+                    misuses.push((i, Misuse::Dropped));
+                }
+            }
+        }
+
+        misuses.sort();
+        misuses.dedup();
+        misuses
+    }
+
+    /// Applies the effect of a statement on the tracked continuations:
+    /// awaiting one or passing it on spends it, and assigning one holds it.
+    /// The continuation a `suspend` hands out must be spent by its body.
+    fn transfer(&self, statement: &Statement, state: &mut [u8], misuses: &mut Vec<(usize, Misuse)>) {
+        match statement {
+            &Statement::Suspend { ref continuation, ref body } => {
+                let i = self.tracked.iter()
+                    .position(|&(name, _)| name == continuation)
+                    .expect("the continuation of every `suspend` is tracked");
+                self.hold(i, state, misuses);
+                for statement in body.iter() {
+                    self.transfer(statement, state, misuses);
+                }
+                if state[i] & HELD != 0 {
+                    misuses.push((i, Misuse::Dropped));
+                }
+            }
+            &Statement::Atomic(ref body) => {
+                for statement in body.iter() {
+                    self.transfer(statement, state, misuses);
+                }
+            }
+            &Statement::Await(ref expr) if self.variable(expr).is_some() => {
+                self.spend(self.variable(expr).unwrap(), state, misuses);
+            }
+            &Statement::Let { ref var, ref expr } => {
+                self.pass_on(expr, state, misuses);
+                if let Some(i) = self.tracked.iter().position(|&(name, _)| name == var.name) {
+                    self.hold(i, state, misuses);
+                }
+            }
+            &Statement::Return(ref expr) => self.pass_on(expr, state, misuses),
+            &Statement::Assignment { ref target, ref expr } => {
+                self.pass_on(expr, state, misuses);
+                if let Some(i) = self.variable(target) {
+                    self.hold(i, state, misuses);
+                }
+            }
+            _ => {}
+        }
+
+        for expr in walk::statement_expressions(statement) {
+            walk::subexpressions(expr, &mut |expr| {
+                if let &Expression::FnCall { ref args, .. } = expr {
+                    for arg in args.iter() {
+                        self.pass_on(arg, state, misuses);
+                    }
+                }
+            });
+        }
+    }
+
+    fn pass_on(&self, expr: &Expression, state: &mut [u8], misuses: &mut Vec<(usize, Misuse)>) {
+        if let Some(i) = self.variable(expr) {
+            self.spend(i, state, misuses);
+        }
+    }
+
+    fn spend(&self, i: usize, state: &mut [u8], misuses: &mut Vec<(usize, Misuse)>) {
+        if state[i] & SPENT != 0 {
+            misuses.push((i, Misuse::Reused));
+        }
+        state[i] = SPENT;
+    }
+
+    fn hold(&self, i: usize, state: &mut [u8], misuses: &mut Vec<(usize, Misuse)>) {
+        if state[i] & HELD != 0 {
+            misuses.push((i, Misuse::Overwritten));
+        }
+        state[i] = HELD;
+    }
+
+    fn variable(&self, expr: &Expression) -> Option<usize> {
+        match expr {
+            &Expression::Identifier(ref name) => self.tracked.iter().position(|&(tracked, _)| tracked == name),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that only functions suspend, that a plain function suspends as the
+/// last thing it does, and that the body of a `suspend` hands out its
+/// continuation without suspending again.
+fn check_suspends(name: &str, body: &[Statement], async: bool, interrupt: bool) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let last = body.last();
+
+    walk::statements(body, &mut |statement| {
+        let suspend_body = match statement {
+            &Statement::Suspend { ref body, .. } => body,
+            _ => return,
+        };
+
+        if interrupt {
+            diagnostics.push(Diagnostic::error(format!("`suspend` in interrupt `{}`", name))
+                .with_help("interrupts cannot suspend; hand out the continuation in a function the task awaits".into()));
+        } else if async == false && last.map(|last| ::std::ptr::eq(last, statement)).unwrap_or(false) == false {
+            diagnostics.push(Diagnostic::error(format!("`suspend` in `{}` is not the last statement of the function", name))
+                .with_help("a plain function completes by suspending; move the code after it into the `suspend` block".into()));
+        }
+
+        let mut suspends = false;
+        walk::statements(suspend_body, &mut |nested| {
+            match nested {
+                &Statement::Select(..) | &Statement::Yield(..) | &Statement::ForAwait { .. } => suspends = true,
+                &Statement::Suspend { .. } => suspends = true,
+                &Statement::Await(Expression::Identifier(..)) => {}
+                _ if walk::awaited(nested).is_some() => suspends = true,
+                _ => {}
+            }
+        });
+        if suspends {
+            diagnostics.push(Diagnostic::error(format!("the body of a `suspend` in `{}` suspends", name))
+                .with_help("it runs before the task suspends, and can only store or invoke the continuation".into()));
+        }
+    });
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    fn continuation() -> TypeRef {
+        ty("Continuation")
+    }
+
+    #[test]
+    fn continuations_invoked_exactly_once_are_accepted() {
+        let (errors, warnings) = diagnostics(vec![
+            TopLevelNode::GlobalDecl(var("ready", continuation())),
+            plain_fn("wake", vec![var("k", continuation())], unit(), vec![Statement::Await(ident("k"))]),
+            plain_fn("arm", vec![var("k", continuation())], unit(), vec![
+                Statement::Assignment { target: ident("ready"), expr: ident("k") },
+            ]),
+        ]);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn continuations_reused_overwritten_or_dropped_are_reported() {
+        let warnings = warnings(vec![
+            TopLevelNode::GlobalDecl(var("ready", continuation())),
+            plain_fn("twice", vec![var("k", continuation())], unit(), vec![
+                Statement::Await(ident("k")),
+                Statement::Await(ident("k")),
+            ]),
+            plain_fn("arm", vec![var("a", continuation()), var("b", continuation())], unit(), vec![
+                Statement::Assignment { target: ident("ready"), expr: ident("a") },
+                Statement::Assignment { target: ident("ready"), expr: ident("b") },
+            ]),
+            plain_fn("forget", vec![var("k", continuation())], unit(), vec![]),
+        ]);
+        assert!(mentions(&warnings, "`k` may be invoked more than once in `twice`"), "{:?}", warnings);
+        assert!(mentions(&warnings, "`ready` in `arm` may be overwritten before it is invoked"), "{:?}", warnings);
+        assert!(mentions(&warnings, "`k` in `forget` may be dropped without being invoked"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn continuations_take_no_type_parameters() {
+        let errors = errors(vec![
+            TopLevelNode::GlobalDecl(var("ready", TypeRef::Named { name: "Continuation".into(), type_params: vec![ty("u8")] })),
+        ]);
+        assert!(mentions(&errors, "`ready` is declared as a `Continuation`, which takes no type parameters"), "{:?}", errors);
+    }
+
+    fn suspend(continuation: &str, body: Vec<Statement>) -> Statement {
+        Statement::Suspend { continuation: continuation.into(), body }
+    }
+
+    #[test]
+    fn suspends_end_plain_functions_and_hand_out_their_continuation() {
+        let (errors, warnings) = diagnostics(vec![
+            async_fn("poll", vec![], unit(), vec![]),
+            TopLevelNode::GlobalDecl(var("ready", continuation())),
+            plain_fn("delay", vec![], unit(), vec![
+                suspend("k", vec![Statement::Assignment { target: ident("ready"), expr: ident("k") }]),
+            ]),
+            plain_fn("early", vec![], unit(), vec![
+                suspend("k", vec![Statement::Await(ident("k"))]),
+                call_statement("delay", vec![]),
+            ]),
+            plain_fn("nested", vec![], unit(), vec![
+                suspend("k", vec![Statement::Await(call("poll", vec![])), Statement::Await(ident("k"))]),
+            ]),
+            plain_fn("lost", vec![], unit(), vec![suspend("k", vec![])]),
+            TopLevelNode::InterruptDecl { name: "tick".into(), body: vec![suspend("k", vec![Statement::Await(ident("k"))])] },
+        ]);
+        assert!(mentions(&errors, "`suspend` in `early` is not the last statement of the function"), "{:?}", errors);
+        assert!(mentions(&errors, "the body of a `suspend` in `nested` suspends"), "{:?}", errors);
+        assert!(mentions(&errors, "`suspend` in interrupt `tick`"), "{:?}", errors);
+        assert!(mentions(&errors, "`delay` suspends and must be awaited in `early`"), "{:?}", errors);
+        assert!(mentions(&errors, "in `delay`") == false, "{:?}", errors);
+        assert!(mentions(&warnings, "`k` in `lost` may be dropped without being invoked"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn stored_continuations_are_taken_out_when_invoked() {
+        let output = compile(vec![
+            TopLevelNode::GlobalDecl(var("ready", continuation())),
+            TopLevelNode::InterruptDecl { name: "timer_overflow".into(), body: vec![Statement::Await(ident("ready"))] },
+        ]);
+        assert!(output.source.contains("INTERRUPT(timer_overflow) {\n    Continuation_invoke(Continuation_take(&globals.ready));\n}"), "{}", output.source);
+    }
+}
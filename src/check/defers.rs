@@ -0,0 +1,80 @@
+use super::Diagnostic;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+/// Checks that `defer` blocks only appear where the generated code can run
+/// them: directly in the body of an async function, without suspending.
+pub fn check(nodes: &[TopLevelNode]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for node in nodes.iter() {
+        let (name, body, async) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async, .. } => (name, body, async),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body, false),
+            _ => continue,
+        };
+
+        let top_level = body.iter()
+            .filter(|statement| is_defer(statement))
+            .collect::<Vec<_>>();
+
+        walk::statements(body, &mut |statement| {
+            let defer_body = match statement {
+                &Statement::Defer(ref defer_body) => defer_body,
+                _ => return,
+            };
+
+            if async == false {
+                diagnostics.push(Diagnostic::error(format!("`defer` in `{}`, which is not async", name))
+                    .with_help("only tasks can be cancelled; run the cleanup at the end of the function instead".into()));
+            } else if top_level.iter().any(|d| ::std::ptr::eq(*d, statement)) == false {
+                diagnostics.push(Diagnostic::error(format!("`defer` nested inside another statement in `{}`", name))
+                    .with_help("move the `defer` to the top level of the function body".into()));
+            }
+
+            let mut suspends = false;
+            walk::statements(defer_body, &mut |nested| {
+                match nested {
+                    &Statement::Select(..) | &Statement::Yield(..) | &Statement::ForAwait { .. } |
+                    &Statement::Suspend { .. } => suspends = true,
+                    _ if walk::awaited(nested).is_some() => suspends = true,
+                    _ => {}
+                }
+            });
+            if suspends {
+                diagnostics.push(Diagnostic::error(format!("a `defer` in `{}` suspends", name))
+                    .with_help("cleanup runs while the task is being cancelled and cannot suspend".into()));
+            }
+        });
+    }
+
+    diagnostics
+}
+
+fn is_defer(statement: &Statement) -> bool {
+    match statement {
+        &Statement::Defer(..) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    #[test]
+    fn defers_run_only_at_the_top_of_tasks_and_never_suspend() {
+        let errors = errors(vec![
+            async_fn("poll", vec![], unit(), vec![]),
+            plain_fn("reset", vec![], unit(), vec![Statement::Defer(vec![])]),
+            async_fn("nested", vec![], unit(), vec![Statement::Loop(vec![Statement::Defer(vec![])])]),
+            async_fn("flush", vec![], unit(), vec![Statement::Defer(vec![Statement::Await(call("poll", vec![]))])]),
+            async_fn("main", vec![], unit(), vec![Statement::Defer(vec![]), Statement::Await(call("poll", vec![]))]),
+        ]);
+        assert!(mentions(&errors, "`defer` in `reset`, which is not async"), "{:?}", errors);
+        assert!(mentions(&errors, "`defer` nested inside another statement in `nested`"), "{:?}", errors);
+        assert!(mentions(&errors, "a `defer` in `flush` suspends"), "{:?}", errors);
+        assert!(mentions(&errors, "`main`") == false, "{:?}", errors);
+    }
+}
@@ -0,0 +1,143 @@
+use super::Diagnostic;
+use super::symbols::SymbolTable;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+/// Checks that generators are async and declare what they yield, that only
+/// generators `yield`, and that generators are only ever consumed by a
+/// `for await` loop in an async function.
+pub fn check(nodes: &[TopLevelNode], symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for node in nodes.iter() {
+        if let &TopLevelNode::FnDecl { ref name, ref returns, async, generator: true, .. } = node {
+            if async == false {
+                diagnostics.push(Diagnostic::error(format!("generator `{}` must be async", name))
+                    .with_help("declare it as `async gen`".into()));
+            }
+            if is_unit(returns) {
+                diagnostics.push(Diagnostic::error(format!("generator `{}` does not declare what it yields", name))
+                    .with_help(format!("declare the type of its values, e.g. `async gen {}() -> u8`", name)));
+            }
+        }
+
+        let (name, body, async, generator) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async, generator, .. } => (name, body, async, generator),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body, false, false),
+            _ => continue,
+        };
+
+        let mut streams = Vec::new();
+        walk::statements(body, &mut |statement| {
+            match statement {
+                &Statement::Yield(..) if generator == false => {
+                    diagnostics.push(Diagnostic::error(format!("`yield` in `{}`, which is not a generator", name))
+                        .with_help(format!("declare `{}` as `async gen` to produce a stream of values", name)));
+                }
+                &Statement::ForAwait { ref stream, .. } => {
+                    streams.push(stream);
+                    if async == false {
+                        diagnostics.push(Diagnostic::error(format!("`for await` in `{}`, which is not async", name)));
+                    }
+
+                    let callee = match stream {
+                        &Expression::FnCall { ref target, .. } => symbols.function(target),
+                        _ => None,
+                    };
+                    match callee {
+                        Some(callee) if callee.generator => {}
+                        Some(callee) => {
+                            diagnostics.push(Diagnostic::error(format!(
+                                "`for await` in `{}` consumes `{}`, which is not a generator", name, callee.c_name,
+                            )));
+                        }
+                        None => {
+                            diagnostics.push(Diagnostic::error(format!(
+                                "`for await` in `{}` can only consume a call to a generator", name,
+                            )));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        });
+
+        walk::expressions(body, &mut |expr| {
+            let target = match expr {
+                &Expression::FnCall { ref target, .. } => target,
+                _ => return,
+            };
+            match symbols.function(target) {
+                Some(callee) if callee.generator && streams.iter().any(|s| ::std::ptr::eq(*s, expr)) == false => {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "generator `{}` is called in `{}` outside of a `for await` loop", callee.c_name, name,
+                    )).with_help(format!("consume it with `for await x in {}(...) {{ ... }}`", callee.c_name)));
+                }
+                _ => {}
+            }
+        });
+        walk::statements(body, &mut |statement| {
+            if let &Statement::FnCall { ref target, .. } = statement {
+                if let Some(callee) = symbols.function(target).filter(|callee| callee.generator) {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "generator `{}` is called in `{}` outside of a `for await` loop", callee.c_name, name,
+                    )).with_help(format!("consume it with `for await x in {}(...) {{ ... }}`", callee.c_name)));
+                }
+            }
+        });
+    }
+
+    diagnostics
+}
+
+fn is_unit(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Tuple { ref type_refs } => type_refs.is_empty(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    fn generator(name: &str, returns: TypeRef, async: bool) -> TopLevelNode {
+        TopLevelNode::FnDecl {
+            name: name.into(), params: vec![], returns, body: vec![Statement::Yield(int(1))],
+            async, public: false, priority: None, generator: true,
+        }
+    }
+
+    fn for_await(stream: Expression) -> Statement {
+        Statement::ForAwait { var: var("x", ty("u8")), stream, body: vec![] }
+    }
+
+    #[test]
+    fn generators_are_async_and_declare_what_they_yield() {
+        let errors = errors(vec![
+            generator("ticks", unit(), true),
+            generator("bytes", ty("u8"), false),
+        ]);
+        assert!(mentions(&errors, "generator `ticks` does not declare what it yields"), "{:?}", errors);
+        assert!(mentions(&errors, "generator `bytes` must be async"), "{:?}", errors);
+    }
+
+    #[test]
+    fn only_generators_yield_and_only_for_await_consumes_them() {
+        let errors = errors(vec![
+            generator("bytes", ty("u8"), true),
+            async_fn("read", vec![], ty("u8"), vec![Statement::Yield(int(1))]),
+            plain_fn("poll", vec![], unit(), vec![for_await(call("bytes", vec![]))]),
+            async_fn("main", vec![], unit(), vec![
+                for_await(call("bytes", vec![])),
+                for_await(call("read", vec![])),
+                call_statement("bytes", vec![]),
+            ]),
+        ]);
+        assert!(mentions(&errors, "`yield` in `read`, which is not a generator"), "{:?}", errors);
+        assert!(mentions(&errors, "`for await` in `poll`, which is not async"), "{:?}", errors);
+        assert!(mentions(&errors, "`for await` in `main` consumes `read`, which is not a generator"), "{:?}", errors);
+        assert!(mentions(&errors, "generator `bytes` is called in `main` outside of a `for await` loop"), "{:?}", errors);
+    }
+}
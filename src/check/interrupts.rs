@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+
+use super::Diagnostic;
+use super::consteval::display;
+use super::continuations;
+use super::locks;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+use super::super::options::{Dispatch, Options};
+
+/// Types that targets read and write in a single instruction, which an
+/// interrupt cannot split. A wider global can be seen half-written.
+const WORD_TYPES: &[&str] = &["u8", "u16", "u32", "i8", "i16", "i32", "f32", "bool", "str"];
+
+/// A read or write of a global.
+struct Access<'a> {
+    global: &'a str,
+    write: bool,
+    /// The `atomic` block the access is in, numbered in source order.
+    atomic: Option<usize>,
+}
+
+/// Finds the globals that interrupt handlers share with tasks and the main
+/// loop, and warns where code outside the handlers accesses them in a way an
+/// interrupt can break: reading and then writing one outside a single
+/// `atomic` block, or touching one too wide to be accessed at once. Also
+/// checks that `atomic` blocks do not suspend.
+///
+/// Functions called from a handler run in its context, and everything else
+/// reachable from a task or an uncalled function runs outside of it.
+/// Channels and locks guard themselves and are not considered.
+pub fn check(nodes: &[TopLevelNode]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let mut globals: Vec<(&str, &TypeRef)> = Vec::new();
+    let mut functions: Vec<(&str, &[Statement], &[VarDecl], bool)> = Vec::new();
+    let mut interrupts: Vec<(&str, &[Statement])> = Vec::new();
+    for node in nodes.iter() {
+        match node {
+            &TopLevelNode::GlobalDecl(ref var) | &TopLevelNode::ExternGlobalDecl { ref var, .. } => {
+                if guards_itself(&var.type_ref) == false {
+                    globals.push((&var.name, &var.type_ref));
+                }
+            }
+            &TopLevelNode::FnDecl { ref name, ref body, ref params, async, .. } => {
+                functions.push((name, body, params, async));
+            }
+            &TopLevelNode::InterruptDecl { ref name, ref body } => interrupts.push((name, body)),
+            _ => {}
+        }
+
+        let (name, body) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, .. } => (name, body),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body),
+            _ => continue,
+        };
+        walk::statements(body, &mut |statement| {
+            if let &Statement::Atomic(ref atomic_body) = statement {
+                let mut suspends = false;
+                walk::statements(atomic_body, &mut |nested| {
+                    match nested {
+                        &Statement::Select(..) | &Statement::Yield(..) | &Statement::ForAwait { .. } => suspends = true,
+                        &Statement::Suspend { .. } => suspends = true,
+                        _ if walk::awaited(nested).is_some() => suspends = true,
+                        _ => {}
+                    }
+                });
+                if suspends {
+                    diagnostics.push(Diagnostic::error(format!("an `atomic` block in `{}` suspends", name))
+                        .with_help("interrupts stay disabled until the block ends; move the `await` after it".into()));
+                }
+            }
+        });
+    }
+
+    let is_function = |name: &str| functions.iter().any(|&(f, ..)| f == name);
+    let calls = functions.iter()
+        .map(|&(name, body, ..)| (name, called(body, &is_function)))
+        .collect::<HashMap<_, _>>();
+
+    // The interrupts each plain function may run in.
+    let mut in_interrupts: HashMap<&str, Vec<&str>> = HashMap::new();
+    for &(interrupt, body) in interrupts.iter() {
+        let mut pending = called(body, &is_function);
+        while let Some(name) = pending.pop() {
+            let async = functions.iter().any(|&(f, _, _, async)| f == name && async);
+            let reached = in_interrupts.entry(name).or_insert_with(Vec::new);
+            if async || reached.contains(&interrupt) {
+                continue;
+            }
+            reached.push(interrupt);
+            pending.extend(calls[name].iter().cloned());
+        }
+    }
+
+    // The functions that run outside of interrupts: tasks, functions nothing
+    // else calls, such as `init`, and whatever those call.
+    let mut outside = Vec::new();
+    let mut pending = functions.iter()
+        .filter(|&&(name, _, _, async)| {
+            async || (calls.values().any(|c| c.contains(&name)) == false && in_interrupts.contains_key(name) == false)
+        })
+        .map(|&(name, ..)| name)
+        .collect::<Vec<_>>();
+    while let Some(name) = pending.pop() {
+        if outside.contains(&name) == false {
+            outside.push(name);
+            pending.extend(calls[name].iter().cloned());
+        }
+    }
+
+    // How each interrupt accesses each global.
+    let mut interrupt_accesses: Vec<(&str, Access)> = Vec::new();
+    for &(interrupt, body) in interrupts.iter() {
+        for access in accesses(body, &[], &globals) {
+            interrupt_accesses.push((interrupt, access));
+        }
+    }
+    for &(name, body, params, _) in functions.iter() {
+        if let Some(reached) = in_interrupts.get(name) {
+            for access in accesses(body, params, &globals) {
+                for &interrupt in reached.iter() {
+                    interrupt_accesses.push((interrupt, Access { global: access.global, write: access.write, atomic: None }));
+                }
+            }
+        }
+    }
+
+    for &(name, body, params, _) in functions.iter().filter(|&&(name, ..)| outside.contains(&name)) {
+        let accesses = accesses(body, params, &globals);
+
+        let mut reported: Vec<&str> = Vec::new();
+        for (i, access) in accesses.iter().enumerate() {
+            if reported.contains(&access.global) {
+                continue;
+            }
+            let sharing = |write: Option<bool>| {
+                let mut result = interrupt_accesses.iter()
+                    .filter(|&&(_, ref a)| a.global == access.global && write.map(|w| w == a.write).unwrap_or(true))
+                    .map(|&(interrupt, _)| interrupt)
+                    .collect::<Vec<_>>();
+                result.sort();
+                result.dedup();
+                result
+            };
+            if sharing(None).is_empty() {
+                continue;
+            }
+
+            let read_before = accesses[..i].iter().any(|earlier| {
+                earlier.global == access.global && earlier.write == false &&
+                    (earlier.atomic.is_none() || earlier.atomic != access.atomic)
+            });
+            if access.write && read_before {
+                reported.push(access.global);
+                diagnostics.push(Diagnostic::warning(format!(
+                    "`{}` is read and then written in `{}` outside a single `atomic` block, but interrupt {} also accesses it",
+                    access.global, name, list(&sharing(None)),
+                )).with_help("an interrupt in between loses one of the updates; do both in one `atomic { ... }` block".into()));
+                continue;
+            }
+
+            let type_ref = globals.iter().find(|&&(g, _)| g == access.global).unwrap().1;
+            let conflicting = sharing(Some(access.write == false));
+            if access.atomic.is_none() && is_word(type_ref) == false && conflicting.is_empty() == false {
+                reported.push(access.global);
+                let (verb, other) = if access.write { ("writes", "reads") } else { ("reads", "writes") };
+                diagnostics.push(Diagnostic::warning(format!(
+                    "`{}` {} `{}` outside an `atomic` block while interrupt {} {} it",
+                    name, verb, access.global, list(&conflicting), other,
+                )).with_help(format!(
+                    "a `{}` is not accessed in a single instruction; wrap the access in `atomic {{ ... }}`",
+                    display(type_ref),
+                )));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// The plain functions that run in an interrupt whose dispatch is deferred,
+/// called from its handler directly or through other plain functions. A C
+/// function is generated only once, so these resume tasks through the ready
+/// queue wherever else they are called from.
+pub fn deferred_functions<'a>(nodes: &'a [TopLevelNode], options: &Options) -> Vec<&'a str> {
+    let functions = nodes.iter()
+        .filter_map(|node| match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async: false, .. } => Some((name.as_str(), body)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let is_function = |name: &str| functions.iter().any(|&(f, _)| f == name);
+
+    let mut pending = Vec::new();
+    for node in nodes.iter() {
+        if let &TopLevelNode::InterruptDecl { ref name, ref body } = node {
+            if options.dispatch(name) == Dispatch::Deferred {
+                pending.extend(called(body, &is_function));
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    while let Some(name) = pending.pop() {
+        if result.contains(&name) == false {
+            result.push(name);
+            let body = functions.iter().find(|&&(f, _)| f == name).unwrap().1;
+            pending.extend(called(body, &is_function));
+        }
+    }
+    result
+}
+
+/// The functions of this module that a body calls or awaits.
+fn called<'a, F: Fn(&str) -> bool>(body: &'a [Statement], is_function: &F) -> Vec<&'a str> {
+    let mut result = Vec::new();
+    let mut add = |target: &'a Expression| {
+        if let &Expression::Identifier(ref name) = target {
+            if is_function(name) && result.contains(&name.as_str()) == false {
+                result.push(name.as_str());
+            }
+        }
+    };
+
+    walk::expressions(body, &mut |expr| {
+        if let &Expression::FnCall { ref target, .. } = expr {
+            add(target);
+        }
+    });
+    walk::statements(body, &mut |statement| {
+        if let &Statement::FnCall { ref target, .. } = statement {
+            add(target);
+        }
+    });
+    result
+}
+
+/// The accesses of a body to the given globals in source order, leaving
+/// out those shadowed by parameters or locals.
+fn accesses<'a>(body: &[Statement], params: &[VarDecl], globals: &[(&'a str, &TypeRef)]) -> Vec<Access<'a>> {
+    let shadowed = walk::let_bindings(body).into_iter().chain(params.iter())
+        .map(|var| var.name.as_str())
+        .collect::<Vec<_>>();
+    let globals = globals.iter()
+        .filter(|&&(name, _)| shadowed.contains(&name) == false)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut collector = Collector { globals, accesses: Vec::new(), atomic_blocks: 0 };
+    collector.statements(body, None);
+    collector.accesses
+}
+
+struct Collector<'a, 't> {
+    globals: Vec<(&'a str, &'t TypeRef)>,
+    accesses: Vec<Access<'a>>,
+    atomic_blocks: usize,
+}
+
+impl<'a, 't> Collector<'a, 't> {
+    fn statements(&mut self, body: &[Statement], atomic: Option<usize>) {
+        for statement in body.iter() {
+            match statement {
+                &Statement::Atomic(ref nested) => {
+                    let block = match atomic {
+                        Some(block) => block,
+                        None => {
+                            self.atomic_blocks += 1;
+                            self.atomic_blocks
+                        }
+                    };
+                    self.statements(nested, Some(block));
+                }
+                &Statement::Loop(ref nested) | &Statement::Defer(ref nested) => self.statements(nested, atomic),
+                &Statement::Suspend { ref body, .. } => self.statements(body, atomic),
+                &Statement::ForAwait { ref stream, ref body, .. } => {
+                    self.reads(stream, atomic);
+                    self.statements(body, atomic);
+                }
+                &Statement::Select(ref arms) => {
+                    for arm in arms.iter() {
+                        self.reads(&arm.task, atomic);
+                        self.statements(&arm.body, atomic);
+                    }
+                }
+                &Statement::Assignment { ref target, ref expr } => {
+                    self.reads(expr, atomic);
+                    match target {
+                        &Expression::Identifier(ref name) => self.access(name, true, atomic),
+                        _ => self.reads(target, atomic),
+                    }
+                }
+                &Statement::Await(Expression::Identifier(ref name)) if self.is_continuation(name) => {
+                    // taking a continuation out of a global to invoke it
+                    // clears the global
+                    self.access(name, false, atomic);
+                    self.access(name, true, atomic);
+                }
+                _ => {
+                    for expr in walk::statement_expressions(statement) {
+                        self.reads(expr, atomic);
+                    }
+                }
+            }
+        }
+    }
+
+    fn reads(&mut self, expr: &Expression, atomic: Option<usize>) {
+        let mut names = Vec::new();
+        walk::subexpressions(expr, &mut |expr| {
+            if let &Expression::Identifier(ref name) = expr {
+                names.push(name.as_str());
+            }
+        });
+        for name in names {
+            self.access(name, false, atomic);
+        }
+    }
+
+    fn access(&mut self, name: &str, write: bool, atomic: Option<usize>) {
+        if let Some(&(global, _)) = self.globals.iter().find(|&&(global, _)| global == name) {
+            self.accesses.push(Access { global, write, atomic });
+        }
+    }
+
+    fn is_continuation(&self, name: &str) -> bool {
+        self.globals.iter().any(|&(global, type_ref)| global == name && continuations::is_continuation(type_ref))
+    }
+}
+
+fn guards_itself(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Channel { .. } => true,
+        _ => locks::lock_kind(type_ref).is_some(),
+    }
+}
+
+fn is_word(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Named { ref name, .. } => WORD_TYPES.contains(&name.as_str()),
+        _ => false,
+    }
+}
+
+fn list(names: &[&str]) -> String {
+    names.iter().map(|name| format!("`{}`", name)).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    fn increment(global: &str) -> Statement {
+        Statement::Assignment { target: ident(global), expr: add(ident(global), int(1)) }
+    }
+
+    #[test]
+    fn updates_of_globals_shared_with_interrupts_must_be_atomic() {
+        let warnings = warnings(vec![
+            TopLevelNode::GlobalDecl(var("count", ty("u32"))),
+            TopLevelNode::GlobalDecl(var("guarded", ty("u32"))),
+            plain_fn("bump", vec![], unit(), vec![increment("count"), increment("guarded")]),
+            TopLevelNode::InterruptDecl { name: "tick".into(), body: vec![call_statement("bump", vec![])] },
+            async_fn("main", vec![], unit(), vec![
+                increment("count"),
+                Statement::Atomic(vec![increment("guarded")]),
+            ]),
+        ]);
+        assert_eq!(warnings, vec![
+            "`count` is read and then written in `main` outside a single `atomic` block, but interrupt `tick` also accesses it".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn wide_globals_shared_with_interrupts_are_accessed_atomically() {
+        let warnings = warnings(vec![
+            TopLevelNode::GlobalDecl(var("stamp", ty("u64"))),
+            TopLevelNode::GlobalDecl(var("last", ty("u64"))),
+            TopLevelNode::InterruptDecl { name: "tick".into(), body: vec![
+                Statement::Assignment { target: ident("stamp"), expr: int(1) },
+                Statement::Assignment { target: ident("last"), expr: int(1) },
+            ] },
+            async_fn("main", vec![], unit(), vec![
+                let_("now", ty("u64"), ident("stamp")),
+                Statement::Atomic(vec![let_("then", ty("u64"), ident("last"))]),
+            ]),
+        ]);
+        assert_eq!(warnings, vec![
+            "`main` reads `stamp` outside an `atomic` block while interrupt `tick` writes it".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn atomic_blocks_do_not_suspend() {
+        let errors = errors(vec![
+            async_fn("poll", vec![], unit(), vec![]),
+            async_fn("main", vec![], unit(), vec![Statement::Atomic(vec![Statement::Await(call("poll", vec![]))])]),
+        ]);
+        assert!(mentions(&errors, "an `atomic` block in `main` suspends"), "{:?}", errors);
+    }
+
+    #[test]
+    fn atomic_blocks_disable_interrupts() {
+        let output = compile(vec![
+            TopLevelNode::GlobalDecl(var("count", ty("u32"))),
+            plain_fn("bump", vec![], unit(), vec![Statement::Atomic(vec![increment("count")])]),
+        ]);
+        let bump = output.function("bump");
+        assert!(bump.contains("    ATOMIC_ENTER();\n    globals.count = (globals.count + 1);\n    ATOMIC_EXIT();"), "{}", output.source);
+    }
+}
@@ -0,0 +1,229 @@
+use super::Diagnostic;
+use super::channels::is_array;
+use super::symbols::SymbolTable;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+pub const MUTEX: &str = "Mutex";
+pub const SEMAPHORE: &str = "Semaphore";
+
+pub const LOCK: &str = "lock";
+pub const TRY_LOCK: &str = "try_lock";
+pub const UNLOCK: &str = "unlock";
+pub const ACQUIRE: &str = "acquire";
+pub const TRY_ACQUIRE: &str = "try_acquire";
+pub const RELEASE: &str = "release";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockKind {
+    /// `Mutex<T>`, which guards a value of `T` stored with it.
+    Mutex,
+    /// `Semaphore<N>`, a counter that starts at `N`.
+    Semaphore,
+}
+
+impl LockKind {
+    /// The methods a lock of this kind supports, the first of which suspends
+    /// until the lock is taken.
+    pub fn methods(&self) -> &'static [&'static str] {
+        match self {
+            &LockKind::Mutex => &[LOCK, TRY_LOCK, UNLOCK],
+            &LockKind::Semaphore => &[ACQUIRE, TRY_ACQUIRE, RELEASE],
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &LockKind::Mutex => MUTEX,
+            &LockKind::Semaphore => SEMAPHORE,
+        }
+    }
+}
+
+/// A call to one of the built-in methods of a mutex or semaphore, e.g.
+/// `spi.lock()`.
+pub struct LockCall<'a, 'e> {
+    /// The global the lock is stored in.
+    pub lock: &'a VarDecl,
+    pub kind: LockKind,
+    pub method: &'e str,
+    pub args: &'e [Expression],
+}
+
+impl<'a, 'e> LockCall<'a, 'e> {
+    /// Whether the method waits for the lock, and therefore has to be awaited.
+    pub fn suspends(&self) -> bool {
+        self.method == self.kind.methods()[0]
+    }
+}
+
+pub fn lock_kind(type_ref: &TypeRef) -> Option<LockKind> {
+    match type_ref {
+        &TypeRef::Named { ref name, .. } if name == MUTEX => Some(LockKind::Mutex),
+        &TypeRef::Named { ref name, .. } if name == SEMAPHORE => Some(LockKind::Semaphore),
+        &TypeRef::Semaphore { .. } => Some(LockKind::Semaphore),
+        _ => None,
+    }
+}
+
+/// Recognises a call whose target is a method of a global mutex or
+/// semaphore.
+pub fn lock_call<'a, 'e>(target: &'e Expression, args: &'e [Expression], symbols: &SymbolTable<'a>) -> Option<LockCall<'a, 'e>> {
+    let (name, method) = match target {
+        &Expression::MemberOf { ref structure, ref member } => match **structure {
+            Expression::Identifier(ref name) => (name, member),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let lock = match symbols.globals.get(name.as_str()) {
+        Some(global) if global.external == false => global.var,
+        _ => return None,
+    };
+    lock_kind(&lock.type_ref).map(|kind| LockCall { lock, kind, method, args })
+}
+
+/// The lock an `await` waits for, if any.
+pub fn awaited_lock_call<'a, 'e>(statement: &'e Statement, symbols: &SymbolTable<'a>) -> Option<LockCall<'a, 'e>> {
+    match walk::awaited(statement) {
+        Some(&Expression::FnCall { ref target, ref args }) => lock_call(target, args, symbols),
+        _ => None,
+    }
+}
+
+/// Checks that mutexes and semaphores are only stored in globals, that tasks
+/// await `lock` and `acquire` while other code uses the `try_` methods, and
+/// that interrupts never unlock a mutex, which they cannot have locked.
+pub fn check(nodes: &[TopLevelNode], symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for node in nodes.iter() {
+        match node {
+            &TopLevelNode::GlobalDecl(ref var) => match lock_kind(&var.type_ref) {
+                Some(kind) => diagnostics.extend(check_type_params(&var.name, kind, &var.type_ref)),
+                None if contains_lock(&var.type_ref) => diagnostics.push(misplaced(&var.name)),
+                None => {}
+            },
+            &TopLevelNode::ExternGlobalDecl { ref var, .. } if contains_lock(&var.type_ref) => {
+                diagnostics.push(Diagnostic::error(format!("`{}` is a lock and cannot be implemented in C", var.name))
+                    .with_help("declare it as a `global` instead".into()));
+            }
+            &TopLevelNode::ConstDecl { ref name, ref type_ref, .. } if contains_lock(type_ref) => {
+                diagnostics.push(misplaced(name));
+            }
+            &TopLevelNode::FnDecl { ref params, ref returns, .. } |
+            &TopLevelNode::ExternFnDecl { ref params, ref returns, .. } => {
+                for param in params.iter().filter(|p| contains_lock(&p.type_ref)) {
+                    diagnostics.push(misplaced(&param.name));
+                }
+                if contains_lock(returns) {
+                    diagnostics.push(Diagnostic::error("mutexes and semaphores cannot be returned".into()));
+                }
+            }
+            _ => {}
+        }
+
+        let (name, body, async, interrupt) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async, .. } => (name, body, async, false),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body, false, true),
+            _ => continue,
+        };
+
+        for local in walk::let_bindings(body) {
+            if contains_lock(&local.type_ref) {
+                diagnostics.push(misplaced(&local.name));
+            }
+        }
+
+        let mut awaited = Vec::new();
+        walk::statements(body, &mut |statement| {
+            if let Some(call) = awaited_lock_call(statement, symbols) {
+                awaited.push(walk::awaited(statement).unwrap());
+                if call.suspends() == false {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}.{}` is awaited in `{}` but never suspends", call.lock.name, call.method, name,
+                    )).with_help(format!("await `{}.{}` to wait for the lock", call.lock.name, call.kind.methods()[0])));
+                } else if async == false {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}.{}` is awaited in `{}`, which is not async", call.lock.name, call.method, name,
+                    )).with_help(format!("use `{}.{}`, which does not suspend", call.lock.name, call.kind.methods()[1])));
+                }
+            }
+        });
+
+        let mut calls = Vec::new();
+        walk::expressions(body, &mut |expr| {
+            if let &Expression::FnCall { ref target, ref args } = expr {
+                if let Some(call) = lock_call(target, args, symbols) {
+                    let is_awaited = awaited.iter().any(|a| ::std::ptr::eq(*a, expr));
+                    calls.push((call, is_awaited));
+                }
+            }
+        });
+        walk::statements(body, &mut |statement| {
+            if let &Statement::FnCall { ref target, ref args } = statement {
+                if let Some(call) = lock_call(target, args, symbols) {
+                    calls.push((call, false));
+                }
+            }
+        });
+
+        for (call, is_awaited) in calls {
+            if is_awaited == false && call.suspends() {
+                diagnostics.push(Diagnostic::error(format!(
+                    "`{}.{}` in `{}` must be awaited", call.lock.name, call.method, name,
+                )));
+            }
+            if interrupt && call.method == UNLOCK {
+                diagnostics.push(Diagnostic::error(format!(
+                    "interrupt `{}` unlocks `{}`, which only the task holding it may do", name, call.lock.name,
+                )).with_help("signal tasks from interrupts with a `Semaphore` and `release`".into()));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+fn check_type_params(name: &str, kind: LockKind, type_ref: &TypeRef) -> Vec<Diagnostic> {
+    let type_params = match type_ref {
+        &TypeRef::Named { ref type_params, .. } => type_params,
+        &TypeRef::Semaphore { .. } => return vec![],
+        _ => unreachable!(),
+    };
+
+    match kind {
+        LockKind::Mutex if type_params.len() != 1 => vec![
+            Diagnostic::error(format!("mutex `{}` must name the type it guards", name))
+                .with_help("declare it as e.g. `Mutex<u8>`".into()),
+        ],
+        LockKind::Mutex if contains_lock(&type_params[0]) => vec![
+            Diagnostic::error(format!("mutex `{}` cannot guard another lock", name)),
+        ],
+        LockKind::Mutex if is_array(&type_params[0]) => vec![
+            Diagnostic::error(format!("mutex `{}` cannot guard an array yet", name)),
+        ],
+        LockKind::Semaphore => vec![
+            Diagnostic::error(format!("semaphore `{}` must give the count it starts with", name))
+                .with_help("declare it as e.g. `Semaphore<0>`".into()),
+        ],
+        _ => vec![],
+    }
+}
+
+fn misplaced(name: &str) -> Diagnostic {
+    Diagnostic::error(format!("`{}` holds a lock, but mutexes and semaphores can only be globals", name))
+        .with_help("declare the lock as a `global` and refer to it by name".into())
+}
+
+fn contains_lock(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Named { ref type_params, .. } => {
+            lock_kind(type_ref).is_some() || type_params.iter().any(contains_lock)
+        }
+        &TypeRef::Array { ref element, .. } | &TypeRef::Channel { ref element, .. } => contains_lock(element),
+        &TypeRef::Tuple { ref type_refs } => type_refs.iter().any(contains_lock),
+        &TypeRef::Semaphore { .. } => true,
+    }
+}
@@ -0,0 +1,98 @@
+
+pub mod symbols;
+pub mod signatures;
+pub mod consteval;
+pub mod closures;
+pub mod defers;
+pub mod spawns;
+pub mod channels;
+pub mod locks;
+pub mod timers;
+pub mod generators;
+pub mod continuations;
+pub mod coloring;
+pub mod interrupts;
+pub mod recursion;
+pub mod stack;
+pub mod assignments;
+
+use std::fmt;
+
+use self::symbols::SymbolTable;
+use super::module::Module;
+
+/// Runs every check on a module, whose symbol table must already hold its
+/// imports. Constants are evaluated first, since the other checks read them.
+pub fn check_module<'a>(module: &'a Module, symbols: &mut SymbolTable<'a>) -> Vec<Diagnostic> {
+    let ast = &module.nodes;
+    let mut diagnostics = consteval::evaluate_constants(ast, symbols);
+    diagnostics.extend(signatures::check(ast, symbols));
+    diagnostics.extend(closures::check(ast));
+    diagnostics.extend(defers::check(ast));
+    diagnostics.extend(spawns::check(module, symbols));
+    diagnostics.extend(channels::check(ast, symbols));
+    diagnostics.extend(locks::check(ast, symbols));
+    diagnostics.extend(timers::check(ast, symbols));
+    diagnostics.extend(generators::check(ast, symbols));
+    diagnostics.extend(continuations::check(ast, symbols));
+    diagnostics.extend(coloring::check(ast, symbols));
+    diagnostics.extend(interrupts::check(ast));
+    diagnostics.extend(recursion::check(ast, symbols));
+    diagnostics.extend(assignments::check(ast));
+    diagnostics
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: String) -> Self {
+        Diagnostic {
+            level: Level::Error,
+            message,
+            help: None,
+        }
+    }
+
+    pub fn warning(message: String) -> Self {
+        Diagnostic {
+            level: Level::Warning,
+            message,
+            help: None,
+        }
+    }
+
+    pub fn with_help(mut self, help: String) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.level == Level::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.level {
+            Level::Error => write!(f, "error: {}", self.message)?,
+            Level::Warning => write!(f, "warning: {}", self.message)?,
+        }
+
+        if let Some(ref help) = self.help {
+            write!(f, "\n  help: {}", help)?;
+        }
+
+        Ok(())
+    }
+}
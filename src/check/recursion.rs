@@ -0,0 +1,154 @@
+use std::collections::{HashMap, VecDeque};
+
+use petgraph::{Graph, algo::tarjan_scc, graph::NodeIndex, visit::EdgeRef};
+
+use super::Diagnostic;
+use super::consteval::display;
+use super::symbols::SymbolTable;
+use super::timers;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+/// Checks that no async function awaits itself, directly or through others.
+/// The state of every awaited task is embedded in the state of the task
+/// awaiting it, so such a state would have to contain itself. Awaiting a
+/// call as `box(n) f(...)` keeps its state in a pool instead, which breaks
+/// the cycle.
+pub fn check(nodes: &[TopLevelNode], symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut graph: Graph<&str, &Expression> = Graph::new();
+    // Functions are keyed by their C names, which calls through a path
+    // resolve to as well.
+    let mut indices: HashMap<&str, NodeIndex> = HashMap::new();
+    let mut nodes_by_name: HashMap<&str, NodeIndex> = HashMap::new();
+    for node in nodes.iter() {
+        if let &TopLevelNode::FnDecl { ref name, async: true, .. } = node {
+            let idx = graph.add_node(name);
+            nodes_by_name.insert(name, idx);
+            if let Some(signature) = symbols.functions.get(name.as_str()) {
+                indices.insert(&signature.c_name, idx);
+            }
+        }
+    }
+
+    for node in nodes.iter() {
+        let (name, body) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async: true, .. } => (name, body),
+            _ => continue,
+        };
+        for call in embedded(body, symbols) {
+            let callee = match call {
+                &Expression::FnCall { ref target, .. } => symbols.function(target)
+                    .and_then(|callee| indices.get(callee.c_name.as_str())),
+                _ => None,
+            };
+            if let Some(&callee) = callee {
+                graph.add_edge(nodes_by_name[name.as_str()], callee, call);
+            }
+        }
+    }
+
+    let mut diagnostics = Vec::new();
+    for component in tarjan_scc(&graph) {
+        let is_cycle = component.len() > 1 || graph.find_edge(component[0], component[0]).is_some();
+        if is_cycle {
+            let start = *component.iter().min_by_key(|&&idx| idx.index()).unwrap();
+            diagnostics.push(Diagnostic::error(format!(
+                "`{}` awaits itself, so its task state would contain itself: {}",
+                graph[start], cycle_path(&graph, start, &component),
+            )).with_help("await one of these calls as `box(<slots>) f(...)`, which keeps its state in a pool of that many slots".into()));
+        }
+    }
+    diagnostics
+}
+
+/// The calls whose task states a body embeds in its own: those it awaits,
+/// and the tasks of its `join`s, `select`s, `timeout`s and `for await` loops.
+fn embedded<'a>(body: &'a [Statement], symbols: &SymbolTable) -> Vec<&'a Expression> {
+    let mut result = Vec::new();
+    walk::statements(body, &mut |statement| {
+        match walk::awaited(statement) {
+            Some(&Expression::Join { ref tasks }) => result.extend(tasks.iter()),
+            Some(expr) => match timers::timeout_call(expr, symbols) {
+                Some((_, task)) => result.push(task),
+                None => result.push(expr),
+            },
+            None => {}
+        }
+        if let &Statement::Select(ref arms) = statement {
+            result.extend(arms.iter().map(|arm| &arm.task));
+        }
+        result.extend(walk::streamed(statement));
+    });
+    result
+}
+
+/// The shortest cycle through `start` within a strongly connected
+/// component, naming the call each function awaits the next one with.
+fn cycle_path(graph: &Graph<&str, &Expression>, start: NodeIndex, component: &[NodeIndex]) -> String {
+    let mut predecessors: HashMap<NodeIndex, (NodeIndex, &Expression)> = HashMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(start);
+
+    while let Some(current) = queue.pop_front() {
+        for edge in graph.edges(current).filter(|e| component.contains(&e.target())) {
+            let next = edge.target();
+            if next == start {
+                let mut steps = vec![(current, *edge.weight())];
+                let mut node = current;
+                while node != start {
+                    let (previous, call) = predecessors[&node];
+                    steps.push((previous, call));
+                    node = previous;
+                }
+
+                return steps.iter()
+                    .rev()
+                    .map(|&(idx, call)| format!("`{}` awaits `{}`", graph[idx], display(call)))
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+            }
+
+            if predecessors.contains_key(&next) == false {
+                predecessors.insert(next, (current, *edge.weight()));
+                queue.push_back(next);
+            }
+        }
+    }
+
+    unreachable!("strongly connected component without a cycle")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use super::super::symbols::SymbolTable;
+    use super::super::super::ast::nodes::*;
+    use super::super::super::module::Module;
+    use super::super::super::testing::*;
+
+    #[test]
+    fn tasks_awaiting_themselves_are_rejected_unless_boxed() {
+        let errors = errors(vec![
+            async_fn("ping", vec![], unit(), vec![Statement::Await(call("pong", vec![]))]),
+            async_fn("pong", vec![], unit(), vec![Statement::Await(call("ping", vec![]))]),
+            async_fn("walk", vec![], unit(), vec![
+                Statement::Await(Expression::Boxed { task: Box::new(call("walk", vec![])), slots: Box::new(int(4)) }),
+            ]),
+        ]);
+        assert!(mentions(&errors, "`ping` awaits itself"), "{:?}", errors);
+        assert!(mentions(&errors, "`walk` awaits itself") == false, "{:?}", errors);
+    }
+
+    #[test]
+    fn calls_through_paths_are_followed() {
+        let module = Module::root(vec![
+            async_fn("ping", vec![], unit(), vec![Statement::Await(path_call(&["main", "ping"], vec![]))]),
+        ]);
+        let (mut symbols, _) = SymbolTable::build(&module);
+        let ping = symbols.functions["ping"].clone();
+        symbols.imported_functions.insert("main::ping".into(), ping);
+
+        let messages = check(&module.nodes, &symbols).into_iter().map(|d| d.message).collect::<Vec<_>>();
+        assert!(mentions(&messages, "`ping` awaits itself"), "{:?}", messages);
+    }
+}
@@ -0,0 +1,708 @@
+use std::collections::HashMap;
+
+use super::Diagnostic;
+use super::channels::{self, ChannelCall};
+use super::consteval::{array_length, display};
+use super::continuations::{self, CONTINUATION};
+use super::locks::{self, LockCall};
+use super::symbols::{SymbolTable, BUILTIN_FUNCTIONS};
+use super::timers;
+use super::super::ast::nodes::*;
+use super::super::ast::visitor::VisitorMut;
+use super::super::ast::walk;
+
+const INTEGER_TYPES: &[&str] = &["u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64"];
+const FLOAT_TYPES: &[&str] = &["f32", "f64"];
+
+/// Checks every call to a named function against its declared signature,
+/// including calls into C functions declared with `extern`.
+pub fn check(nodes: &[TopLevelNode], symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut checker = SignatureChecker {
+        symbols,
+        context: String::new(),
+        yields: None,
+        returns: None,
+        in_closure: false,
+        locals: HashMap::new(),
+        diagnostics: Vec::new(),
+    };
+
+    for node in nodes.iter() {
+        checker.diagnostics.extend(check_c_types(node));
+        checker.accept_top_level_node(node);
+    }
+
+    checker.diagnostics
+}
+
+/// Checks that the types of a declaration can be written in C: tuples other
+/// than `()` have no C type yet, and arrays cannot be passed or returned by
+/// value.
+fn check_c_types(node: &TopLevelNode) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let tuple = |what: String, type_ref: &TypeRef| Diagnostic::error(format!(
+        "{} has the type `{}`, but tuples cannot be represented in C yet", what, display(type_ref),
+    ));
+
+    let (name, params, returns, body) = match node {
+        &TopLevelNode::FnDecl { ref name, ref params, ref returns, ref body, .. } => (name, params, returns, &body[..]),
+        &TopLevelNode::ExternFnDecl { ref name, ref params, ref returns, .. } => (name, params, returns, &[][..]),
+        &TopLevelNode::GlobalDecl(ref var) | &TopLevelNode::ExternGlobalDecl { ref var, .. } => {
+            if contains_tuple(&var.type_ref) {
+                diagnostics.push(tuple(format!("`{}`", var.name), &var.type_ref));
+            }
+            return diagnostics;
+        }
+        &TopLevelNode::InterruptDecl { ref body, .. } => {
+            for local in walk::let_bindings(body) {
+                if contains_tuple(&local.type_ref) {
+                    diagnostics.push(tuple(format!("`{}`", local.name), &local.type_ref));
+                }
+            }
+            return diagnostics;
+        }
+        _ => return diagnostics,
+    };
+
+    for param in params.iter() {
+        if contains_tuple(&param.type_ref) {
+            diagnostics.push(tuple(format!("parameter `{}` of `{}`", param.name, name), &param.type_ref));
+        } else if let TypeRef::Array { .. } = param.type_ref {
+            diagnostics.push(Diagnostic::error(format!("parameter `{}` of `{}` is an array", param.name, name))
+                .with_help("C cannot pass arrays by value; keep the array in a global instead".into()));
+        }
+    }
+    if contains_tuple(returns) {
+        diagnostics.push(tuple(format!("the result of `{}`", name), returns));
+    } else if let &TypeRef::Array { .. } = returns {
+        diagnostics.push(Diagnostic::error(format!("`{}` returns an array", name))
+            .with_help("C cannot return arrays by value; keep the array in a global instead".into()));
+    }
+    for local in walk::let_bindings(body) {
+        if contains_tuple(&local.type_ref) {
+            diagnostics.push(tuple(format!("`{}` in `{}`", local.name, name), &local.type_ref));
+        }
+    }
+    diagnostics
+}
+
+/// Whether a type is or contains a tuple other than `()`.
+fn contains_tuple(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Tuple { ref type_refs } => type_refs.is_empty() == false,
+        &TypeRef::Named { ref type_params, .. } => type_params.iter().any(contains_tuple),
+        &TypeRef::Array { ref element, .. } | &TypeRef::Channel { ref element, .. } => contains_tuple(element),
+        &TypeRef::Semaphore { .. } => false,
+    }
+}
+
+struct SignatureChecker<'a, 'b: 'a> {
+    symbols: &'a SymbolTable<'b>,
+    context: String,
+    /// The type of the values the current function yields, if it is an
+    /// `async gen`.
+    yields: Option<TypeRef>,
+    /// The type of the value the current function completes with, if it is
+    /// declared to return one.
+    returns: Option<TypeRef>,
+    /// Whether the statements being checked are the body of a closure.
+    in_closure: bool,
+    locals: HashMap<String, TypeRef>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// What awaiting an expression completes with.
+enum Awaited {
+    Value(TypeRef),
+    Nothing,
+    /// The awaited expression is invalid, which is reported elsewhere.
+    Unknown,
+}
+
+enum ArgType {
+    Integer,
+    Float,
+    Boolean,
+    String,
+    Typed(TypeRef),
+}
+
+impl<'a, 'b> SignatureChecker<'a, 'b> {
+    fn check_call(&mut self, target: &Expression, args: &[Expression], awaited: bool) {
+        if let Some(call) = channels::channel_call(target, args, self.symbols) {
+            self.check_channel_call(&call);
+            return;
+        }
+        if let Some(call) = locks::lock_call(target, args, self.symbols) {
+            self.check_lock_call(&call);
+            return;
+        }
+
+        let name = match target {
+            &Expression::Identifier(ref name) => name.clone(),
+            &Expression::Path(ref segments) => segments.join("::"),
+            _ => return,
+        };
+
+        let signature = match self.symbols.function(target) {
+            Some(signature) => signature,
+            None => {
+                if BUILTIN_FUNCTIONS.contains(&name.as_str()) == false {
+                    let help = match target {
+                        &Expression::Path(ref segments) => format!(
+                            "check that `{}` is imported and that `{}` is `pub`", segments[0], segments[segments.len() - 1],
+                        ),
+                        _ => format!("declare it, e.g. `extern fn {}(...);` if it is implemented in C", name),
+                    };
+                    let diagnostic = Diagnostic::error(format!("call to undeclared function `{}` in `{}`", name, self.context))
+                        .with_help(help);
+                    self.diagnostics.push(diagnostic);
+                }
+                return;
+            }
+        };
+
+        let params = if awaited { signature.awaited_params() } else { signature.params };
+
+        if params.len() != args.len() {
+            self.diagnostics.push(Diagnostic::error(format!(
+                "`{}` takes {} argument(s) but {} were supplied in `{}`",
+                name, params.len(), args.len(), self.context,
+            )));
+            return;
+        }
+
+        for (param, arg) in params.iter().zip(args.iter()) {
+            if self.compatible(arg, &param.type_ref) == false {
+                self.diagnostics.push(Diagnostic::error(format!(
+                    "argument `{}` of `{}` has the wrong type in `{}`",
+                    param.name, name, self.context,
+                )));
+            }
+        }
+    }
+
+    fn accept_awaited(&mut self, x: &Expression) {
+        match x {
+            &Expression::FnCall { ref target, ref args } => {
+                for arg in args.iter() {
+                    self.accept_expression(arg);
+                }
+                self.check_call(target, args, true);
+            }
+            &Expression::Join { ref tasks } => {
+                for task in tasks.iter() {
+                    if let &Expression::FnCall { .. } = task {
+                        self.accept_awaited(task);
+                    } else {
+                        self.diagnostics.push(Diagnostic::error(format!(
+                            "`join` in `{}` can only start calls to async functions", self.context,
+                        )));
+                    }
+                }
+            }
+            &Expression::Boxed { ref task, ref slots } => {
+                match array_length(slots, self.symbols) {
+                    Ok(0) => self.diagnostics.push(Diagnostic::error(format!(
+                        "`box` in `{}` needs at least one slot", self.context,
+                    ))),
+                    Ok(..) => {}
+                    Err(message) => self.diagnostics.push(Diagnostic::error(format!(
+                        "invalid slot count of `box` in `{}`: {}", self.context, message,
+                    ))),
+                }
+
+                let boxes_task = match **task {
+                    Expression::FnCall { ref target, .. } => self.symbols.function(target)
+                        .map(|f| f.async && f.generator == false && f.external == false)
+                        .unwrap_or(false),
+                    _ => false,
+                };
+                if boxes_task == false {
+                    self.diagnostics.push(Diagnostic::error(format!(
+                        "`box` in `{}` can only hold a call to an async function of this program", self.context,
+                    )));
+                }
+                self.accept_awaited(task);
+            }
+            x => {
+                self.accept_expression(x);
+                let invokes_continuation = match self.arg_type(x) {
+                    Some(ArgType::Typed(ref type_ref)) => continuations::is_continuation(type_ref),
+                    Some(..) => false,
+                    None => true,
+                };
+                if invokes_continuation == false {
+                    self.diagnostics.push(Diagnostic::error(format!(
+                        "`await` in `{}` expects a call or a `{}`", self.context, CONTINUATION,
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Checks that a continuation is only ever stored in a `Continuation`,
+    /// and that a `Continuation` only ever holds one.
+    fn check_store(&mut self, name: &str, type_ref: &TypeRef, expr: &Expression) {
+        let stores_continuation = continuations::is_continuation(type_ref) || match self.arg_type(expr) {
+            Some(ArgType::Typed(ref arg_type)) => continuations::is_continuation(arg_type),
+            _ => false,
+        };
+        if stores_continuation == false || self.compatible(expr, type_ref) {
+            return;
+        }
+
+        if continuations::is_continuation(type_ref) {
+            self.diagnostics.push(Diagnostic::error(format!(
+                "`{}` in `{}` is a `{}` and can only be assigned another one", name, self.context, CONTINUATION,
+            )));
+        } else {
+            self.diagnostics.push(Diagnostic::error(format!(
+                "`{}` in `{}` is declared as `{}` but is assigned a `{}`",
+                name, self.context, display(type_ref), CONTINUATION,
+            )));
+        }
+    }
+
+    fn check_channel_call(&mut self, call: &ChannelCall) {
+        let arity = match call.method {
+            channels::SEND | channels::TRY_SEND => 1,
+            channels::RECV => 0,
+            _ => {
+                self.diagnostics.push(Diagnostic::error(format!(
+                    "channel `{}` has no method `{}`", call.channel.name, call.method,
+                )).with_help("channels support `send`, `recv` and `try_send`".into()));
+                return;
+            }
+        };
+
+        if call.args.len() != arity {
+            self.diagnostics.push(Diagnostic::error(format!(
+                "`{}.{}` takes {} argument(s) but {} were supplied in `{}`",
+                call.channel.name, call.method, arity, call.args.len(), self.context,
+            )));
+        } else if arity == 1 && self.compatible(&call.args[0], call.element) == false {
+            self.diagnostics.push(Diagnostic::error(format!(
+                "`{}.{}` in `{}` expects a `{}`",
+                call.channel.name, call.method, self.context, display(call.element),
+            )));
+        }
+    }
+
+    fn check_lock_call(&mut self, call: &LockCall) {
+        let methods = call.kind.methods();
+        if methods.contains(&call.method) == false {
+            self.diagnostics.push(Diagnostic::error(format!(
+                "{} `{}` has no method `{}`", call.kind.name().to_lowercase(), call.lock.name, call.method,
+            )).with_help(format!(
+                "a `{}` supports `{}`, `{}` and `{}`", call.kind.name(), methods[0], methods[1], methods[2],
+            )));
+        } else if call.args.is_empty() == false {
+            self.diagnostics.push(Diagnostic::error(format!(
+                "`{}.{}` takes no arguments but {} were supplied in `{}`",
+                call.lock.name, call.method, call.args.len(), self.context,
+            )));
+        }
+    }
+
+    /// What awaiting `expr` completes with, for storing it in a local.
+    fn awaited_type(&self, expr: &Expression) -> Awaited {
+        let (target, args) = match expr {
+            &Expression::FnCall { ref target, ref args } => (target, args),
+            &Expression::Boxed { ref task, .. } => return self.awaited_type(task),
+            _ => return Awaited::Nothing,
+        };
+
+        if let Some(call) = channels::channel_call(target, args, self.symbols) {
+            return match call.method {
+                channels::RECV => Awaited::Value(call.element.clone()),
+                _ => Awaited::Nothing,
+            };
+        }
+        if locks::lock_call(target, args, self.symbols).is_some() || timers::is_sleep(target, self.symbols) {
+            return Awaited::Nothing;
+        }
+        if let Some((_, task)) = timers::timeout_call(expr, self.symbols) {
+            let callee = match task {
+                &Expression::FnCall { ref target, .. } => self.symbols.function(target),
+                _ => None,
+            };
+            return match callee {
+                Some(callee) if callee.async && callee.external == false => {
+                    Awaited::Value(timers::timeout_type(callee.returns))
+                }
+                _ => Awaited::Unknown,
+            };
+        }
+
+        match self.symbols.function(target) {
+            Some(callee) if callee.async && callee.external == false && is_unit(callee.returns) == false => {
+                Awaited::Value(callee.returns.clone())
+            }
+            Some(..) => Awaited::Nothing,
+            None => Awaited::Unknown,
+        }
+    }
+
+    /// Checks that `var` can store what awaiting `expr` completes with.
+    fn check_binding(&mut self, var: &VarDecl, expr: &Expression) {
+        match self.awaited_type(expr) {
+            Awaited::Value(ref type_ref) if *type_ref == var.type_ref => {}
+            Awaited::Value(ref type_ref) => {
+                self.diagnostics.push(Diagnostic::error(format!(
+                    "`{}` in `{}` is declared as `{}` but the await completes with `{}`",
+                    var.name, self.context, display(&var.type_ref), display(type_ref),
+                )));
+            }
+            Awaited::Nothing => {
+                self.diagnostics.push(Diagnostic::error(format!(
+                    "`{}` in `{}` cannot be stored: the await completes without a value",
+                    var.name, self.context,
+                )).with_help("remove the binding".into()));
+            }
+            Awaited::Unknown => {}
+        }
+    }
+
+    fn accept_select_arm(&mut self, arm: &SelectArm) {
+        match arm.task {
+            Expression::FnCall { ref target, .. } => {
+                self.accept_awaited(&arm.task);
+                // the arms that lose are cancelled, which only works for the
+                // tasks of this program; a C function may still complete
+                // into the `select` long after, even once it has started over
+                if let Some(callee) = self.symbols.function(target) {
+                    if callee.external || callee.async == false {
+                        self.diagnostics.push(Diagnostic::error(format!(
+                            "`select` in `{}` cannot race `{}`, which cannot be cancelled", self.context, display(&**target),
+                        )).with_help("only async functions declared in the program can be `select` arms; await it on its own".into()));
+                    }
+                }
+            }
+            _ => {
+                self.diagnostics.push(Diagnostic::error(format!(
+                    "`select` in `{}` can only start calls to async functions", self.context,
+                )));
+            }
+        }
+
+        if let Some(ref binding) = arm.binding {
+            self.check_binding(binding, &arm.task);
+            self.accept_var_decl(binding);
+        }
+        for statement in arm.body.iter() {
+            self.accept_statement(statement);
+        }
+    }
+
+    fn compatible(&self, arg: &Expression, type_ref: &TypeRef) -> bool {
+        match self.arg_type(arg) {
+            Some(ArgType::Integer) => is_named_one_of(type_ref, INTEGER_TYPES),
+            Some(ArgType::Float) => is_named_one_of(type_ref, FLOAT_TYPES),
+            Some(ArgType::Boolean) => is_named_one_of(type_ref, &["bool"]),
+            Some(ArgType::String) => is_named_one_of(type_ref, &["str"]),
+            Some(ArgType::Typed(ref arg_type)) => arg_type == type_ref,
+            None => true,
+        }
+    }
+
+    fn arg_type(&self, arg: &Expression) -> Option<ArgType> {
+        match arg {
+            &Expression::Literal(Literal::Integer(..)) => Some(ArgType::Integer),
+            &Expression::Literal(Literal::Float(..)) => Some(ArgType::Float),
+            &Expression::Literal(Literal::Boolean(..)) => Some(ArgType::Boolean),
+            &Expression::Literal(Literal::String(..)) => Some(ArgType::String),
+            &Expression::Identifier(ref name) => {
+                self.locals.get(name)
+                    .or_else(|| self.symbols.globals.get(name.as_str()).map(|global| &global.var.type_ref))
+                    .or_else(|| self.symbols.constant(arg).map(|constant| constant.type_ref))
+                    .map(|type_ref| ArgType::Typed(type_ref.clone()))
+            }
+            &Expression::Path(..) => {
+                self.symbols.constant(arg).map(|constant| ArgType::Typed(constant.type_ref.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn is_unit(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Tuple { ref type_refs } => type_refs.is_empty(),
+        _ => false,
+    }
+}
+
+fn is_named_one_of(type_ref: &TypeRef, names: &[&str]) -> bool {
+    match type_ref {
+        &TypeRef::Named { ref name, .. } => names.contains(&name.as_str()),
+        _ => false,
+    }
+}
+
+impl<'a, 'b> VisitorMut<()> for SignatureChecker<'a, 'b> {
+    fn accept_type_ref(&mut self, _x: &TypeRef) -> () {}
+
+    fn accept_var_decl(&mut self, x: &VarDecl) -> () {
+        self.locals.insert(x.name.clone(), x.type_ref.clone());
+    }
+
+    fn accept_literal(&mut self, _x: &Literal) -> () {}
+
+    fn accept_operator(&mut self, _x: &Operator) -> () {}
+
+    fn accept_expression(&mut self, x: &Expression) -> () {
+        match x {
+            &Expression::Literal(..) | &Expression::Identifier(..) | &Expression::Path(..) => {}
+            &Expression::MemberOf { ref structure, .. } => self.accept_expression(structure),
+            &Expression::BinOp { ref left, ref right, .. } => {
+                self.accept_expression(left);
+                self.accept_expression(right);
+            }
+            &Expression::FnCall { ref target, ref args } => {
+                self.accept_expression(target);
+                for arg in args.iter() {
+                    self.accept_expression(arg);
+                }
+                self.check_call(target, args, false);
+            }
+            &Expression::Closure { ref body } => {
+                let in_closure = self.in_closure;
+                self.in_closure = true;
+                for statement in body.iter() {
+                    self.accept_statement(statement);
+                }
+                self.in_closure = in_closure;
+            }
+            &Expression::Join { .. } => {
+                self.diagnostics.push(Diagnostic::error(format!("`join` in `{}` must be awaited", self.context)));
+            }
+            &Expression::Boxed { .. } => {
+                self.diagnostics.push(Diagnostic::error(format!("`box` in `{}` must be awaited", self.context)));
+            }
+        }
+    }
+
+    fn accept_statement(&mut self, x: &Statement) -> () {
+        match x {
+            &Statement::Let { ref var, ref expr } => {
+                self.accept_expression(expr);
+                self.check_store(&var.name, &var.type_ref, expr);
+                self.accept_var_decl(var);
+            }
+            &Statement::Assignment { ref target, ref expr } => {
+                self.accept_expression(target);
+                self.accept_expression(expr);
+                if let (&Expression::Identifier(ref name), Some(ArgType::Typed(type_ref))) = (target, self.arg_type(target)) {
+                    self.check_store(name, &type_ref, expr);
+                }
+            }
+            &Statement::FnCall { ref target, ref args } => {
+                for arg in args.iter() {
+                    self.accept_expression(arg);
+                }
+                self.check_call(target, args, false);
+            }
+            &Statement::Await(ref expr) => self.accept_awaited(expr),
+            &Statement::LetAwait { ref var, ref expr } => {
+                self.accept_awaited(expr);
+                self.check_binding(var, expr);
+                self.accept_var_decl(var);
+            }
+            &Statement::Spawn { task: Expression::FnCall { ref target, ref args }, .. } => {
+                for arg in args.iter() {
+                    self.accept_expression(arg);
+                }
+                self.check_call(target, args, false);
+            }
+            &Statement::Spawn { ref task, .. } => self.accept_expression(task),
+            &Statement::Loop(ref statements) | &Statement::Defer(ref statements) | &Statement::Atomic(ref statements) => {
+                for statement in statements.iter() {
+                    self.accept_statement(statement);
+                }
+            }
+            &Statement::Select(ref arms) => {
+                for arm in arms.iter() {
+                    self.accept_select_arm(arm);
+                }
+            }
+            &Statement::Yield(ref expr) => {
+                self.accept_expression(expr);
+                if let Some(yields) = self.yields.clone() {
+                    if self.compatible(expr, &yields) == false {
+                        self.diagnostics.push(Diagnostic::error(format!(
+                            "`yield` in `{}` expects a `{}`", self.context, display(&yields),
+                        )));
+                    }
+                }
+            }
+            &Statement::ForAwait { ref var, ref stream, ref body } => {
+                self.accept_expression(stream);
+                if let &Expression::FnCall { ref target, .. } = stream {
+                    match self.symbols.function(target) {
+                        Some(callee) if callee.generator && *callee.returns != var.type_ref => {
+                            self.diagnostics.push(Diagnostic::error(format!(
+                                "`{}` in `{}` is declared as `{}` but `{}` yields `{}`",
+                                var.name, self.context, display(&var.type_ref), callee.c_name, display(callee.returns),
+                            )));
+                        }
+                        _ => {}
+                    }
+                }
+                self.accept_var_decl(var);
+                for statement in body.iter() {
+                    self.accept_statement(statement);
+                }
+            }
+            &Statement::Suspend { ref continuation, ref body } => {
+                self.locals.insert(continuation.clone(), TypeRef::Named {
+                    name: CONTINUATION.into(),
+                    type_params: vec![],
+                });
+                for statement in body.iter() {
+                    self.accept_statement(statement);
+                }
+            }
+            &Statement::Return(ref expr) => {
+                self.accept_expression(expr);
+                if self.in_closure {
+                    self.diagnostics.push(Diagnostic::error(format!(
+                        "a closure in `{}` cannot `return`", self.context,
+                    )).with_help("a closure runs as a continuation and completes with nothing".into()));
+                } else if let Some(returns) = self.returns.clone() {
+                    if self.compatible(expr, &returns) == false {
+                        self.diagnostics.push(Diagnostic::error(format!(
+                            "`return` in `{}` expects a `{}`", self.context, display(&returns),
+                        )));
+                    }
+                } else {
+                    self.diagnostics.push(Diagnostic::error(format!(
+                        "`return` in `{}`, which does not complete with a value", self.context,
+                    )).with_help(
+                        "only functions declared with a return type can `return`; an `async gen` hands out values with `yield`".into(),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn accept_top_level_node(&mut self, x: &TopLevelNode) -> () {
+        self.locals.clear();
+
+        let (name, params, body) = match x {
+            &TopLevelNode::FnDecl { ref name, ref params, ref body, .. } => (name, &params[..], body),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, &[][..], body),
+            _ => return,
+        };
+
+        self.context = name.clone();
+        self.yields = match x {
+            &TopLevelNode::FnDecl { ref returns, generator: true, .. } => Some(returns.clone()),
+            _ => None,
+        };
+        self.returns = match x {
+            &TopLevelNode::FnDecl { ref returns, generator: false, .. } if is_unit(returns) == false => Some(returns.clone()),
+            _ => None,
+        };
+        for param in params.iter() {
+            self.accept_var_decl(param);
+        }
+        for statement in body.iter() {
+            self.accept_statement(statement);
+        }
+
+        let last = body.last();
+        walk::statements(body, &mut |statement| {
+            if let &Statement::Return(..) = statement {
+                if last.map(|last| ::std::ptr::eq(last, statement)).unwrap_or(false) == false {
+                    self.diagnostics.push(Diagnostic::error(format!(
+                        "`return` in `{}` must be the last statement of its body", name,
+                    )));
+                }
+            }
+        });
+        if let Some(returns) = self.returns.clone() {
+            match last {
+                Some(&Statement::Return(..)) => {}
+                _ => {
+                    self.diagnostics.push(Diagnostic::error(format!(
+                        "`{}` returns `{}` but its body does not end with `return`", name, display(&returns),
+                    )));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    #[test]
+    fn calls_to_extern_functions_are_checked() {
+        let errors = errors(vec![
+            extern_fn("uart_write", vec![var("byte", ty("u8"))], unit(), false),
+            plain_fn("idle", vec![], unit(), vec![
+                call_statement("uart_write", vec![]),
+                call_statement("uart_write", vec![Expression::Literal(Literal::String("x".into()))]),
+                call_statement("uart_read", vec![]),
+            ]),
+        ]);
+        assert!(mentions(&errors, "`uart_write` takes 1 argument(s) but 0 were supplied in `idle`"), "{:?}", errors);
+        assert!(mentions(&errors, "argument `byte` of `uart_write` has the wrong type in `idle`"), "{:?}", errors);
+        assert!(mentions(&errors, "call to undeclared function `uart_read` in `idle`"), "{:?}", errors);
+    }
+
+    #[test]
+    fn returns_are_checked_against_the_function() {
+        let errors = errors(vec![
+            async_fn("measure", vec![], ty("u32"), vec![
+                Statement::Return(Expression::Literal(Literal::Boolean(true))),
+            ]),
+            async_fn("forgets", vec![], ty("u32"), vec![]),
+            async_fn("early", vec![], ty("u32"), vec![
+                Statement::Loop(vec![Statement::Return(int(1))]),
+                Statement::Return(int(2)),
+            ]),
+            async_fn("nothing", vec![], unit(), vec![Statement::Return(int(1))]),
+        ]);
+        assert!(mentions(&errors, "`return` in `measure` expects a `u32`"), "{:?}", errors);
+        assert!(mentions(&errors, "`forgets` returns `u32` but its body does not end with `return`"), "{:?}", errors);
+        assert!(mentions(&errors, "`return` in `early` must be the last statement of its body"), "{:?}", errors);
+        assert!(mentions(&errors, "`return` in `nothing`, which does not complete with a value"), "{:?}", errors);
+    }
+
+    #[test]
+    fn select_arms_must_be_cancellable() {
+        let errors = errors(vec![
+            extern_fn("uart_read", vec![], ty("u8"), true),
+            async_fn("poll", vec![], ty("u8"), vec![Statement::Return(int(0))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Loop(vec![
+                    Statement::Select(vec![
+                        SelectArm { binding: None, task: call("uart_read", vec![]), body: vec![] },
+                        SelectArm { binding: None, task: call("poll", vec![]), body: vec![] },
+                    ]),
+                ]),
+            ]),
+        ]);
+        assert_eq!(errors, vec!["`select` in `main` cannot race `uart_read`, which cannot be cancelled".to_string()]);
+    }
+
+    #[test]
+    fn types_without_a_c_representation_are_reported() {
+        let pair = || TypeRef::Tuple { type_refs: vec![ty("u8"), ty("u8")] };
+        let bytes = || TypeRef::Array { element: Box::new(ty("u8")), length: Box::new(int(4)) };
+        let errors = errors(vec![
+            extern_fn("read_pair", vec![], pair(), true),
+            plain_fn("fill", vec![var("buffer", bytes())], unit(), vec![]),
+            async_fn("frame", vec![], bytes(), vec![Statement::Return(ident("frame"))]),
+            TopLevelNode::GlobalDecl(var("last", pair())),
+        ]);
+        assert!(mentions(&errors, "the result of `read_pair` has the type `(u8, u8)`, but tuples cannot be represented in C yet"), "{:?}", errors);
+        assert!(mentions(&errors, "parameter `buffer` of `fill` is an array"), "{:?}", errors);
+        assert!(mentions(&errors, "`frame` returns an array"), "{:?}", errors);
+        assert!(mentions(&errors, "`last` has the type `(u8, u8)`"), "{:?}", errors);
+    }
+}
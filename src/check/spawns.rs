@@ -0,0 +1,190 @@
+use super::Diagnostic;
+use super::symbols::SymbolTable;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+use super::super::module::Module;
+
+/// The function the runtime calls once at startup, whose top-level spawns
+/// therefore run exactly once.
+const INIT_FN: &str = "init";
+
+/// The `spawn` statements that run exactly once, which are those of `init`
+/// in the root module outside loops, `select` arms and `defer` blocks. Each
+/// gets a task pool slot of its own.
+pub fn static_spawns(module: &Module) -> Vec<&Statement> {
+    init_spawns(module).into_iter()
+        .filter(|&(_, nesting)| nesting == Nesting::Once)
+        .map(|(statement, _)| statement)
+        .collect()
+}
+
+/// Where a `spawn` in `init` sits, which decides how often it runs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Nesting {
+    Once,
+    /// Inside a `loop` or `for await`, so it may run without bound.
+    Loop,
+    /// Inside the body of the given kind of statement, which may run once,
+    /// never, or at a later time.
+    Within(&'static str),
+}
+
+/// The `spawn` statements of `init` in the root module with where they sit.
+fn init_spawns(module: &Module) -> Vec<(&Statement, Nesting)> {
+    let mut result = Vec::new();
+    if module.path.is_empty() == false {
+        return result;
+    }
+
+    for node in module.nodes.iter() {
+        if let &TopLevelNode::FnDecl { ref name, ref body, async: false, .. } = node {
+            if name == INIT_FN {
+                collect_spawns(body, Nesting::Once, &mut result);
+            }
+        }
+    }
+    result
+}
+
+fn collect_spawns<'a>(body: &'a [Statement], nesting: Nesting, result: &mut Vec<(&'a Statement, Nesting)>) {
+    // The innermost loop decides, since it repeats whatever it contains.
+    let inner = |kind| if nesting == Nesting::Loop { Nesting::Loop } else { kind };
+
+    for statement in body.iter() {
+        match statement {
+            &Statement::Spawn { .. } => result.push((statement, nesting)),
+            &Statement::Loop(ref nested) => collect_spawns(nested, Nesting::Loop, result),
+            &Statement::ForAwait { ref body, .. } => collect_spawns(body, Nesting::Loop, result),
+            &Statement::Atomic(ref nested) | &Statement::Suspend { body: ref nested, .. } => {
+                collect_spawns(nested, nesting, result)
+            }
+            &Statement::Defer(ref nested) => collect_spawns(nested, inner(Nesting::Within("defer")), result),
+            &Statement::Select(ref arms) => {
+                for arm in arms.iter() {
+                    collect_spawns(&arm.body, inner(Nesting::Within("select")), result);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Checks that only async functions of this program are spawned, at
+/// priorities that agree with their declarations, and that spawns known to
+/// run without bound cannot exhaust their task pool.
+pub fn check(module: &Module, symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let nestings = init_spawns(module);
+
+    for node in module.nodes.iter() {
+        if let &TopLevelNode::FnDecl { ref name, async: false, priority: Some(..), .. } = node {
+            diagnostics.push(Diagnostic::error(format!("`{}` has a priority but is not async", name))
+                .with_help("only tasks are scheduled; remove the priority".into()));
+        }
+    }
+
+    for node in module.nodes.iter() {
+        let (name, body) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, .. } => (name, body),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body),
+            _ => continue,
+        };
+
+        walk::statements(body, &mut |statement| {
+            let (task, priority) = match statement {
+                &Statement::Spawn { ref task, priority } => (task, priority),
+                _ => return,
+            };
+
+            let callee = match task {
+                &Expression::FnCall { ref target, .. } => symbols.function(target),
+                _ => {
+                    diagnostics.push(Diagnostic::error(format!("`spawn` in `{}` can only start calls to async functions", name)));
+                    return;
+                }
+            };
+            match callee {
+                Some(callee) if callee.async && callee.external == false => {
+                    if let (Some(spawned), Some(declared)) = (priority, callee.priority) {
+                        if spawned != declared {
+                            diagnostics.push(Diagnostic::error(format!(
+                                "`{}` is spawned in `{}` at priority {} but always runs at priority {}",
+                                callee.c_name, name, spawned, declared,
+                            )).with_help(format!("remove the priority from either the `spawn` or `{}`", callee.c_name)));
+                        }
+                    }
+                }
+                Some(callee) => {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}` is spawned in `{}` but is not an async function of this program", callee.c_name, name,
+                    )).with_help("only async functions written in asynclang have a task state to put in a pool".into()));
+                    return;
+                }
+                None => return,
+            }
+
+            match nestings.iter().find(|&&(s, _)| ::std::ptr::eq(s, statement)).map(|&(_, nesting)| nesting) {
+                Some(Nesting::Loop) => {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`spawn` inside a loop in `{}` overflows the task pool: it spawns without bound at startup", name,
+                    )).with_help("spawn the task once and loop inside it instead".into()));
+                }
+                Some(Nesting::Within(kind)) => {
+                    diagnostics.push(Diagnostic::warning(format!(
+                        "`spawn` inside a `{}` in `{}` may not run exactly once, so it shares the task pool with other spawns",
+                        kind, name,
+                    )).with_help("move it to the top level of `init` to give the task a slot of its own".into()));
+                }
+                _ => {}
+            }
+        });
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    fn spawn(name: &str) -> Statement {
+        Statement::Spawn { task: call(name, vec![]), priority: None }
+    }
+
+    #[test]
+    fn priorities_are_declared_on_tasks_and_agree_with_spawns() {
+        let errors = errors(vec![
+            TopLevelNode::FnDecl {
+                name: "log".into(), params: vec![], returns: unit(), body: vec![],
+                async: false, public: false, priority: Some(1), generator: false,
+            },
+            TopLevelNode::FnDecl {
+                name: "motor".into(), params: vec![], returns: unit(), body: vec![],
+                async: true, public: false, priority: Some(3), generator: false,
+            },
+            plain_fn("init", vec![], unit(), vec![
+                Statement::Spawn { task: call("motor", vec![]), priority: Some(2) },
+            ]),
+        ]);
+        assert!(mentions(&errors, "`log` has a priority but is not async"), "{:?}", errors);
+        assert!(mentions(&errors, "`motor` is spawned in `init` at priority 2 but always runs at priority 3"), "{:?}", errors);
+    }
+
+    #[test]
+    fn spawns_in_init_are_reported_by_where_they_sit() {
+        let (errors, warnings) = diagnostics(vec![
+            async_fn("blink", vec![], unit(), vec![]),
+            plain_fn("init", vec![], unit(), vec![
+                spawn("blink"),
+                Statement::Atomic(vec![spawn("blink")]),
+                Statement::Defer(vec![spawn("blink")]),
+                Statement::Loop(vec![Statement::Defer(vec![spawn("blink")])]),
+            ]),
+        ]);
+        let loops = errors.iter().filter(|error| error.contains("`spawn` inside a loop in `init`")).count();
+        assert_eq!(loops, 1, "{:?}", errors);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(mentions(&warnings, "`spawn` inside a `defer` in `init` may not run exactly once"), "{:?}", warnings);
+    }
+}
@@ -0,0 +1,609 @@
+use std::collections::HashMap;
+use std::io::{Error, Write};
+
+use petgraph::{Direction, stable_graph::NodeIndex, visit::EdgeRef};
+
+use super::Diagnostic;
+use super::{channels, continuations, interrupts, locks, timers};
+use super::symbols::SymbolTable;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+use super::super::cfg::builder::Builder;
+use super::super::cfg::cfg::ControlFlowGraph;
+use super::super::cfg::graph::Edge;
+use super::super::options::{Dispatch, Options};
+
+/// A C function on the stack of a synchronous run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+enum Frame<'a> {
+    Function(&'a str),
+    Interrupt(&'a str),
+    /// The function that starts a task, which runs its first block.
+    Start(&'a str),
+    /// The function generated for a block of a task.
+    Block(&'a str, NodeIndex),
+}
+
+impl<'a> Frame<'a> {
+    fn name(&self) -> &'a str {
+        match *self {
+            Frame::Function(name) | Frame::Interrupt(name) | Frame::Start(name) | Frame::Block(name, _) => name,
+        }
+    }
+}
+
+/// The deepest synchronous run of an interrupt handler, or of a task from any
+/// of the points it is started or resumed at.
+pub struct StackDepth<'a> {
+    pub name: &'a str,
+    pub interrupt: bool,
+    /// The most C functions on the stack at once, or `None` where a run may
+    /// never suspend.
+    pub frames: Option<usize>,
+}
+
+/// Finds how deep the C stack grows between suspension points. Tasks call
+/// their next block directly, and invoke the continuation of whatever they
+/// complete into, so a chain of awaits that complete on the spot runs on one
+/// stack; a run ends only where every function on it returns, once the task
+/// is waiting on a timer, the ready queue or an interrupt. Each further pass
+/// of a loop starts from the ready queue.
+///
+/// Awaits of C functions are assumed to complete on the spot, and calls into
+/// C or other modules to take one frame. Warns about
+/// recursion, and about loops that may never suspend.
+pub fn analyze<'a>(nodes: &'a [TopLevelNode], symbols: &SymbolTable<'a>, options: &Options) -> (Vec<StackDepth<'a>>, Vec<Diagnostic>) {
+    let mut graph = CallGraph {
+        symbols,
+        edges: HashMap::new(),
+        invokes: Vec::new(),
+        completions: Vec::new(),
+        stores: Vec::new(),
+        awaiters: HashMap::new(),
+        resumed: HashMap::new(),
+    };
+
+    let deferred = interrupts::deferred_functions(nodes, options);
+    let mut tasks: Vec<(&str, ControlFlowGraph)> = Vec::new();
+    let mut handlers = Vec::new();
+    for node in nodes.iter() {
+        match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async: true, .. } => {
+                let mut cfg = Builder::new(name).build(body);
+                cfg.tidy_graph();
+                graph.task(name, &cfg);
+                tasks.push((name, cfg));
+            }
+            &TopLevelNode::FnDecl { ref name, ref body, ref params, .. } => {
+                graph.function(name, params, body, deferred.contains(&name.as_str()));
+            }
+            &TopLevelNode::InterruptDecl { ref name, ref body } => {
+                let frame = Frame::Interrupt(name);
+                graph.calls(frame, call_targets(body));
+                if options.dispatch(name) == Dispatch::Direct {
+                    graph.invoked_globals(frame, body);
+                }
+                handlers.push(name.as_str());
+            }
+            _ => {}
+        }
+    }
+    graph.link();
+
+    let mut search = Search { graph: &graph, visits: HashMap::new(), path: Vec::new(), cycles: Vec::new() };
+    let mut depths = Vec::new();
+    for interrupt in handlers {
+        let frames = search.depth(Frame::Interrupt(interrupt));
+        depths.push(StackDepth { name: interrupt, interrupt: true, frames });
+    }
+    for &(name, ref cfg) in tasks.iter() {
+        let back_edges = cfg.back_edges();
+        let mut roots = vec![Frame::Start(name)];
+        roots.extend(cfg.graph.node_indices()
+            .filter(|&idx| cfg.graph.edges_directed(idx, Direction::Incoming)
+                .any(|edge| *edge.weight() == Edge::Await || *edge.weight() == Edge::Yield)
+                || back_edges.iter().any(|&(_, to)| to == idx))
+            .map(|idx| Frame::Block(name, idx)));
+
+        let mut frames = Some(0);
+        for root in roots {
+            frames = match (frames, search.depth(root)) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            };
+        }
+        depths.push(StackDepth { name, interrupt: false, frames });
+    }
+
+    let mut diagnostics = Vec::new();
+    let mut reported: Vec<Vec<&str>> = Vec::new();
+    for cycle in search.cycles.iter() {
+        let mut path = cycle.iter().map(|frame| frame.name()).collect::<Vec<_>>();
+        path.dedup();
+        if path.len() > 1 && path.first() == path.last() {
+            path.pop();
+        }
+        let mut key = path.clone();
+        key.sort();
+        key.dedup();
+        if reported.contains(&key) {
+            continue;
+        }
+        reported.push(key);
+
+        path.push(path[0]);
+        let path = path.iter().map(|name| format!("`{}`", name)).collect::<Vec<_>>().join(" -> ");
+        let recursive = cycle.iter().all(|frame| match frame {
+            &Frame::Function(..) => true,
+            _ => false,
+        });
+        diagnostics.push(if recursive {
+            Diagnostic::warning(format!("`{}` is recursive: {}", cycle[0].name(), path))
+                .with_help("the stack it needs cannot be bounded; write it as a loop".into())
+        } else {
+            Diagnostic::warning(format!("`{}` may run on without ever suspending: {}", cycle[0].name(), path))
+                .with_help("an await that completes on the spot resumes its caller deeper in the same C stack; make sure each pass suspends, e.g. on `sleep_ms`".into())
+        });
+    }
+
+    (depths, diagnostics)
+}
+
+/// Writes the stack depths of a module as a report.
+pub fn write_report(w: &mut Write, module: &str, depths: &[StackDepth]) -> Result<(), Error> {
+    writeln!(w, "stack depth of module `{}`:", module)?;
+    for depth in depths.iter() {
+        let name = format!("{} {}", if depth.interrupt { "interrupt" } else { "task" }, depth.name);
+        match depth.frames {
+            Some(frames) => writeln!(w, "    {:<32} {:>6} frames", name, frames)?,
+            None => writeln!(w, "    {:<32}   unbounded", name)?,
+        }
+    }
+    Ok(())
+}
+
+/// Which frames may call which, each with the number of frames of C and the
+/// runtime in between. Calls into C of unknown depth have no callee frame.
+struct CallGraph<'a, 's: 'a> {
+    symbols: &'a SymbolTable<'s>,
+    edges: HashMap<Frame<'s>, Vec<(Option<Frame<'s>>, usize)>>,
+    /// The frames that invoke a continuation stored in a global.
+    invokes: Vec<(Frame<'s>, &'s str)>,
+    /// The frames in which a task completes or yields, or a plain function
+    /// invokes the continuation it was handed.
+    completions: Vec<(&'s str, Frame<'s>)>,
+    /// The globals plain functions store the continuation they were handed in.
+    stores: Vec<(&'s str, &'s str)>,
+    /// The frames that resume once a task or plain function completes, and
+    /// the frames of the runtime in between. `None` stands for a `select`
+    /// arm that schedules the next pass of a loop, after which the run ends.
+    awaiters: HashMap<&'s str, Vec<(Option<Frame<'s>>, usize)>>,
+    /// The frames resumed through each global continuation.
+    resumed: HashMap<&'s str, Vec<Option<Frame<'s>>>>,
+}
+
+impl<'a, 's> CallGraph<'a, 's> {
+    fn edge(&mut self, from: Frame<'s>, to: Option<Frame<'s>>, between: usize) {
+        self.edges.entry(from).or_insert_with(Vec::new).push((to, between));
+    }
+
+    /// Adds a plain function, which resumes tasks through the ready queue
+    /// if it is `deferred` like an interrupt it runs in.
+    fn function(&mut self, name: &'s str, params: &'s [VarDecl], body: &'s [Statement], deferred: bool) {
+        let frame = Frame::Function(name);
+        self.calls(frame, call_targets(body));
+        if deferred == false {
+            self.invoked_globals(frame, body);
+        }
+
+        let mut handed = params.iter()
+            .filter(|param| continuations::is_continuation(&param.type_ref))
+            .map(|param| param.name.as_str())
+            .collect::<Vec<_>>();
+        handed.extend(continuations::suspended(body));
+        let (invoked, stored) = handed_on(&handed, body, self.symbols);
+        if invoked && deferred == false {
+            self.completions.push((name, frame));
+        }
+        for global in stored {
+            self.stores.push((name, global));
+        }
+    }
+
+    fn task(&mut self, name: &'s str, cfg: &ControlFlowGraph<'s>) {
+        self.edge(Frame::Start(name), Some(Frame::Block(name, cfg.entry_node)), 0);
+
+        // jumps back to the start of a loop go through the ready queue
+        let back_edges = cfg.back_edges();
+
+        for idx in cfg.graph.node_indices() {
+            let frame = Frame::Block(name, idx);
+            let statements = &cfg.graph[idx].statements;
+            for &statement in statements.iter() {
+                // the bodies of `select` arms and `for await` loops are
+                // blocks of their own
+                let targets = match statement {
+                    &Statement::Select(ref arms) => arms.iter().flat_map(|arm| expression_targets(&arm.task)).collect(),
+                    &Statement::ForAwait { ref stream, .. } => expression_targets(stream),
+                    _ => call_targets(::std::slice::from_ref(statement)),
+                };
+                self.calls(frame, targets);
+                self.invoked_globals(frame, ::std::slice::from_ref(statement));
+            }
+
+            let exits = cfg.graph.edges(idx)
+                .map(|edge| (edge.target(), *edge.weight()))
+                .collect::<Vec<_>>();
+            let exit = |wanted: Edge| exits.iter()
+                .find(|&&(_, kind)| kind == wanted)
+                .map(|&(target, _)| Frame::Block(name, target));
+            let last = statements.last().map(|statement| *statement);
+
+            match last {
+                Some(&Statement::Select(ref arms)) => {
+                    for (i, arm) in arms.iter().enumerate() {
+                        let target = exits.iter().find(|&&(_, kind)| kind == Edge::SelectArm(i)).map(|&(target, _)| target);
+                        if let Some(target) = target {
+                            let into = Frame::Block(name, target);
+                            let into = if back_edges.contains(&(idx, target)) { None } else { Some(into) };
+                            self.child(frame, &arm.task, into, 2);
+                        }
+                    }
+                    continue;
+                }
+                Some(&Statement::ForAwait { ref stream, .. }) if exit(Edge::StreamItem).is_some() => {
+                    // the generator yields and finishes into a function that
+                    // picks the block to run
+                    for &wanted in [Edge::StreamItem, Edge::StreamEnd].iter() {
+                        let into = exit(wanted).expect("`for await` loops always both take an item and end");
+                        self.child(frame, stream, Some(into), 2);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            match exits.len() {
+                0 => self.completions.push((name, frame)),
+                1 => {
+                    let (target, kind) = exits[0];
+                    let next = Frame::Block(name, target);
+                    match (kind, last) {
+                        (Edge::Await, Some(&Statement::Suspend { ref continuation, ref body })) => {
+                            let (invoked, stored) = handed_on(&[continuation.as_str()], body, self.symbols);
+                            if invoked {
+                                self.edge(frame, Some(next), 1);
+                            }
+                            for global in stored {
+                                self.resumed.entry(global).or_insert_with(Vec::new).push(Some(next));
+                            }
+                        }
+                        (Edge::Await, Some(statement)) => self.awaited(frame, statement, next),
+                        (Edge::Yield, _) => self.completions.push((name, frame)),
+                        _ if back_edges.contains(&(idx, target)) => {}
+                        _ => self.edge(frame, Some(next), 0),
+                    }
+                }
+                // a `for await` body that loops back asks the generator for
+                // the next value through the ready queue
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds the frames a block may run on top of itself after the `await` it
+    /// ends with, if that completes on the spot.
+    fn awaited(&mut self, frame: Frame<'s>, statement: &'s Statement, next: Frame<'s>) {
+        let expr = match walk::awaited(statement) {
+            Some(expr) => expr,
+            None => return,
+        };
+
+        if channels::awaited_channel_call(statement, self.symbols).is_some()
+            || locks::awaited_lock_call(statement, self.symbols).is_some() {
+            // channels and locks always resume their tasks through the ready
+            // queue
+        } else if timers::awaits_sleep(statement, self.symbols) {
+            // timers always expire through the ready queue
+        } else if let Some((_, task)) = timers::timeout_call(expr, self.symbols) {
+            self.child(frame, task, Some(next), 2);
+        } else if let &Expression::Join { ref tasks } = expr {
+            for task in tasks.iter() {
+                self.child(frame, task, Some(next), 2);
+            }
+        } else if let &Expression::Identifier(..) = expr {
+            self.edge(frame, Some(next), 0);
+        } else if let &Expression::Boxed { ref task, .. } = expr {
+            self.child(frame, task, Some(next), 1);
+        } else {
+            self.child(frame, expr, Some(next), 1);
+        }
+    }
+
+    /// Adds the ways a task or plain function that `frame` starts may
+    /// complete into `into`, through `between` frames of the runtime.
+    fn child(&mut self, frame: Frame<'s>, task: &'s Expression, into: Option<Frame<'s>>, between: usize) {
+        let target = match task {
+            &Expression::FnCall { ref target, .. } => target,
+            _ => return,
+        };
+        if timers::is_sleep(target, self.symbols) {
+            // timers always expire through the ready queue
+            return;
+        }
+        match (self.symbols.function(target), &**target) {
+            (Some(callee), &Expression::Identifier(ref name)) if callee.external == false => {
+                if callee.async || callee.takes_implicit_continuation() {
+                    self.awaiters.entry(name.as_str()).or_insert_with(Vec::new).push((into, between));
+                }
+            }
+            // C and other modules may invoke the continuation on the spot
+            _ => self.edge(frame, into, between + 1),
+        }
+    }
+
+    fn calls(&mut self, frame: Frame<'s>, targets: Vec<&'s Expression>) {
+        for target in targets {
+            let callee = match (self.symbols.function(target), target) {
+                (Some(callee), &Expression::Identifier(ref name)) if callee.external == false => {
+                    if callee.async { Frame::Start(name) } else { Frame::Function(name) }
+                }
+                _ => {
+                    self.edge(frame, None, 1);
+                    continue;
+                }
+            };
+            self.edge(frame, Some(callee), 0);
+        }
+    }
+
+    fn invoked_globals(&mut self, frame: Frame<'s>, body: &'s [Statement]) {
+        let symbols = self.symbols;
+        walk::statements(body, &mut |statement| {
+            if let &Statement::Await(Expression::Identifier(ref name)) = statement {
+                if symbols.globals.get(name.as_str()).map(|g| continuations::is_continuation(&g.var.type_ref)).unwrap_or(false) {
+                    self.invokes.push((frame, name));
+                }
+            }
+        });
+    }
+
+    /// Resolves the continuations that completions and globals invoke into
+    /// edges.
+    fn link(&mut self) {
+        for &(function, global) in self.stores.iter() {
+            let awaiters = self.awaiters.get(function).cloned().unwrap_or_default();
+            let resumed = self.resumed.entry(global).or_insert_with(Vec::new);
+            resumed.extend(awaiters.into_iter().map(|(into, _)| into));
+        }
+
+        let mut edges = Vec::new();
+        for &(frame, global) in self.invokes.iter() {
+            for &into in self.resumed.get(global).map(|r| &r[..]).unwrap_or(&[]) {
+                edges.push((frame, into, 1));
+            }
+        }
+        for &(task, frame) in self.completions.iter() {
+            for &(into, between) in self.awaiters.get(task).map(|a| &a[..]).unwrap_or(&[]) {
+                edges.push((frame, into, between));
+            }
+        }
+        for (from, to, between) in edges {
+            self.edge(from, to, between);
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Visit {
+    Active,
+    Done(Option<usize>),
+}
+
+/// A depth-first search for the deepest path from each frame, which
+/// records the cycles it runs into.
+struct Search<'g, 'a: 'g, 's: 'a> {
+    graph: &'g CallGraph<'a, 's>,
+    visits: HashMap<Frame<'s>, Visit>,
+    path: Vec<Frame<'s>>,
+    cycles: Vec<Vec<Frame<'s>>>,
+}
+
+impl<'g, 'a, 's> Search<'g, 'a, 's> {
+    /// The most frames on the stack while `frame` runs, including itself.
+    fn depth(&mut self, frame: Frame<'s>) -> Option<usize> {
+        match self.visits.get(&frame) {
+            Some(&Visit::Done(depth)) => return depth,
+            Some(&Visit::Active) => {
+                let start = self.path.iter().position(|&f| f == frame).unwrap();
+                self.cycles.push(self.path[start..].to_vec());
+                return None;
+            }
+            None => {}
+        }
+
+        self.visits.insert(frame, Visit::Active);
+        self.path.push(frame);
+        let mut deepest = Some(0);
+        for &(callee, between) in self.graph.edges.get(&frame).map(|e| &e[..]).unwrap_or(&[]) {
+            let depth = match callee {
+                Some(callee) => self.depth(callee).map(|depth| depth + between),
+                None => Some(between),
+            };
+            deepest = match (deepest, depth) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            };
+        }
+        self.path.pop();
+
+        let depth = deepest.map(|depth| depth + 1);
+        self.visits.insert(frame, Visit::Done(depth));
+        depth
+    }
+}
+
+/// The functions a body calls.
+fn call_targets(body: &[Statement]) -> Vec<&Expression> {
+    let mut result = Vec::new();
+    walk::expressions(body, &mut |expr| {
+        if let &Expression::FnCall { ref target, .. } = expr {
+            result.push(&**target);
+        }
+    });
+    walk::statements(body, &mut |statement| {
+        if let &Statement::FnCall { ref target, .. } = statement {
+            result.push(target);
+        }
+    });
+    result
+}
+
+fn expression_targets(expr: &Expression) -> Vec<&Expression> {
+    let mut result = Vec::new();
+    walk::subexpressions(expr, &mut |expr| {
+        if let &Expression::FnCall { ref target, .. } = expr {
+            result.push(&**target);
+        }
+    });
+    result
+}
+
+/// Whether a body invokes one of the given continuations on the spot, and
+/// the globals it stores them in for an interrupt to invoke.
+fn handed_on<'s>(names: &[&str], body: &'s [Statement], symbols: &SymbolTable) -> (bool, Vec<&'s str>) {
+    let mut invoked = false;
+    let mut stored = Vec::new();
+    walk::statements(body, &mut |statement| {
+        match statement {
+            &Statement::Await(Expression::Identifier(ref name)) if names.contains(&name.as_str()) => invoked = true,
+            &Statement::Assignment { target: Expression::Identifier(ref global), expr: Expression::Identifier(ref name) } => {
+                if names.contains(&name.as_str()) && symbols.globals.contains_key(global.as_str()) {
+                    stored.push(global.as_str());
+                }
+            }
+            _ => {}
+        }
+    });
+    (invoked, stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, write_report};
+    use super::super::symbols::SymbolTable;
+    use super::super::super::ast::nodes::*;
+    use super::super::super::module::Module;
+    use super::super::super::options::{Dispatch, Options};
+    use super::super::super::testing::*;
+
+    fn stack_warnings(nodes: Vec<TopLevelNode>) -> Vec<String> {
+        let module = Module::root(nodes);
+        let (symbols, _) = SymbolTable::build(&module);
+        let (_, diagnostics) = analyze(&module.nodes, &symbols, &Options::default());
+        diagnostics.into_iter().map(|d| d.message).collect()
+    }
+
+    fn channel(name: &str) -> TopLevelNode {
+        TopLevelNode::GlobalDecl(var(name, TypeRef::Channel { element: Box::new(ty("u8")), capacity: Box::new(int(4)) }))
+    }
+
+    #[test]
+    fn channel_loops_suspend_on_every_pass() {
+        let warnings = stack_warnings(vec![
+            channel("bytes"),
+            async_fn("pump", vec![], unit(), vec![
+                Statement::Loop(vec![Statement::Await(method_call("bytes", "send", vec![int(1)]))]),
+            ]),
+            async_fn("drain", vec![], unit(), vec![
+                Statement::Loop(vec![let_await("byte", ty("u8"), method_call("bytes", "recv", vec![]))]),
+            ]),
+        ]);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn lock_loops_suspend_on_every_pass() {
+        let warnings = stack_warnings(vec![
+            TopLevelNode::GlobalDecl(var("permits", TypeRef::Semaphore { initial: Box::new(int(2)) })),
+            TopLevelNode::GlobalDecl(var("bus", TypeRef::Named { name: "Mutex".into(), type_params: vec![ty("u8")] })),
+            async_fn("worker", vec![], unit(), vec![
+                Statement::Loop(vec![
+                    Statement::Await(method_call("permits", "acquire", vec![])),
+                    Statement::Await(method_call("bus", "lock", vec![])),
+                    Statement::FnCall {
+                        target: Expression::MemberOf { structure: Box::new(ident("bus")), member: "unlock".into() },
+                        args: vec![],
+                    },
+                    Statement::FnCall {
+                        target: Expression::MemberOf { structure: Box::new(ident("permits")), member: "release".into() },
+                        args: vec![],
+                    },
+                ]),
+            ]),
+        ]);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn loops_that_never_suspend_have_a_bounded_stack() {
+        let module = Module::root(vec![
+            TopLevelNode::GlobalDecl(var("count", ty("u32"))),
+            async_fn("step", vec![], unit(), vec![
+                Statement::Assignment { target: ident("count"), expr: add(ident("count"), int(1)) },
+            ]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Loop(vec![Statement::Await(call("step", vec![]))]),
+            ]),
+        ]);
+        let (symbols, _) = SymbolTable::build(&module);
+        let (depths, diagnostics) = analyze(&module.nodes, &symbols, &Options::default());
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics.iter().map(|d| &d.message).collect::<Vec<_>>());
+        let main = depths.iter().find(|depth| depth.name == "main").unwrap();
+        assert!(main.frames.is_some());
+    }
+
+    #[test]
+    fn recursion_is_reported() {
+        let warnings = stack_warnings(vec![
+            plain_fn("walk", vec![], unit(), vec![call_statement("walk", vec![])]),
+            async_fn("main", vec![], unit(), vec![call_statement("walk", vec![])]),
+        ]);
+        assert_eq!(warnings, vec!["`walk` is recursive: `walk` -> `walk`".to_string()]);
+    }
+
+    #[test]
+    fn interrupts_resuming_tasks_directly_run_them_on_their_stack() {
+        let module = Module::root(vec![
+            TopLevelNode::GlobalDecl(var("ready", ty("Continuation"))),
+            extern_fn("start_timer", vec![], unit(), false),
+            plain_fn("delay", vec![], unit(), vec![
+                Statement::Suspend { continuation: "k".into(), body: vec![
+                    Statement::Assignment { target: ident("ready"), expr: ident("k") },
+                    call_statement("start_timer", vec![]),
+                ] },
+            ]),
+            plain_fn("wake", vec![], unit(), vec![Statement::Await(ident("ready"))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Await(call("delay", vec![])),
+                call_statement("start_timer", vec![]),
+            ]),
+            TopLevelNode::InterruptDecl { name: "timer_overflow".into(), body: vec![call_statement("wake", vec![])] },
+        ]);
+        let (symbols, _) = SymbolTable::build(&module);
+        let depth = |options: &Options| {
+            let (depths, _) = analyze(&module.nodes, &symbols, options);
+            let mut report = Vec::new();
+            write_report(&mut report, "main", &depths).unwrap();
+            String::from_utf8(report).unwrap()
+        };
+
+        // the handler and `wake`, then the runtime invoking the rest of `main`
+        let direct = depth(&Options::default());
+        assert!(direct.contains("    interrupt timer_overflow              5 frames\n"), "{}", direct);
+        let mut options = Options::default();
+        options.dispatch.insert("timer_overflow".into(), Dispatch::Deferred);
+        let deferred = depth(&options);
+        assert!(deferred.contains("    interrupt timer_overflow              2 frames\n"), "{}", deferred);
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+
+use super::Diagnostic;
+use super::consteval::ConstValue;
+use super::continuations;
+use super::timers;
+use super::super::ast::nodes::*;
+use super::super::module::Module;
+
+/// Functions provided by the runtime rather than declared in source.
+pub const BUILTIN_FUNCTIONS: &[&str] = &["task_current", timers::SLEEP_MS, timers::TIMER_TICK, timers::TIMEOUT];
+
+#[derive(Clone)]
+pub struct FnSignature<'a> {
+    pub params: &'a [VarDecl],
+    pub returns: &'a TypeRef,
+    pub async: bool,
+    pub external: bool,
+    pub public: bool,
+    pub priority: Option<u8>,
+    /// Whether the function is an `async gen`, whose `returns` is the type
+    /// of the values it yields.
+    pub generator: bool,
+    /// Whether the function is a plain `fn` that hands its caller's
+    /// continuation out with `suspend`, and so completes asynchronously.
+    pub suspends: bool,
+    /// The name of the function in generated C.
+    pub c_name: String,
+}
+
+impl<'a> FnSignature<'a> {
+    /// A plain `fn` whose first parameter is a `Continuation`, or that
+    /// suspends, may be awaited, in which case the continuation is supplied
+    /// implicitly.
+    pub fn takes_implicit_continuation(&self) -> bool {
+        let first_param = self.params.first().map(|p| &p.type_ref);
+        self.async == false && (self.suspends || first_param.map(continuations::is_continuation).unwrap_or(false))
+    }
+
+    /// The parameters that an `await` of the function supplies arguments for.
+    pub fn awaited_params(&self) -> &'a [VarDecl] {
+        if self.takes_implicit_continuation() && self.suspends == false {
+            &self.params[1..]
+        } else {
+            self.params
+        }
+    }
+}
+
+pub struct GlobalSymbol<'a> {
+    pub var: &'a VarDecl,
+    pub external: bool,
+}
+
+#[derive(Clone)]
+pub struct ConstSymbol<'a> {
+    pub type_ref: &'a TypeRef,
+    pub public: bool,
+    /// Filled in once the constant has been evaluated.
+    pub value: Option<ConstValue>,
+}
+
+pub struct SymbolTable<'a> {
+    pub functions: HashMap<&'a str, FnSignature<'a>>,
+    pub imported_functions: HashMap<String, FnSignature<'a>>,
+    pub globals: HashMap<&'a str, GlobalSymbol<'a>>,
+    /// Constants declared in this module, and those imported as `alias::NAME`.
+    pub constants: HashMap<String, ConstSymbol<'a>>,
+    pub interrupts: Vec<&'a str>,
+    pub headers: Vec<&'a str>,
+    /// The modules imported so far, by the alias their items are reached by.
+    aliases: HashMap<String, String>,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn build(module: &'a Module) -> (Self, Vec<Diagnostic>) {
+        let mut table = SymbolTable {
+            functions: HashMap::new(),
+            imported_functions: HashMap::new(),
+            globals: HashMap::new(),
+            constants: HashMap::new(),
+            interrupts: Vec::new(),
+            headers: Vec::new(),
+            aliases: HashMap::new(),
+        };
+        let mut diagnostics = Vec::new();
+
+        let mut declared: Vec<&'a str> = Vec::new();
+        for node in module.nodes.iter() {
+            let name = match node {
+                &TopLevelNode::Import { .. } => continue,
+                &TopLevelNode::GlobalDecl(ref var) => {
+                    table.globals.entry(&var.name).or_insert(GlobalSymbol { var, external: false });
+                    &var.name
+                }
+                &TopLevelNode::ExternGlobalDecl { ref var, ref header } => {
+                    table.add_header(header);
+                    table.globals.entry(&var.name).or_insert(GlobalSymbol { var, external: true });
+                    &var.name
+                }
+                &TopLevelNode::ConstDecl { ref name, ref type_ref, public, .. } => {
+                    let constant = ConstSymbol { type_ref, public, value: None };
+                    table.constants.entry(name.clone()).or_insert(constant);
+                    name
+                }
+                &TopLevelNode::FnDecl { ref name, ref params, ref returns, ref body, async, public, priority, generator } => {
+                    let c_name = module.mangle(name);
+                    let suspends = async == false && continuations::suspended(body).is_some();
+                    let signature = FnSignature {
+                        params, returns, async, external: false, public, priority, generator, suspends, c_name,
+                    };
+                    table.functions.entry(name).or_insert(signature);
+                    name
+                }
+                &TopLevelNode::ExternFnDecl { ref name, ref params, ref returns, async, ref header } => {
+                    table.add_header(header);
+                    let c_name = name.clone();
+                    let signature = FnSignature {
+                        params, returns, async, external: true, public: false, priority: None, generator: false,
+                        suspends: false, c_name,
+                    };
+                    table.functions.entry(name).or_insert(signature);
+                    name
+                }
+                &TopLevelNode::InterruptDecl { ref name, .. } => {
+                    if table.interrupts.contains(&name.as_str()) == false {
+                        table.interrupts.push(name);
+                    }
+                    name
+                }
+            };
+
+            if declared.contains(&name.as_str()) {
+                diagnostics.push(Diagnostic::error(format!("`{}` is declared more than once", name))
+                    .with_help("functions, globals, constants and interrupts share one namespace".into()));
+            } else {
+                declared.push(name);
+            }
+        }
+
+        (table, diagnostics)
+    }
+
+    /// Makes the functions and constants of an imported module available as
+    /// `alias::name`, where the alias is the last segment of the imported
+    /// module's path. A module whose alias is already taken is not imported.
+    pub fn import(&mut self, module: &Module, table: &SymbolTable<'a>) -> Vec<Diagnostic> {
+        let alias = module.path.last().map(|s| s.as_str()).unwrap_or("main");
+        if let Some(other) = self.aliases.get(alias) {
+            return vec![
+                Diagnostic::error(format!("`{}` and `{}` are both imported as `{}`", other, module.name(), alias))
+                    .with_help(format!("the items of an imported module are reached as `{}::name`, so import only one of them", alias)),
+            ];
+        }
+        self.aliases.insert(alias.into(), module.name());
+
+        for (name, signature) in table.functions.iter() {
+            if signature.public && signature.external == false {
+                let qualified = format!("{}::{}", alias, name);
+                self.imported_functions.insert(qualified, signature.clone());
+            }
+        }
+
+        for (name, constant) in table.constants.iter() {
+            if constant.public && name.contains("::") == false {
+                let qualified = format!("{}::{}", alias, name);
+                self.constants.insert(qualified, constant.clone());
+            }
+        }
+
+        vec![]
+    }
+
+    /// Looks up the constant an expression such as `PERIOD` or `timer::PERIOD` refers to.
+    pub fn constant(&self, expr: &Expression) -> Option<&ConstSymbol<'a>> {
+        match expr {
+            &Expression::Identifier(ref name) => self.constants.get(name),
+            &Expression::Path(ref segments) => self.constants.get(&segments.join("::")),
+            _ => None,
+        }
+    }
+
+    /// Looks up the function an expression such as `delay` or `uart::read` refers to.
+    pub fn function(&self, expr: &Expression) -> Option<&FnSignature<'a>> {
+        match expr {
+            &Expression::Identifier(ref name) => self.functions.get(name.as_str()),
+            &Expression::Path(ref segments) => self.imported_functions.get(&segments.join("::")),
+            _ => None,
+        }
+    }
+
+    fn add_header(&mut self, header: &'a Option<String>) {
+        if let &Some(ref header) = header {
+            if self.headers.contains(&header.as_str()) == false {
+                self.headers.push(header);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SymbolTable;
+    use super::super::super::ast::nodes::*;
+    use super::super::super::module::Module;
+    use super::super::super::testing::*;
+
+    #[test]
+    fn names_are_unique_across_functions_and_globals() {
+        let errors = errors(vec![
+            TopLevelNode::GlobalDecl(var("foo", ty("u32"))),
+            plain_fn("foo", vec![], unit(), vec![]),
+        ]);
+        assert!(mentions(&errors, "`foo` is declared more than once"), "{:?}", errors);
+    }
+
+    #[test]
+    fn names_are_unique_across_constants_and_extern_declarations() {
+        let errors = errors(vec![
+            TopLevelNode::ConstDecl { name: "LIMIT".into(), type_ref: ty("u32"), expr: int(4), public: false },
+            TopLevelNode::ExternGlobalDecl { var: var("LIMIT", ty("u32")), header: None },
+        ]);
+        assert!(mentions(&errors, "`LIMIT` is declared more than once"), "{:?}", errors);
+    }
+
+    #[test]
+    fn functions_are_declared_once() {
+        let errors = errors(vec![
+            plain_fn("tick", vec![], unit(), vec![]),
+            extern_fn("tick", vec![], unit(), false),
+        ]);
+        assert_eq!(errors, vec!["`tick` is declared more than once".to_string()]);
+    }
+
+    #[test]
+    fn distinct_names_are_accepted() {
+        let errors = errors(vec![
+            TopLevelNode::GlobalDecl(var("count", ty("u32"))),
+            extern_fn("led_on", vec![], unit(), false),
+            plain_fn("idle", vec![], unit(), vec![call_statement("led_on", vec![])]),
+        ]);
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn modules_imported_under_one_alias_are_reported() {
+        let x = Module { path: vec!["x".into(), "util".into()], nodes: vec![public(plain_fn("f", vec![], unit(), vec![]))] };
+        let y = Module { path: vec!["y".into(), "util".into()], nodes: vec![public(plain_fn("f", vec![], unit(), vec![]))] };
+        let root = Module::root(vec![]);
+        let (x_symbols, _) = SymbolTable::build(&x);
+        let (y_symbols, _) = SymbolTable::build(&y);
+        let (mut symbols, _) = SymbolTable::build(&root);
+
+        assert!(symbols.import(&x, &x_symbols).is_empty());
+        let messages = symbols.import(&y, &y_symbols).into_iter().map(|d| d.message).collect::<Vec<_>>();
+        assert!(mentions(&messages, "`x::util` and `y::util` are both imported as `util`"), "{:?}", messages);
+        assert_eq!(symbols.imported_functions["util::f"].c_name, "x__util__f");
+    }
+
+    #[test]
+    fn only_pub_functions_are_imported() {
+        let util = Module { path: vec!["util".into()], nodes: vec![
+            public(plain_fn("shared", vec![], unit(), vec![])),
+            plain_fn("helper", vec![], unit(), vec![]),
+        ] };
+        let root = Module::root(vec![]);
+        let (util_symbols, _) = SymbolTable::build(&util);
+        let (mut symbols, _) = SymbolTable::build(&root);
+
+        assert!(symbols.import(&util, &util_symbols).is_empty());
+        assert!(symbols.imported_functions.contains_key("util::shared"));
+        assert!(symbols.imported_functions.contains_key("util::helper") == false);
+    }
+}
@@ -0,0 +1,219 @@
+use super::Diagnostic;
+use super::symbols::SymbolTable;
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+
+/// Suspends the calling task for at least the given number of milliseconds.
+pub const SLEEP_MS: &str = "sleep_ms";
+/// Advances the runtime's software timers by one tick.
+pub const TIMER_TICK: &str = "timer_tick";
+/// Awaits a task for at most the given number of milliseconds, cancelling it
+/// when the time is up.
+pub const TIMEOUT: &str = "timeout";
+/// What awaiting `timeout` completes with: `Timeout<T>` for a task returning
+/// `T`, whose `status` is `Ok` or `TimedOut` and whose `value` holds the
+/// task's result if it completed in time.
+pub const TIMEOUT_TYPE: &str = "Timeout";
+
+/// Whether `target` names the built-in `sleep_ms`, which a function of the
+/// program may shadow.
+pub fn is_sleep(target: &Expression, symbols: &SymbolTable) -> bool {
+    is_builtin(target, SLEEP_MS, symbols)
+}
+
+pub fn is_tick(target: &Expression, symbols: &SymbolTable) -> bool {
+    is_builtin(target, TIMER_TICK, symbols)
+}
+
+/// The duration and the task of a call to `timeout`.
+pub fn timeout_call<'e>(expr: &'e Expression, symbols: &SymbolTable) -> Option<(&'e Expression, &'e Expression)> {
+    match expr {
+        &Expression::FnCall { ref target, ref args } if args.len() == 2 && is_builtin(target, TIMEOUT, symbols) => {
+            Some((&args[0], &args[1]))
+        }
+        _ => None,
+    }
+}
+
+/// The result type of a `timeout` around a task returning `returns`.
+pub fn timeout_type(returns: &TypeRef) -> TypeRef {
+    TypeRef::Named { name: TIMEOUT_TYPE.into(), type_params: vec![returns.clone()] }
+}
+
+/// Whether a statement awaits `sleep_ms`.
+pub fn awaits_sleep(statement: &Statement, symbols: &SymbolTable) -> bool {
+    match walk::awaited(statement) {
+        Some(&Expression::FnCall { ref target, .. }) => is_sleep(target, symbols),
+        _ => false,
+    }
+}
+
+/// Checks that only tasks sleep or time out, that only tasks of this program
+/// are given a timeout, and that a single interrupt drives the software
+/// timers.
+pub fn check(nodes: &[TopLevelNode], symbols: &SymbolTable) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut tick_interrupts: Vec<&str> = Vec::new();
+
+    for node in nodes.iter() {
+        let (name, body, async, interrupt) = match node {
+            &TopLevelNode::FnDecl { ref name, ref body, async, .. } => (name, body, async, false),
+            &TopLevelNode::InterruptDecl { ref name, ref body } => (name, body, false, true),
+            _ => continue,
+        };
+
+        let mut awaited = Vec::new();
+        walk::statements(body, &mut |statement| {
+            // the arms of a `select` and the operands of a `join` are awaited
+            // with the rest, each in a slot of its own
+            let children = match (statement, walk::awaited(statement)) {
+                (&Statement::Select(ref arms), _) => arms.iter().map(|arm| &arm.task).collect(),
+                (_, Some(&Expression::Join { ref tasks })) => tasks.iter().collect(),
+                _ => Vec::new(),
+            };
+            for child in children {
+                match child {
+                    &Expression::FnCall { ref target, .. } if is_sleep(target, symbols) => awaited.push(child),
+                    &Expression::FnCall { ref target, .. } if is_builtin(target, TIMEOUT, symbols) => {
+                        awaited.push(child);
+                        diagnostics.push(Diagnostic::error(format!(
+                            "`{}` in `{}` cannot be raced or joined with other tasks", TIMEOUT, name,
+                        )).with_help(format!("race the task against `{}` in another `select` arm instead", SLEEP_MS)));
+                    }
+                    _ => {}
+                }
+            }
+            if awaits_sleep(statement, symbols) {
+                awaited.push(walk::awaited(statement).unwrap());
+                if async == false {
+                    diagnostics.push(Diagnostic::error(format!("`{}` sleeps, but is not async", name))
+                        .with_help("only tasks can sleep; spawn a task that sleeps instead".into()));
+                }
+            }
+            if let Some(expr @ &Expression::FnCall { ref target, .. }) = walk::awaited(statement) {
+                if is_builtin(target, TIMEOUT, symbols) {
+                    awaited.push(expr);
+                    if async == false {
+                        diagnostics.push(Diagnostic::error(format!("`{}` awaits a `{}`, but is not async", name, TIMEOUT)));
+                    }
+                }
+            }
+            if let Some((_, task)) = walk::awaited(statement).and_then(|expr| timeout_call(expr, symbols)) {
+                let callee = match task {
+                    &Expression::FnCall { ref target, .. } => symbols.function(target),
+                    _ => None,
+                };
+                match callee {
+                    Some(callee) if callee.async && callee.external == false => {}
+                    Some(callee) => {
+                        diagnostics.push(Diagnostic::error(format!(
+                            "`{}` in `{}` is given a timeout but cannot be cancelled", callee.c_name, name,
+                        )).with_help("wrap it in an async function, whose task state the timeout can cancel".into()));
+                    }
+                    None => {
+                        diagnostics.push(Diagnostic::error(format!(
+                            "`{}` in `{}` can only be given a call to an async function", TIMEOUT, name,
+                        )));
+                    }
+                }
+            }
+        });
+
+        let mut calls: Vec<(&Expression, usize, bool)> = Vec::new();
+        walk::expressions(body, &mut |expr| {
+            if let &Expression::FnCall { ref target, ref args } = expr {
+                calls.push((&**target, args.len(), awaited.iter().any(|a| ::std::ptr::eq(*a, expr))));
+            }
+        });
+        walk::statements(body, &mut |statement| {
+            if let &Statement::FnCall { ref target, ref args } = statement {
+                calls.push((target, args.len(), false));
+            }
+        });
+
+        for (target, args, is_awaited) in calls {
+            if is_sleep(target, symbols) {
+                if args != 1 {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}` takes 1 argument but {} were supplied in `{}`", SLEEP_MS, args, name,
+                    )));
+                }
+                if is_awaited == false {
+                    diagnostics.push(Diagnostic::error(format!("`{}` in `{}` must be awaited", SLEEP_MS, name)));
+                }
+            } else if is_builtin(target, TIMEOUT, symbols) {
+                if args != 2 {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}` takes 2 arguments but {} were supplied in `{}`", TIMEOUT, args, name,
+                    )).with_help(format!("give the duration in milliseconds and the task, e.g. `{}(100, read())`", TIMEOUT)));
+                }
+                if is_awaited == false {
+                    diagnostics.push(Diagnostic::error(format!("`{}` in `{}` must be awaited", TIMEOUT, name)));
+                }
+            } else if is_tick(target, symbols) {
+                if args != 0 {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}` takes no arguments but {} were supplied in `{}`", TIMER_TICK, args, name,
+                    )));
+                }
+                if interrupt == false {
+                    diagnostics.push(Diagnostic::error(format!("`{}` is called in `{}`, which is not an interrupt", TIMER_TICK, name))
+                        .with_help("call it from the interrupt of the hardware timer that counts ticks".into()));
+                } else if tick_interrupts.contains(&name.as_str()) == false {
+                    tick_interrupts.push(name);
+                }
+            }
+        }
+    }
+
+    if tick_interrupts.len() > 1 {
+        diagnostics.push(Diagnostic::error(format!(
+            "`{}` is called in more than one interrupt: {}", TIMER_TICK, tick_interrupts.join(", "),
+        )).with_help("drive the software timers from a single tick interrupt".into()));
+    }
+
+    diagnostics
+}
+
+fn is_builtin(target: &Expression, builtin: &str, symbols: &SymbolTable) -> bool {
+    match target {
+        &Expression::Identifier(ref name) => name == builtin && symbols.function(target).is_none(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    #[test]
+    fn sleeps_raced_or_joined_are_awaited() {
+        let errors = errors(vec![
+            async_fn("read", vec![], ty("u32"), vec![Statement::Return(int(1))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Select(vec![
+                    SelectArm { binding: None, task: call("read", vec![]), body: vec![] },
+                    SelectArm { binding: None, task: call("sleep_ms", vec![int(100)]), body: vec![] },
+                ]),
+                Statement::Await(Expression::Join { tasks: vec![call("read", vec![]), call("sleep_ms", vec![int(5)])] }),
+            ]),
+        ]);
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn timeouts_cannot_be_raced() {
+        let errors = errors(vec![
+            async_fn("read", vec![], ty("u32"), vec![Statement::Return(int(1))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Select(vec![
+                    SelectArm { binding: None, task: call("read", vec![]), body: vec![] },
+                    SelectArm { binding: None, task: call("timeout", vec![int(100), call("read", vec![])]), body: vec![] },
+                ]),
+            ]),
+        ]);
+        assert!(mentions(&errors, "`timeout` in `main` cannot be raced or joined with other tasks"), "{:?}", errors);
+        assert!(mentions(&errors, "must be awaited") == false, "{:?}", errors);
+    }
+}
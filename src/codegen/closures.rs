@@ -0,0 +1,70 @@
+use std::io::{Error, Write};
+
+use super::ctypes::c_declaration;
+use super::super::ast::nodes::*;
+use super::super::check::closures::{captures, closures};
+use super::super::check::symbols::SymbolTable;
+
+/// A closure expression and the locals of its enclosing function that it
+/// captures. Captured locals are reached through pointers held in a context
+/// struct, which lives in the enclosing task state.
+pub struct Closure<'a> {
+    pub expr: &'a Expression,
+    pub body: &'a [Statement],
+    pub captures: Vec<&'a VarDecl>,
+    pub index: usize,
+    pub c_name: String,
+}
+
+impl<'a> Closure<'a> {
+    pub fn fn_name(&self) -> String {
+        format!("closure_{}", self.c_name)
+    }
+
+    pub fn context_type_name(&self) -> String {
+        format!("Closure_{}", self.c_name)
+    }
+
+    /// The name of this closure's context within its task state.
+    pub fn member_name(&self) -> String {
+        format!("closure{}", self.index)
+    }
+
+    pub fn has_context(&self) -> bool {
+        self.captures.is_empty() == false
+    }
+
+    pub fn captures_local(&self, name: &str) -> bool {
+        self.captures.iter().any(|local| local.name == name)
+    }
+
+    pub fn write_context_typedef(&self, w: &mut Write, symbols: &SymbolTable) -> Result<(), Error> {
+        writeln!(w, "typedef struct _{} {{", self.context_type_name())?;
+        for local in self.captures.iter() {
+            writeln!(w, "    {};", c_declaration(&local.type_ref, &format!("(*{})", local.name), symbols))?;
+        }
+        writeln!(w, "}} {};", self.context_type_name())
+    }
+}
+
+/// Collects the closures of a function, naming them after the function's C name.
+pub fn collect<'a>(owner: &str, locals: &[&'a VarDecl], body: &'a [Statement]) -> Vec<Closure<'a>> {
+    closures(body)
+        .into_iter()
+        .enumerate()
+        .map(|(index, expr)| {
+            let body = match expr {
+                &Expression::Closure { ref body } => &body[..],
+                _ => unreachable!(),
+            };
+
+            Closure {
+                expr,
+                body,
+                captures: captures(body, locals),
+                index,
+                c_name: format!("{}_{}", owner, index),
+            }
+        })
+        .collect()
+}
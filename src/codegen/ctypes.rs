@@ -0,0 +1,115 @@
+use super::super::ast::nodes::*;
+use super::super::check::consteval::array_length;
+use super::super::check::locks::{self, LockKind};
+use super::super::check::timers::TIMEOUT_TYPE;
+use super::super::check::symbols::SymbolTable;
+
+pub fn c_type(type_ref: &TypeRef) -> String {
+    match type_ref {
+        &TypeRef::Named { ref name, .. } => {
+            match name.as_str() {
+                "u8" => "uint8_t",
+                "u16" => "uint16_t",
+                "u32" => "uint32_t",
+                "u64" => "uint64_t",
+                "i8" => "int8_t",
+                "i16" => "int16_t",
+                "i32" => "int32_t",
+                "i64" => "int64_t",
+                "f32" => "float",
+                "f64" => "double",
+                "bool" => "bool",
+                "str" => "const char *",
+                other => other,
+            }.into()
+        }
+        &TypeRef::Semaphore { .. } => "Semaphore".into(),
+        &TypeRef::Tuple { ref type_refs } if type_refs.is_empty() => "void".into(),
+        &TypeRef::Tuple { .. } => unreachable!("tuple types are rejected before code generation: {:?}", type_ref),
+        &TypeRef::Array { .. } | &TypeRef::Channel { .. } => {
+            unreachable!("array and channel types are only accepted in declarations: {:?}", type_ref)
+        }
+    }
+}
+
+/// Formats a declaration of `name` with the given type, e.g. `uint32_t period_ms`
+/// or `uint8_t buffer[16]`. Array lengths and channel capacities must already
+/// have been checked by constant evaluation.
+pub fn c_declaration(type_ref: &TypeRef, name: &str, symbols: &SymbolTable) -> String {
+    if let &TypeRef::Channel { ref element, ref capacity } = type_ref {
+        let capacity = array_length(capacity, symbols)
+            .expect("channel capacities are checked before code generation");
+        return format!("CHANNEL({}, {}) {}", c_type(element), capacity, name);
+    }
+
+    if let (Some(LockKind::Mutex), &TypeRef::Named { ref type_params, .. }) = (locks::lock_kind(type_ref), type_ref) {
+        return format!("MUTEX({}) {}", c_type(&type_params[0]), name);
+    }
+
+    if let &TypeRef::Named { name: ref type_name, ref type_params } = type_ref {
+        if type_name == TIMEOUT_TYPE && type_params.len() == 1 {
+            if is_unit(&type_params[0]) {
+                return format!("TIMEOUT_UNIT {}", name);
+            }
+            return format!("TIMEOUT({}) {}", c_type(&type_params[0]), name);
+        }
+    }
+
+    if let &TypeRef::Array { ref element, ref length } = type_ref {
+        let length = array_length(length, symbols)
+            .expect("array lengths are checked before code generation");
+        return c_declaration(element, &format!("{}[{}]", name, length), symbols);
+    }
+
+    let c_type = c_type(type_ref);
+    if c_type.ends_with('*') {
+        format!("{}{}", c_type, name)
+    } else {
+        format!("{} {}", c_type, name)
+    }
+}
+
+pub fn is_unit(type_ref: &TypeRef) -> bool {
+    match type_ref {
+        &TypeRef::Tuple { ref type_refs } => type_refs.is_empty(),
+        _ => false,
+    }
+}
+
+pub fn string_literal(text: &str) -> String {
+    let mut result = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+pub fn operator(operator: &Operator) -> &'static str {
+    match operator {
+        &Operator::Divide => "/",
+        &Operator::Multiply => "*",
+        &Operator::Add => "+",
+        &Operator::Subtract => "-",
+        &Operator::ShiftLeft => "<<",
+        &Operator::ShiftRight => ">>",
+        &Operator::LessThan => "<",
+        &Operator::LessThanEqual => "<=",
+        &Operator::GreaterThan => ">",
+        &Operator::GreaterThanEqual => ">=",
+        &Operator::Equal => "==",
+        &Operator::NotEqual => "!=",
+        &Operator::BitwiseAnd => "&",
+        &Operator::BitwiseXor => "^",
+        &Operator::BitwiseOr => "|",
+        &Operator::LogicalAnd => "&&",
+        &Operator::LogicalOr => "||",
+    }
+}
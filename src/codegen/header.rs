@@ -0,0 +1,112 @@
+use std::io::{Error, Write};
+
+use super::Generator;
+use super::ctypes::{c_declaration, c_type};
+use super::task_state::TaskState;
+use super::super::ast::nodes::*;
+use super::super::check::continuations::{self, CONTINUATION};
+use super::super::check::symbols::SymbolTable;
+
+impl<'a, 'b> Generator<'a, 'b> {
+    /// Writes the public interface of the module. Every task state is defined
+    /// in full so that C code can allocate them statically with `sizeof`.
+    pub fn write_header(&self, w: &mut Write) -> Result<(), Error> {
+        let guard = format!("ASYNCLANG_{}_H", self.module.file_stem().to_uppercase());
+
+        writeln!(w, "/* generated by asynclang from module `{}`, do not edit */", self.module.name())?;
+        writeln!(w, "#ifndef {}", guard)?;
+        writeln!(w, "#define {}", guard)?;
+        writeln!(w)?;
+        writeln!(w, "#include <stdbool.h>")?;
+        writeln!(w, "#include <stddef.h>")?;
+        writeln!(w, "#include <stdint.h>")?;
+        writeln!(w)?;
+        writeln!(w, "#include \"core.h\"")?;
+        for header in self.imported_header_names() {
+            writeln!(w, "#include \"{}\"", header)?;
+        }
+        writeln!(w)?;
+
+        let mut any_constants = false;
+        for node in self.nodes.iter() {
+            if let &TopLevelNode::ConstDecl { ref name, public: true, .. } = node {
+                if let Some(value) = self.symbols.constants.get(name).and_then(|c| c.value) {
+                    writeln!(w, "#define {} {}", self.module.mangle(name), value.to_c_literal())?;
+                    any_constants = true;
+                }
+            }
+        }
+        if any_constants {
+            writeln!(w)?;
+        }
+
+        if let Some(highest) = self.states.iter().filter_map(|s| s.priority).max() {
+            writeln!(w, "#if PRIORITY_LEVELS <= {}", highest)?;
+            writeln!(w, "#error \"module `{}` needs PRIORITY_LEVELS of at least {}\"", self.module.name(), highest as u32 + 1)?;
+            writeln!(w, "#endif")?;
+            writeln!(w)?;
+        }
+
+        for state in self.states.iter() {
+            state.write_typedef(w, self.symbols)?;
+            writeln!(w)?;
+        }
+
+        for state in self.states.iter().filter(|s| s.public) {
+            writeln!(w, "{};", self.start_signature(state))?;
+        }
+
+        for node in self.nodes.iter() {
+            if let &TopLevelNode::FnDecl { ref name, ref params, ref returns, ref body, async: false, public: true, .. } = node {
+                let params = c_params(params, body);
+                writeln!(w, "{};", fn_signature(&self.module.mangle(name), &params, returns, self.symbols))?;
+            }
+        }
+
+        writeln!(w)?;
+        writeln!(w, "#endif")
+    }
+
+    pub fn start_fn_name(&self, task: &str) -> String {
+        format!("{}_start", task)
+    }
+
+    pub fn start_signature(&self, state: &TaskState) -> String {
+        let mut result = format!(
+            "void {}({} *state, Continuation on_done",
+            self.start_fn_name(&state.c_name), state.type_name(),
+        );
+        for param in state.params.iter() {
+            result += &format!(", {}", c_declaration(&param.type_ref, &param.name, self.symbols));
+        }
+        result += ")";
+        result
+    }
+}
+
+/// The parameters of a plain function in C, which start with the
+/// continuation it hands out if it suspends.
+pub fn c_params(params: &[VarDecl], body: &[Statement]) -> Vec<VarDecl> {
+    let mut result = Vec::new();
+    if let Some(continuation) = continuations::suspended(body) {
+        result.push(VarDecl {
+            name: continuation.into(),
+            type_ref: TypeRef::Named { name: CONTINUATION.into(), type_params: vec![] },
+        });
+    }
+    result.extend(params.iter().cloned());
+    result
+}
+
+pub fn fn_signature(name: &str, params: &[VarDecl], returns: &TypeRef, symbols: &SymbolTable) -> String {
+    let params = if params.is_empty() {
+        "void".into()
+    } else {
+        params.iter()
+            .map(|p| c_declaration(&p.type_ref, &p.name, symbols))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    format!("{} {}({})", c_type(returns), name, params)
+}
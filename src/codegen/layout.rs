@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use super::super::ast::nodes::*;
+use super::super::check::consteval::array_length;
+use super::super::check::continuations::CONTINUATION;
+use super::super::check::locks::{self, LockKind};
+use super::super::check::timers::TIMEOUT_TYPE;
+use super::super::check::symbols::SymbolTable;
+use super::super::options::Options;
+use super::ctypes::is_unit;
+
+/// The size of `int`, `unsigned` and enums. Targets with 16 bit ints are
+/// overestimated.
+const INT_SIZE: usize = 4;
+
+/// The size and alignment of a C type on the target, in bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Layout {
+    pub size: usize,
+    pub align: usize,
+}
+
+impl Layout {
+    pub fn empty() -> Layout {
+        Layout { size: 0, align: 1 }
+    }
+
+    pub fn array(self, length: usize) -> Layout {
+        Layout { size: self.size * length, align: self.align }
+    }
+
+    /// The layout of a struct of the given members in order, padded so that
+    /// each member and the struct as a whole are aligned.
+    pub fn structure(members: &[Layout]) -> Layout {
+        let align = members.iter().map(|m| m.align).max().unwrap_or(1);
+        let size = members.iter().fold(0, |offset, m| align_to(offset, m.align) + m.size);
+        Layout { size: align_to(size, align), align }
+    }
+
+    pub fn union(members: &[Layout]) -> Layout {
+        let align = members.iter().map(|m| m.align).max().unwrap_or(1);
+        let size = members.iter().map(|m| m.size).max().unwrap_or(0);
+        Layout { size: align_to(size, align), align }
+    }
+}
+
+/// Computes layouts for the target described by the options, and remembers
+/// those of the task states computed so far, which later states embed.
+pub struct Layouts<'o> {
+    options: &'o Options,
+    states: HashMap<String, Layout>,
+    /// The types whose layout is not known, such as those of C headers,
+    /// which are counted as empty.
+    pub unknown: Vec<String>,
+}
+
+impl<'o> Layouts<'o> {
+    pub fn new(options: &'o Options) -> Self {
+        Layouts { options, states: HashMap::new(), unknown: Vec::new() }
+    }
+
+    /// A type whose alignment is its size, up to the target's largest.
+    pub fn scalar(&self, size: usize) -> Layout {
+        Layout { size, align: size.min(self.options.max_align).max(1) }
+    }
+
+    pub fn pointer(&self) -> Layout {
+        self.scalar(self.options.pointer_size)
+    }
+
+    pub fn int(&self) -> Layout {
+        self.scalar(INT_SIZE)
+    }
+
+    pub fn continuation(&self) -> Layout {
+        Layout::structure(&[self.pointer(), self.pointer(), self.scalar(1)])
+    }
+
+    pub fn task_state_core(&self) -> Layout {
+        Layout::structure(&[self.continuation(), self.pointer(), self.scalar(1), self.scalar(1)])
+    }
+
+    pub fn waiter(&self) -> Layout {
+        Layout::structure(&[self.continuation(), self.pointer()])
+    }
+
+    pub fn channel_waiter(&self) -> Layout {
+        Layout::structure(&[self.waiter(), self.pointer()])
+    }
+
+    pub fn timer(&self) -> Layout {
+        Layout::structure(&[self.continuation(), self.scalar(4), self.pointer()])
+    }
+
+    /// The layout of a task state computed earlier, by the C name of its
+    /// function.
+    pub fn task_state(&mut self, c_name: &str) -> Layout {
+        match self.states.get(c_name) {
+            Some(&layout) => layout,
+            None => self.unknown(format!("TaskState_{}", c_name)),
+        }
+    }
+
+    pub fn add_task_state(&mut self, c_name: &str, layout: Layout) {
+        self.states.insert(c_name.into(), layout);
+    }
+
+    /// The layout of a type as `c_declaration` declares it.
+    pub fn type_layout(&mut self, type_ref: &TypeRef, symbols: &SymbolTable) -> Layout {
+        match type_ref {
+            &TypeRef::Channel { ref element, ref capacity } => {
+                let capacity = array_length(capacity, symbols)
+                    .expect("channel capacities are checked before code generation");
+                let core = Layout::structure(&[self.int(), self.int(), self.pointer(), self.pointer()]);
+                let buffer = self.type_layout(element, symbols).array(capacity as usize);
+                Layout::structure(&[core, buffer])
+            }
+            &TypeRef::Array { ref element, ref length } => {
+                let length = array_length(length, symbols)
+                    .expect("array lengths are checked before code generation");
+                self.type_layout(element, symbols).array(length as usize)
+            }
+            &TypeRef::Semaphore { .. } => Layout::structure(&[self.int(), self.pointer()]),
+            &TypeRef::Tuple { .. } => Layout::empty(),
+            &TypeRef::Named { ref name, ref type_params } => {
+                match locks::lock_kind(type_ref) {
+                    Some(LockKind::Mutex) => {
+                        let core = Layout::structure(&[self.scalar(1), self.pointer()]);
+                        return Layout::structure(&[core, self.type_layout(&type_params[0], symbols)]);
+                    }
+                    Some(LockKind::Semaphore) => return Layout::structure(&[self.int(), self.pointer()]),
+                    None => {}
+                }
+                if name == TIMEOUT_TYPE && type_params.len() == 1 {
+                    if is_unit(&type_params[0]) {
+                        return Layout::structure(&[self.int()]);
+                    }
+                    return Layout::structure(&[self.int(), self.type_layout(&type_params[0], symbols)]);
+                }
+                if name == CONTINUATION {
+                    return self.continuation();
+                }
+
+                match name.as_str() {
+                    "u8" | "i8" | "bool" => self.scalar(1),
+                    "u16" | "i16" => self.scalar(2),
+                    "u32" | "i32" | "f32" => self.scalar(4),
+                    "u64" | "i64" | "f64" => self.scalar(8),
+                    "str" => self.pointer(),
+                    other => self.unknown(other.into()),
+                }
+            }
+        }
+    }
+
+    fn unknown(&mut self, name: String) -> Layout {
+        if self.unknown.contains(&name) == false {
+            self.unknown.push(name);
+        }
+        Layout::empty()
+    }
+}
+
+fn align_to(offset: usize, align: usize) -> usize {
+    (offset + align - 1) / align * align
+}
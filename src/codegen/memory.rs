@@ -0,0 +1,93 @@
+use std::io::{Error, Write};
+
+use super::Generator;
+use super::layout::{Layout, Layouts};
+use super::super::ast::nodes::TopLevelNode;
+
+impl<'a, 'b> Generator<'a, 'b> {
+    /// Lays out every task state of the module, adding them to `layouts`, and
+    /// writes the size of each along with the RAM the module takes up: its
+    /// globals, including the buffers of channels, one state for each task
+    /// started from C, which C must give storage of its own, and the task
+    /// pools. Awaited tasks live inside the state of their caller. The locals
+    /// that share storage within each task state are counted as the bytes
+    /// saved by overlaying them.
+    ///
+    /// Returns the bytes of RAM the module takes up.
+    pub fn write_memory_report(&self, w: &mut Write, layouts: &mut Layouts) -> Result<usize, Error> {
+        writeln!(w, "memory of module `{}`:", self.module.name())?;
+
+        let mut saved = 0;
+        let mut started = 0;
+        for state in self.states.iter() {
+            let layout = state.layout(layouts, self.symbols);
+            layouts.add_task_state(&state.c_name, layout);
+            writeln!(w, "    {:<32} {:>6} bytes", state.type_name(), layout.size)?;
+            let separate = state.locals_layout(layouts, self.symbols, false).size;
+            saved += separate.saturating_sub(state.locals_layout(layouts, self.symbols, true).size);
+            if state.public {
+                started += layout.size;
+            }
+        }
+
+        let mut pools = 0;
+        for pool in self.pools.iter() {
+            let tasks = layouts.task_state(&pool.c_name).array(pool.size());
+            let used = layouts.scalar(1).array(pool.size());
+            let size = Layout::structure(&[tasks, used]).size;
+            writeln!(w, "    {:<32} {:>6} bytes, {} x {}", pool.var_name(), size, pool.size(), pool.type_name())?;
+            pools += size;
+        }
+
+        let globals = self.nodes.iter()
+            .filter_map(|node| match node {
+                &TopLevelNode::GlobalDecl(ref var) => Some(layouts.type_layout(&var.type_ref, self.symbols)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let globals = Layout::structure(&globals).size;
+
+        writeln!(w, "    {:<32} {:>6} bytes", "globals", globals)?;
+        writeln!(w, "    {:<32} {:>6} bytes", "tasks started from C", started)?;
+        writeln!(w, "    {:<32} {:>6} bytes", "task pools", pools)?;
+        writeln!(w, "    {:<32} {:>6} bytes saved by overlay", "locals", saved)?;
+
+        Ok(globals + started + pools)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::options::Options;
+    use super::super::super::testing::*;
+
+    fn program() -> Vec<TopLevelNode> {
+        vec![
+            TopLevelNode::GlobalDecl(var("count", ty("u32"))),
+            TopLevelNode::GlobalDecl(var("bytes", TypeRef::Channel { element: Box::new(ty("u8")), capacity: Box::new(int(16)) })),
+            public(async_fn("main", vec![], unit(), vec![Statement::Await(method_call("bytes", "send", vec![int(1)]))])),
+        ]
+    }
+
+    #[test]
+    fn globals_channel_buffers_and_tasks_started_from_c_count_towards_ram() {
+        let output = compile(program());
+        // `count`, then the channel's two counts and two pointers and its buffer
+        assert!(output.memory_report.contains("    globals                              36 bytes\n"), "{}", output.memory_report);
+        assert!(output.memory_report.contains("    tasks started from C                 44 bytes\n"), "{}", output.memory_report);
+        assert_eq!(output.ram, 36 + 44);
+    }
+
+    #[test]
+    fn programs_over_the_ram_budget_are_rejected() {
+        let output = compile(program());
+        let mut options = Options::default();
+        options.ram_budget = Some(output.ram);
+        assert!(options.check_ram(output.ram).is_none());
+
+        options.ram_budget = Some(output.ram - 1);
+        let message = options.check_ram(output.ram).map(|d| d.message);
+        assert_eq!(message, Some(format!("the program needs {} bytes of RAM, over the budget of {}", output.ram, output.ram - 1)));
+    }
+}
@@ -0,0 +1,70 @@
+
+pub mod ctypes;
+pub mod closures;
+pub mod task_state;
+pub mod task_pool;
+pub mod layout;
+pub mod memory;
+pub mod header;
+pub mod source;
+
+use self::closures::Closure;
+use self::task_pool::TaskPool;
+use self::task_state::TaskState;
+use super::ast::nodes::TopLevelNode;
+use super::ast::walk;
+use super::check::symbols::SymbolTable;
+use super::module::{self, Module};
+use super::options::Options;
+
+/// Emits a C header and source file for a single asynclang module.
+pub struct Generator<'a, 'b: 'a> {
+    module: &'b Module,
+    nodes: &'b [TopLevelNode],
+    symbols: &'a SymbolTable<'b>,
+    options: &'a Options,
+    states: Vec<TaskState<'b>>,
+    /// Closures created by plain functions and interrupt handlers, which
+    /// cannot capture anything.
+    plain_closures: Vec<Closure<'b>>,
+    pools: Vec<TaskPool<'b>>,
+}
+
+impl<'a, 'b> Generator<'a, 'b> {
+    pub fn new(module: &'b Module, symbols: &'a SymbolTable<'b>, options: &'a Options) -> Self {
+        let mut plain_closures = Vec::new();
+        for node in module.nodes.iter() {
+            let (c_name, body) = match node {
+                &TopLevelNode::FnDecl { ref name, ref body, async: false, .. } => (module.mangle(name), body),
+                &TopLevelNode::InterruptDecl { ref name, ref body } => (name.clone(), body),
+                _ => continue,
+            };
+            plain_closures.extend(closures::collect(&c_name, &walk::let_bindings(body), body));
+        }
+
+        Generator {
+            module,
+            nodes: &module.nodes,
+            symbols,
+            options,
+            states: task_state::collect(module, symbols),
+            plain_closures,
+            pools: task_pool::collect(module, symbols),
+        }
+    }
+
+    pub fn header_name(&self) -> String {
+        format!("{}.h", self.module.file_stem())
+    }
+
+    pub fn source_name(&self) -> String {
+        format!("{}.c", self.module.file_stem())
+    }
+
+    fn imported_header_names(&self) -> Vec<String> {
+        self.module.imports()
+            .into_iter()
+            .map(|path| format!("{}.h", module::file_stem(path)))
+            .collect()
+    }
+}
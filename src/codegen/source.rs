@@ -0,0 +1,1633 @@
+use petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction};
+
+use std::io::{Error, Write};
+
+use super::Generator;
+use super::closures::Closure;
+use super::ctypes::{c_declaration, c_type, is_unit, operator, string_literal};
+use super::header::{c_params, fn_signature};
+use super::task_pool::TaskPool;
+use super::task_state::{Boxed, Child, Join, Select, Stream, TaskState, Timeout, TIMER_MEMBER};
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+use super::super::check::channels::{self, ChannelCall};
+use super::super::check::locks::{self, LockCall, LockKind};
+use super::super::check::timers;
+use super::super::check::interrupts;
+use super::super::check::consteval::{array_length, ConstValue};
+use super::super::cfg::{builder::Builder, cfg::ControlFlowGraph, graph::Edge};
+use super::super::options::Dispatch;
+
+impl<'a, 'b> Generator<'a, 'b> {
+    /// Writes the implementation of the module. Each block of an async
+    /// function's control flow graph becomes a C function taking the task
+    /// state, and suspension points hand a `Continuation` to the next block
+    /// over to the awaited callee.
+    pub fn write_source(&self, w: &mut Write) -> Result<(), Error> {
+        writeln!(w, "/* generated by asynclang from module `{}`, do not edit */", self.module.name())?;
+        writeln!(w, "#include \"{}\"", self.header_name())?;
+        for header in self.symbols.headers.iter() {
+            writeln!(w, "#include \"{}\"", header)?;
+        }
+        writeln!(w)?;
+
+        self.write_extern_declarations(w)?;
+        self.write_globals(w)?;
+        self.write_pools(w)?;
+
+        let cfgs = self.states.iter()
+            .map(|state| {
+                let mut cfg = Builder::new(state.name).build(state.body);
+                cfg.tidy_graph();
+                cfg
+            })
+            .collect::<Vec<_>>();
+
+        for node in self.nodes.iter() {
+            if let &TopLevelNode::FnDecl { ref name, ref params, ref returns, ref body, async: false, public: false, .. } = node {
+                let params = c_params(params, body);
+                writeln!(w, "{};", fn_signature(&self.module.mangle(name), &params, returns, self.symbols))?;
+            }
+        }
+        for (state, cfg) in self.states.iter().zip(cfgs.iter()) {
+            if state.public == false {
+                writeln!(w, "static {};", self.start_signature(state))?;
+            }
+            for idx in cfg.graph.node_indices() {
+                writeln!(w, "static void {}({} *this);", block_fn_name(state, idx), state.type_name())?;
+            }
+            for join in state.joins.iter() {
+                writeln!(w, "static void {}({} *this);", join_done_fn_name(state, join), state.type_name())?;
+            }
+            for select in state.selects.iter() {
+                for arm in 0..select.arms.len() {
+                    writeln!(w, "static void {}({} *this);", select_arm_fn_name(state, select, arm), state.type_name())?;
+                }
+            }
+            for member in cancel_members(state) {
+                writeln!(w, "static void {}({} *this);", cancel_fn_name(state, member.as_ref().map(|m| m.as_str())), state.type_name())?;
+            }
+            for index in 0..state.defers.len() {
+                writeln!(w, "static void {}({} *this);", defer_fn_name(state, index), state.type_name())?;
+            }
+            for stream in state.streams.iter() {
+                writeln!(w, "static void {}({} *this);", stream_next_fn_name(state, stream), state.type_name())?;
+            }
+            for timeout in state.timeouts.iter() {
+                for outcome in TIMEOUT_OUTCOMES.iter() {
+                    writeln!(w, "static void {}({} *this);", timeout_fn_name(state, timeout, outcome), state.type_name())?;
+                }
+            }
+        }
+        for closure in self.all_closures() {
+            writeln!(w, "static {};", closure_signature(closure))?;
+        }
+        writeln!(w)?;
+
+        for closure in self.all_closures() {
+            writeln!(w, "static {} {{", closure_signature(closure))?;
+            for statement in closure.body.iter() {
+                self.write_statement(w, Scope::Closure(closure), statement, 1)?;
+            }
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        let deferred = interrupts::deferred_functions(self.nodes, self.options);
+        for node in self.nodes.iter() {
+            match node {
+                &TopLevelNode::FnDecl { ref name, ref params, ref returns, ref body, async: false, .. } => {
+                    let params = c_params(params, body);
+                    let dispatch = if deferred.contains(&name.as_str()) { Dispatch::Deferred } else { Dispatch::Direct };
+                    writeln!(w, "{} {{", fn_signature(&self.module.mangle(name), &params, returns, self.symbols))?;
+                    for statement in body.iter() {
+                        self.write_statement(w, Scope::Function(dispatch), statement, 1)?;
+                    }
+                    writeln!(w, "}}")?;
+                    writeln!(w)?;
+                }
+                &TopLevelNode::InterruptDecl { ref name, ref body } => {
+                    writeln!(w, "INTERRUPT({}) {{", name)?;
+                    for statement in body.iter() {
+                        self.write_statement(w, Scope::Interrupt(self.options.dispatch(name)), statement, 1)?;
+                    }
+                    writeln!(w, "}}")?;
+                    writeln!(w)?;
+                }
+                _ => {}
+            }
+        }
+
+        for (state, cfg) in self.states.iter().zip(cfgs.iter()) {
+            self.write_task(w, state, cfg)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_extern_declarations(&self, w: &mut Write) -> Result<(), Error> {
+        let mut any = false;
+
+        for node in self.nodes.iter() {
+            match node {
+                &TopLevelNode::ExternFnDecl { ref name, ref params, ref returns, async, header: None } => {
+                    if async {
+                        let mut signature = format!("void {}(Continuation on_done", name);
+                        for param in params.iter() {
+                            signature += &format!(", {}", c_declaration(&param.type_ref, &param.name, self.symbols));
+                        }
+                        writeln!(w, "{});", signature)?;
+                    } else {
+                        writeln!(w, "{};", fn_signature(name, params, returns, self.symbols))?;
+                    }
+                    any = true;
+                }
+                &TopLevelNode::ExternGlobalDecl { ref var, header: None } => {
+                    writeln!(w, "extern {};", c_declaration(&var.type_ref, &var.name, self.symbols))?;
+                    any = true;
+                }
+                _ => {}
+            }
+        }
+
+        if any {
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    fn write_globals(&self, w: &mut Write) -> Result<(), Error> {
+        let globals = self.nodes.iter()
+            .filter_map(|node| match node {
+                &TopLevelNode::GlobalDecl(ref var) => Some(var),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if globals.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(w, "static struct {{")?;
+        for var in globals.iter() {
+            writeln!(w, "    {};", c_declaration(&var.type_ref, &var.name, self.symbols))?;
+        }
+
+        // semaphores that start with a count are the only globals that are
+        // not zeroed
+        let counts = globals.iter()
+            .filter_map(|var| match var.type_ref {
+                TypeRef::Semaphore { ref initial } => Some((var, array_length(initial, self.symbols)
+                    .expect("semaphore counts are checked before code generation"))),
+                _ => None,
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect::<Vec<_>>();
+        if counts.is_empty() {
+            writeln!(w, "}} globals;")?;
+        } else {
+            writeln!(w, "}} globals = {{")?;
+            for (var, count) in counts {
+                writeln!(w, "    .{}.count = {},", var.name, count)?;
+            }
+            writeln!(w, "}};")?;
+        }
+        writeln!(w)
+    }
+
+    /// Writes the task pools of the module along with the functions that
+    /// claim and release their slots.
+    fn write_pools(&self, w: &mut Write) -> Result<(), Error> {
+        for pool in self.pools.iter() {
+            writeln!(w, "static struct {{")?;
+            writeln!(w, "    {} tasks[{}];", pool.type_name(), pool.size())?;
+            writeln!(w, "    bool used[{}];", pool.size())?;
+            writeln!(w, "}} {};", pool.var_name())?;
+            writeln!(w)?;
+
+            writeln!(w, "static void {}({} *task) {{", pool.release_fn_name(), pool.type_name())?;
+            writeln!(w, "    CRITICAL_ENTER();")?;
+            writeln!(w, "    {}.used[task - {}.tasks] = false;", pool.var_name(), pool.var_name())?;
+            writeln!(w, "    CRITICAL_EXIT();")?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+
+            if pool.dynamic > 0 {
+                writeln!(w, "static {} *{}(void) {{", pool.type_name(), pool.acquire_fn_name())?;
+                writeln!(w, "    CRITICAL_ENTER();")?;
+                writeln!(w, "    for (size_t i = {}; i < {}; i++) {{", pool.fixed.len(), pool.size())?;
+                writeln!(w, "        if (!{}.used[i]) {{", pool.var_name())?;
+                writeln!(w, "            {}.used[i] = true;", pool.var_name())?;
+                writeln!(w, "            CRITICAL_EXIT();")?;
+                writeln!(w, "            return &{}.tasks[i];", pool.var_name())?;
+                writeln!(w, "        }}")?;
+                writeln!(w, "    }}")?;
+                writeln!(w, "    CRITICAL_EXIT();")?;
+                writeln!(w, "    return NULL;")?;
+                writeln!(w, "}}")?;
+                writeln!(w)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts a task in its pool. The continuation it completes into releases
+    /// its slot, and carries the priority the task inherits unless its
+    /// function declares one.
+    fn write_spawn(
+        &self, w: &mut Write, scope: Scope, task: &Expression, priority: Option<u8>, statement: &Statement, prefix: &str,
+    ) -> Result<(), Error> {
+        let (target, args) = match task {
+            &Expression::FnCall { ref target, ref args } => (target, args),
+            _ => unreachable!("only calls can be spawned"),
+        };
+        let callee = self.symbols.function(target).expect("calls are checked before code generation");
+        let pool = self.pool(&callee.c_name);
+        let args = args.iter()
+            .map(|arg| format!(", {}", self.expression(scope, arg)))
+            .collect::<String>();
+        let start = self.start_fn_name(&callee.c_name);
+        let priority = priority.or(callee.priority).unwrap_or(DEFAULT_PRIORITY);
+
+        match pool.fixed_slot(statement) {
+            Some(slot) => {
+                let task = format!("&{}.tasks[{}]", pool.var_name(), slot);
+                writeln!(w, "{}{}.used[{}] = true;", prefix, pool.var_name(), slot)?;
+                writeln!(
+                    w, "{}{}({}, ((Continuation){{ (TaskFn){}, {}, {} }}){});",
+                    prefix, start, task, pool.release_fn_name(), task, priority, args,
+                )
+            }
+            None => {
+                writeln!(w, "{}{{", prefix)?;
+                writeln!(w, "{}    {} *spawned = {}();", prefix, pool.type_name(), pool.acquire_fn_name())?;
+                writeln!(w, "{}    if (spawned != NULL) {{", prefix)?;
+                writeln!(
+                    w, "{}        {}(spawned, ((Continuation){{ (TaskFn){}, spawned, {} }}){});",
+                    prefix, start, pool.release_fn_name(), priority, args,
+                )?;
+                writeln!(w, "{}    }} else {{", prefix)?;
+                writeln!(w, "{}        spawn_failed({});", prefix, string_literal(&pool.c_name))?;
+                writeln!(w, "{}    }}", prefix)?;
+                writeln!(w, "{}}}", prefix)
+            }
+        }
+    }
+
+    fn write_task(&self, w: &mut Write, state: &TaskState, cfg: &ControlFlowGraph) -> Result<(), Error> {
+        if state.public == false {
+            write!(w, "static ")?;
+        }
+        writeln!(w, "{} {{", self.start_signature(state))?;
+        writeln!(w, "    state->core.continuesWith = on_done;")?;
+        match state.priority {
+            Some(priority) => writeln!(w, "    state->core.priority = {};", priority)?,
+            None => writeln!(w, "    state->core.priority = on_done.priority;")?,
+        }
+        writeln!(w, "    state->core.cancel = (TaskFn){};", cancel_fn_name(state, None))?;
+        writeln!(w, "    state->core.cancelled = false;")?;
+        if state.defers.is_empty() == false {
+            writeln!(w, "    state->deferred = 0;")?;
+        }
+        if state.generator {
+            writeln!(w, "    state->finished = false;")?;
+        }
+        for stream in state.streams.iter() {
+            writeln!(w, "    state->streams.{}.core.cancel = NULL;", stream.member_name())?;
+        }
+        for param in state.params.iter().filter(|param| state.has_local(&param.name)) {
+            writeln!(w, "    state->locals.{} = {};", param.name, param.name)?;
+        }
+        for closure in state.closures.iter() {
+            for local in closure.captures.iter() {
+                writeln!(
+                    w, "    state->closures.{}.{} = &state->locals.{};",
+                    closure.member_name(), local.name, local.name,
+                )?;
+            }
+        }
+        writeln!(w, "    {}(state);", block_fn_name(state, cfg.entry_node))?;
+        writeln!(w, "}}")?;
+        writeln!(w)?;
+
+        self.write_cancellation(w, state)?;
+
+        // each pass of a loop after the first is resumed through the ready
+        // queue, so that a loop whose awaits complete on the spot, or that
+        // never awaits, does not recurse
+        let back_edges = cfg.back_edges();
+        for idx in cfg.graph.node_indices() {
+            writeln!(w, "static void {}({} *this) {{", block_fn_name(state, idx), state.type_name())?;
+
+            let resumed = cfg.graph.edges_directed(idx, Direction::Incoming)
+                .any(|edge| *edge.weight() == Edge::Await || *edge.weight() == Edge::Yield)
+                || back_edges.iter().any(|&(_, to)| to == idx);
+            if resumed {
+                write_cancelled_guard(w)?;
+            }
+            if let Some((var, callee)) = self.awaited_result(cfg, idx) {
+                writeln!(w, "    this->locals.{} = this->nested_tasks.{}.result;", var.name, callee)?;
+            }
+            if let Some((boxed, binding)) = self.awaited_box(state, cfg, idx) {
+                let member = format!("this->nested_tasks.{}", boxed.member_name());
+                if let Some(var) = binding {
+                    writeln!(w, "    this->locals.{} = {}->result;", var.name, member)?;
+                }
+                writeln!(w, "    {}({});", self.pool(&boxed.task).release_fn_name(), member)?;
+            }
+            let mut completed_join = None;
+            let mut started_select = None;
+            let mut started_timeout = None;
+            let mut started_stream = None;
+
+            let statements = &cfg.graph[idx].statements;
+            let exits = cfg.graph.edges(idx)
+                .map(|edge| (edge.target(), *edge.weight()))
+                .collect::<Vec<_>>();
+
+            let select = statements.last().and_then(|statement| state.select(statement));
+
+            match exits.len() {
+                _ if select.is_some() => {
+                    let select = select.unwrap();
+                    for statement in statements[..statements.len() - 1].iter() {
+                        self.write_statement(w, Scope::Task(state), statement, 1)?;
+                    }
+                    self.write_select(w, state, select)?;
+
+                    let mut arm_blocks = exits.iter()
+                        .filter_map(|&(target, kind)| match kind {
+                            Edge::SelectArm(arm) => {
+                                Some((arm, block_fn_name(state, target), back_edges.contains(&(idx, target))))
+                            }
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    arm_blocks.sort();
+                    started_select = Some((select, arm_blocks));
+                }
+                _ if exits.iter().any(|&(_, kind)| kind == Edge::StreamItem) => {
+                    let target = |wanted| exits.iter()
+                        .find(|&&(_, kind)| kind == wanted)
+                        .map(|&(target, _)| target)
+                        .expect("`for await` loops always both take an item and end");
+                    let (item_block, end_block) = (target(Edge::StreamItem), target(Edge::StreamEnd));
+
+                    match statements.last().and_then(|statement| state.stream(statement)) {
+                        Some(stream) => {
+                            for statement in statements[..statements.len() - 1].iter() {
+                                self.write_statement(w, Scope::Task(state), statement, 1)?;
+                            }
+                            self.write_stream_start(w, state, stream)?;
+                            started_stream = Some((stream, item_block, end_block));
+                        }
+                        None => {
+                            for statement in statements.iter() {
+                                self.write_statement(w, Scope::Task(state), statement, 1)?;
+                            }
+                            let stream = cfg.graph.edges_directed(item_block, Direction::Incoming)
+                                .filter(|edge| *edge.weight() == Edge::StreamItem)
+                                .filter_map(|edge| cfg.graph[edge.source()].statements.last())
+                                .filter_map(|statement| state.stream(statement))
+                                .next()
+                                .expect("the body of a `for await` loop is entered from the loop");
+                            writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, None))?;
+                            writeln!(w, "    Continuation_schedule(this->streams.{}.next);", stream.member_name())?;
+                        }
+                    }
+                }
+                0 => {
+                    for statement in statements.iter() {
+                        self.write_statement(w, Scope::Task(state), statement, 1)?;
+                    }
+                    writeln!(w, "    this->core.cancel = NULL;")?;
+                    for index in (0..state.defers.len()).rev() {
+                        writeln!(w, "    {}(this);", defer_fn_name(state, index))?;
+                    }
+                    if state.generator {
+                        writeln!(w, "    this->finished = true;")?;
+                    }
+                    writeln!(w, "    Continuation_invoke(this->core.continuesWith);")?;
+                }
+                1 => {
+                    let (target, kind) = exits[0];
+                    let next_block = block_fn_name(state, target);
+                    let awaited = statements.split_last()
+                        .and_then(|(last, rest)| walk::awaited(last).map(|expr| (*last, expr, rest)));
+                    match (kind, awaited) {
+                        (Edge::Await, Some((last, expr, rest))) => {
+                            for statement in rest.iter() {
+                                self.write_statement(w, Scope::Task(state), statement, 1)?;
+                            }
+                            let binding = match last {
+                                &Statement::LetAwait { ref var, .. } => Some(var),
+                                _ => None,
+                            };
+                            if let Some(join) = state.join(expr) {
+                                self.write_join(w, state, join, expr)?;
+                                completed_join = Some((join, next_block));
+                            } else if let Some(timeout) = state.timeout(expr) {
+                                self.write_timeout(w, state, timeout)?;
+                                started_timeout = Some((timeout, binding, next_block));
+                            } else if let Some(boxed) = state.boxed(expr) {
+                                self.write_boxed_await(w, state, boxed, &next_block)?;
+                            } else {
+                                self.write_await(w, state, expr, binding, &next_block)?;
+                            }
+                        }
+                        (Edge::Await, None) => {
+                            let (last, rest) = statements.split_last().expect("a `suspend` ends its block");
+                            let (continuation, body) = match *last {
+                                &Statement::Suspend { ref continuation, ref body } => (continuation, body),
+                                _ => unreachable!(),
+                            };
+                            for statement in rest.iter() {
+                                self.write_statement(w, Scope::Task(state), statement, 1)?;
+                            }
+                            writeln!(w, "    Continuation {};", continuation)?;
+                            writeln!(w, "    Continuation_init(&{}, (TaskFn){}, this, this->core.priority);", continuation, next_block)?;
+                            writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, None))?;
+                            for statement in body.iter() {
+                                self.write_statement(w, Scope::Task(state), statement, 1)?;
+                            }
+                        }
+                        (Edge::Yield, _) => {
+                            let (last, rest) = statements.split_last().expect("a `yield` ends its block");
+                            let value = match *last {
+                                &Statement::Yield(ref value) => value,
+                                _ => unreachable!(),
+                            };
+                            for statement in rest.iter() {
+                                self.write_statement(w, Scope::Task(state), statement, 1)?;
+                            }
+                            writeln!(w, "    this->item = {};", self.expression(Scope::Task(state), value))?;
+                            writeln!(w, "    Continuation_init(&this->next, (TaskFn){}, this, this->core.priority);", next_block)?;
+                            writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, None))?;
+                            writeln!(w, "    Continuation_invoke(this->core.continuesWith);")?;
+                        }
+                        _ => {
+                            for statement in statements.iter() {
+                                self.write_statement(w, Scope::Task(state), statement, 1)?;
+                            }
+                            write_jump(w, state, &next_block, back_edges.contains(&(idx, target)))?;
+                        }
+                    }
+                }
+                _ => panic!("conditional control flow cannot be lowered to C yet: {:?}", cfg.graph[idx]),
+            }
+
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+
+            if let Some((join, next_block)) = completed_join {
+                writeln!(w, "static void {}({} *this) {{", join_done_fn_name(state, join), state.type_name())?;
+                write_cancelled_guard(w)?;
+                writeln!(w, "    if (--this->nested_tasks.{}.pending == 0) {{", join.member_name())?;
+                writeln!(w, "        {}(this);", next_block)?;
+                writeln!(w, "    }}")?;
+                writeln!(w, "}}")?;
+                writeln!(w)?;
+            }
+
+            if let Some((stream, item_block, end_block)) = started_stream {
+                self.write_stream_next(w, state, stream, item_block, end_block)?;
+            }
+
+            if let Some((timeout, binding, next_block)) = started_timeout {
+                self.write_timeout_outcomes(w, state, timeout, binding, &next_block)?;
+            }
+
+            if let Some((select, arm_blocks)) = started_select {
+                for (arm, next_block, back) in arm_blocks {
+                    self.write_select_arm(w, state, select, arm, &next_block, back)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The local a block stores the result of the nested task it resumes
+    /// from in, when the block follows a `let x = await f();`.
+    fn awaited_result<'c>(&self, cfg: &ControlFlowGraph<'c>, idx: NodeIndex) -> Option<(&'c VarDecl, String)> {
+        let previous = cfg.awaiting_block(idx)?;
+
+        match cfg.graph[previous].statements.last() {
+            Some(&&Statement::LetAwait { ref var, expr: Expression::FnCall { ref target, ref args } }) => {
+                if channels::channel_call(target, args, self.symbols).is_some() {
+                    return None;
+                }
+                let callee = self.symbols.function(target)?;
+                if callee.async && callee.external == false {
+                    return Some((var, callee.c_name.clone()));
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// The `box` awaited by the block before `idx`, which the block takes the
+    /// result of and releases, and the local the result goes into.
+    fn awaited_box<'s, 'c>(
+        &self, state: &'s TaskState, cfg: &ControlFlowGraph<'c>, idx: NodeIndex,
+    ) -> Option<(&'s Boxed<'s>, Option<&'c VarDecl>)> {
+        let previous = cfg.awaiting_block(idx)?;
+        let last = *cfg.graph[previous].statements.last()?;
+
+        let boxed = state.boxed(walk::awaited(last)?)?;
+        match last {
+            &Statement::LetAwait { ref var, .. } => Some((boxed, Some(var))),
+            _ => Some((boxed, None)),
+        }
+    }
+
+    /// Starts a task awaited with `box` in a slot of its pool. A task that
+    /// finds the pool full reports it to `box_failed` and is cancelled.
+    fn write_boxed_await(&self, w: &mut Write, state: &TaskState, boxed: &Boxed, next_block: &str) -> Result<(), Error> {
+        let (target, args) = match boxed.expr {
+            &Expression::Boxed { ref task, .. } => match **task {
+                Expression::FnCall { ref target, ref args } => (target, args),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        let callee = self.symbols.function(target).expect("boxed calls are checked before code generation");
+        let pool = self.pool(&boxed.task);
+        let args = args.iter()
+            .map(|arg| format!(", {}", self.expression(Scope::Task(state), arg)))
+            .collect::<String>();
+
+        writeln!(w, "    {} *boxed = {}();", pool.type_name(), pool.acquire_fn_name())?;
+        writeln!(w, "    if (boxed == NULL) {{")?;
+        writeln!(w, "        box_failed({});", string_literal(&pool.c_name))?;
+        writeln!(w, "        this->core.cancel = (TaskFn){};", cancel_fn_name(state, None))?;
+        writeln!(w, "        Task_cancel(&this->core);")?;
+        writeln!(w, "        return;")?;
+        writeln!(w, "    }}")?;
+        writeln!(w, "    this->nested_tasks.{} = boxed;", boxed.member_name())?;
+        writeln!(w, "    Continuation resume;")?;
+        writeln!(w, "    Continuation_init(&resume, (TaskFn){}, this, this->core.priority);", next_block)?;
+        writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, Some(&boxed.member_name())))?;
+        writeln!(w, "    {}(boxed, resume{});", self.start_fn_name(&callee.c_name), args)
+    }
+
+    fn pool(&self, c_name: &str) -> &TaskPool<'b> {
+        self.pools.iter()
+            .find(|pool| pool.c_name == c_name)
+            .expect("every spawned or boxed task has a pool")
+    }
+
+    fn write_await(
+        &self, w: &mut Write, state: &TaskState, expr: &Expression, binding: Option<&VarDecl>, next_block: &str,
+    ) -> Result<(), Error> {
+        if let &Expression::FnCall { ref target, ref args } = expr {
+            if let Some(call) = channels::channel_call(target, args, self.symbols) {
+                return self.write_channel_await(w, state, &call, binding, next_block);
+            }
+            if let Some(call) = locks::lock_call(target, args, self.symbols) {
+                let member = state.lock_member_name(call.lock);
+                writeln!(w, "    Continuation resume;")?;
+                writeln!(w, "    Continuation_init(&resume, (TaskFn){}, this, this->core.priority);", next_block)?;
+                writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, Some(&member)))?;
+                return writeln!(
+                    w, "    {}({}, &this->nested_tasks.{}, resume);",
+                    lock_fn_name(&call), lock_handle(call.lock, call.kind), member,
+                );
+            }
+            if timers::is_sleep(target, self.symbols) {
+                writeln!(w, "    Continuation resume;")?;
+                writeln!(w, "    Continuation_init(&resume, (TaskFn){}, this, this->core.priority);", next_block)?;
+                writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, Some(TIMER_MEMBER)))?;
+                return writeln!(
+                    w, "    Timer_sleep(&this->nested_tasks.{}, {}, resume);",
+                    TIMER_MEMBER, self.expression(Scope::Task(state), &args[0]),
+                );
+            }
+            if let Some(callee) = self.symbols.function(target) {
+                let args = args.iter()
+                    .map(|arg| format!(", {}", self.expression(Scope::Task(state), arg)))
+                    .collect::<String>();
+
+                writeln!(w, "    Continuation resume;")?;
+                writeln!(w, "    Continuation_init(&resume, (TaskFn){}, this, this->core.priority);", next_block)?;
+
+                if callee.async && callee.external == false {
+                    writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, Some(&callee.c_name)))?;
+                    writeln!(
+                        w, "    {}(&this->nested_tasks.{}, resume{});",
+                        self.start_fn_name(&callee.c_name), callee.c_name, args,
+                    )?;
+                } else if callee.async || callee.takes_implicit_continuation() {
+                    writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, None))?;
+                    writeln!(w, "    {}(resume{});", callee.c_name, args)?;
+                } else {
+                    writeln!(w, "#error \"`{}` cannot be awaited\"", callee.c_name)?;
+                }
+
+                return Ok(());
+            }
+        }
+
+        writeln!(w, "    Continuation_invoke({});", self.continuation(Scope::Task(state), expr))?;
+        writeln!(w, "    {}(this);", next_block)
+    }
+
+    /// Suspends on a channel, parking the task in its slot of the
+    /// `nested_tasks` union. A sent value is copied into the slot first, and a
+    /// received value goes straight into its local.
+    fn write_channel_await(
+        &self, w: &mut Write, state: &TaskState, call: &ChannelCall, binding: Option<&VarDecl>, next_block: &str,
+    ) -> Result<(), Error> {
+        let channel = format!("globals.{}", call.channel.name);
+        let slot = format!("this->nested_tasks.{}", state.channel_member_name(call.channel));
+
+        writeln!(w, "    Continuation resume;")?;
+        writeln!(w, "    Continuation_init(&resume, (TaskFn){}, this, this->core.priority);", next_block)?;
+        writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, Some(&state.channel_member_name(call.channel))))?;
+
+        match call.method {
+            channels::SEND => {
+                writeln!(w, "    {}.value = {};", slot, self.expression(Scope::Task(state), &call.args[0]))?;
+                writeln!(
+                    w, "    Channel_send(CHANNEL_ARGS({}), &{}.waiter, &{}.value, resume);",
+                    channel, slot, slot,
+                )
+            }
+            channels::RECV => {
+                let into = match binding {
+                    Some(var) => format!("this->locals.{}", var.name),
+                    None => format!("{}.value", slot),
+                };
+                writeln!(w, "    Channel_recv(CHANNEL_ARGS({}), &{}.waiter, &{}, resume);", channel, slot, into)
+            }
+            _ => unreachable!("only `send` and `recv` suspend, which is checked before code generation"),
+        }
+    }
+
+    /// Starts every child of a `join`, each of which completes into a function
+    /// that resumes the task once the last child is done.
+    fn write_join(&self, w: &mut Write, state: &TaskState, join: &Join, expr: &Expression) -> Result<(), Error> {
+        let tasks = match expr {
+            &Expression::Join { ref tasks } => tasks,
+            _ => unreachable!(),
+        };
+
+        writeln!(w, "    Continuation done;")?;
+        writeln!(w, "    Continuation_init(&done, (TaskFn){}, this, this->core.priority);", join_done_fn_name(state, join))?;
+        writeln!(w, "    this->nested_tasks.{}.pending = {};", join.member_name(), tasks.len())?;
+        writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, Some(&join.member_name())))?;
+
+        for (i, task) in tasks.iter().enumerate() {
+            let slot = join.children[i].as_ref()
+                .map(|_| format!("nested_tasks.{}.{}", join.member_name(), join.child_member_name(i)));
+            self.write_child_start(w, state, task, slot, "done")?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the generator of a `for await` loop in its slot, completing into
+    /// a function that runs the body for each value and leaves the loop once
+    /// the generator is finished.
+    fn write_stream_start(&self, w: &mut Write, state: &TaskState, stream: &Stream) -> Result<(), Error> {
+        let generator = walk::streamed(stream.statement).expect("streams are collected from `for await` loops");
+
+        writeln!(w, "    Continuation next;")?;
+        writeln!(w, "    Continuation_init(&next, (TaskFn){}, this, this->core.priority);", stream_next_fn_name(state, stream))?;
+        writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, None))?;
+        self.write_child_start(w, state, generator, Some(format!("streams.{}", stream.member_name())), "next")
+    }
+
+    /// Writes the function a generator completes into each time it yields,
+    /// and once more when it is finished. The body asks for the next value
+    /// through the ready queue, so that a generator which yields without
+    /// suspending cannot grow the stack.
+    fn write_stream_next(
+        &self, w: &mut Write, state: &TaskState, stream: &Stream, item_block: NodeIndex, end_block: NodeIndex,
+    ) -> Result<(), Error> {
+        let var = match stream.statement {
+            &Statement::ForAwait { ref var, .. } => var,
+            _ => unreachable!(),
+        };
+        let member = stream.member_name();
+
+        writeln!(w, "static void {}({} *this) {{", stream_next_fn_name(state, stream), state.type_name())?;
+        write_cancelled_guard(w)?;
+        writeln!(w, "    if (this->streams.{}.finished) {{", member)?;
+        writeln!(w, "        {}(this);", block_fn_name(state, end_block))?;
+        writeln!(w, "        return;")?;
+        writeln!(w, "    }}")?;
+        writeln!(w, "    this->locals.{} = this->streams.{}.item;", var.name, member)?;
+        writeln!(w, "    {}(this);", block_fn_name(state, item_block))?;
+        writeln!(w, "}}")?;
+        writeln!(w)
+    }
+
+    /// Starts the timer of a `timeout`, then the task it races against, so
+    /// that a task completing on the spot can still cancel the timer.
+    fn write_timeout(&self, w: &mut Write, state: &TaskState, timeout: &Timeout) -> Result<(), Error> {
+        let (ms, task) = match timeout.expr {
+            &Expression::FnCall { ref args, .. } => (&args[0], &args[1]),
+            _ => unreachable!(),
+        };
+        let member = timeout.member_name();
+
+        writeln!(w, "    Continuation done;")?;
+        writeln!(w, "    this->nested_tasks.{}.finished = false;", member)?;
+        writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, Some(&member)))?;
+        writeln!(w, "    Continuation_init(&done, (TaskFn){}, this, this->core.priority);", timeout_fn_name(state, timeout, TIMEOUT_OUTCOMES[1]))?;
+        writeln!(
+            w, "    Timer_sleep(&this->nested_tasks.{}.timer, {}, done);",
+            member, self.expression(Scope::Task(state), ms),
+        )?;
+        writeln!(w, "    Continuation_init(&done, (TaskFn){}, this, this->core.priority);", timeout_fn_name(state, timeout, TIMEOUT_OUTCOMES[0]))?;
+        self.write_child_start(w, state, task, Some(format!("nested_tasks.{}.task", member)), "done")
+    }
+
+    /// Writes the functions the task and the timer of a `timeout` complete
+    /// into. Whichever finishes first stops the other and stores the outcome.
+    fn write_timeout_outcomes(
+        &self, w: &mut Write, state: &TaskState, timeout: &Timeout, binding: Option<&VarDecl>, next_block: &str,
+    ) -> Result<(), Error> {
+        let member = timeout.member_name();
+        let returns_value = match timeout.expr {
+            &Expression::FnCall { ref args, .. } => match args[1] {
+                Expression::FnCall { ref target, .. } => self.symbols.function(target)
+                    .map(|task| is_unit(task.returns) == false)
+                    .unwrap_or(false),
+                _ => false,
+            },
+            _ => false,
+        };
+
+        for outcome in TIMEOUT_OUTCOMES.iter() {
+            let completed = *outcome == TIMEOUT_OUTCOMES[0];
+
+            writeln!(w, "static void {}({} *this) {{", timeout_fn_name(state, timeout, outcome), state.type_name())?;
+            write_cancelled_guard(w)?;
+            writeln!(w, "    if (this->nested_tasks.{}.finished) {{", member)?;
+            writeln!(w, "        return;")?;
+            writeln!(w, "    }}")?;
+            writeln!(w, "    this->nested_tasks.{}.finished = true;", member)?;
+            if completed {
+                writeln!(w, "    Timer_cancel(&this->nested_tasks.{}.timer);", member)?;
+            } else {
+                writeln!(w, "    Task_cancel(&this->nested_tasks.{}.task.core);", member)?;
+            }
+            if let Some(var) = binding {
+                writeln!(w, "    this->locals.{}.status = {};", var.name, if completed { "Ok" } else { "TimedOut" })?;
+                if completed && returns_value {
+                    writeln!(w, "    this->locals.{}.value = this->nested_tasks.{}.task.result;", var.name, member)?;
+                }
+            }
+            writeln!(w, "    {}(this);", next_block)?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the task of every arm of a `select`. Each arm completes into a
+    /// function of its own, and an arm whose task completes on the spot ends
+    /// the select before the remaining arms are started.
+    fn write_select(&self, w: &mut Write, state: &TaskState, select: &Select) -> Result<(), Error> {
+        let member = select.member_name();
+
+        writeln!(w, "    Continuation done;")?;
+        writeln!(w, "    this->nested_tasks.{}.winner = 0;", member)?;
+        writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, Some(&member)))?;
+        for (i, child) in select.children.iter().enumerate() {
+            if let &Some(Child::Task(..)) = child {
+                writeln!(w, "    this->nested_tasks.{}.{}.core.cancel = NULL;", member, select.child_member_name(i))?;
+            }
+        }
+
+        for (i, arm) in select.arms.iter().enumerate() {
+            if i > 0 {
+                writeln!(w, "    if (this->nested_tasks.{}.winner != 0) {{", member)?;
+                writeln!(w, "        return;")?;
+                writeln!(w, "    }}")?;
+            }
+            writeln!(w, "    Continuation_init(&done, (TaskFn){}, this, this->core.priority);", select_arm_fn_name(state, select, i))?;
+            let slot = select.children[i].as_ref()
+                .map(|_| format!("nested_tasks.{}.{}", member, select.child_member_name(i)));
+            self.write_child_start(w, state, &arm.task, slot, "done")?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the function an arm of a `select` completes into. The first arm
+    /// to complete stores its result, cancels the other arms and continues
+    /// with its body; arms completing after it are ignored.
+    fn write_select_arm(
+        &self, w: &mut Write, state: &TaskState, select: &Select, arm: usize, next_block: &str, back: bool,
+    ) -> Result<(), Error> {
+        let member = select.member_name();
+
+        writeln!(w, "static void {}({} *this) {{", select_arm_fn_name(state, select, arm), state.type_name())?;
+        write_cancelled_guard(w)?;
+        writeln!(w, "    if (this->nested_tasks.{}.winner != 0) {{", member)?;
+        writeln!(w, "        return;")?;
+        writeln!(w, "    }}")?;
+        writeln!(w, "    this->nested_tasks.{}.winner = {};", member, arm + 1)?;
+        if let Some(ref binding) = select.arms[arm].binding {
+            writeln!(
+                w, "    this->locals.{} = this->nested_tasks.{}.{}.result;",
+                binding.name, member, select.child_member_name(arm),
+            )?;
+        }
+        for (i, child) in select.children.iter().enumerate() {
+            if let (true, &Some(ref child)) = (i != arm, child) {
+                write_child_cancel(w, child, &format!("nested_tasks.{}.{}", member, select.child_member_name(i)))?;
+            }
+        }
+        write_jump(w, state, next_block, back)?;
+        writeln!(w, "}}")?;
+        writeln!(w)
+    }
+
+    /// Writes the cancel hooks of a task, one for each kind of await point it
+    /// can be suspended on, and a function for the body of each `defer`. The
+    /// hook of an await point cancels the tasks started there, then runs the
+    /// `defer` blocks reached so far in reverse order.
+    fn write_cancellation(&self, w: &mut Write, state: &TaskState) -> Result<(), Error> {
+        for (index, defer) in state.defers.iter().enumerate() {
+            let body = match *defer {
+                &Statement::Defer(ref body) => body,
+                _ => unreachable!(),
+            };
+
+            writeln!(w, "static void {}({} *this) {{", defer_fn_name(state, index), state.type_name())?;
+            for statement in body.iter() {
+                self.write_statement(w, Scope::Task(state), statement, 1)?;
+            }
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        writeln!(w, "static void {}({} *this) {{", cancel_fn_name(state, None), state.type_name())?;
+        for stream in state.streams.iter() {
+            writeln!(w, "    Task_cancel(&this->streams.{}.core);", stream.member_name())?;
+        }
+        for index in (0..state.defers.len()).rev() {
+            writeln!(w, "    if (this->deferred > {}) {{", index)?;
+            writeln!(w, "        {}(this);", defer_fn_name(state, index))?;
+            writeln!(w, "    }}")?;
+        }
+        writeln!(w, "}}")?;
+        writeln!(w)?;
+
+        for nested in state.nested_tasks.iter() {
+            writeln!(w, "static void {}({} *this) {{", cancel_fn_name(state, Some(nested)), state.type_name())?;
+            writeln!(w, "    Task_cancel(&this->nested_tasks.{}.core);", nested)?;
+            writeln!(w, "    {}(this);", cancel_fn_name(state, None))?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        let joins = state.joins.iter().map(|join| {
+            let children = join.children.iter().enumerate()
+                .filter_map(|(i, child)| child.as_ref().map(|child| (child, join.child_member_name(i))))
+                .collect::<Vec<_>>();
+            (join.member_name(), children)
+        });
+        let selects = state.selects.iter().map(|select| {
+            let children = select.children.iter().enumerate()
+                .filter_map(|(i, child)| child.as_ref().map(|child| (child, select.child_member_name(i))))
+                .collect::<Vec<_>>();
+            (select.member_name(), children)
+        });
+        for channel in state.channels.iter() {
+            let member = state.channel_member_name(channel);
+            writeln!(w, "static void {}({} *this) {{", cancel_fn_name(state, Some(&member)), state.type_name())?;
+            writeln!(w, "    Channel_cancel(&globals.{}.core, &this->nested_tasks.{}.waiter);", channel.name, member)?;
+            writeln!(w, "    {}(this);", cancel_fn_name(state, None))?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        for lock in state.locks.iter() {
+            let member = state.lock_member_name(lock);
+            let kind = locks::lock_kind(&lock.type_ref).expect("only locks are collected");
+            writeln!(w, "static void {}({} *this) {{", cancel_fn_name(state, Some(&member)), state.type_name())?;
+            writeln!(w, "    {}_cancel({}, &this->nested_tasks.{});", kind.name(), lock_handle(lock, kind), member)?;
+            writeln!(w, "    {}(this);", cancel_fn_name(state, None))?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        for timeout in state.timeouts.iter() {
+            let member = timeout.member_name();
+            writeln!(w, "static void {}({} *this) {{", cancel_fn_name(state, Some(&member)), state.type_name())?;
+            writeln!(w, "    Timer_cancel(&this->nested_tasks.{}.timer);", member)?;
+            writeln!(w, "    Task_cancel(&this->nested_tasks.{}.task.core);", member)?;
+            writeln!(w, "    {}(this);", cancel_fn_name(state, None))?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        for boxed in state.boxes.iter() {
+            let member = boxed.member_name();
+            writeln!(w, "static void {}({} *this) {{", cancel_fn_name(state, Some(&member)), state.type_name())?;
+            writeln!(w, "    Task_cancel(&this->nested_tasks.{}->core);", member)?;
+            writeln!(w, "    {}(this->nested_tasks.{});", self.pool(&boxed.task).release_fn_name(), member)?;
+            writeln!(w, "    {}(this);", cancel_fn_name(state, None))?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        if state.sleeps {
+            writeln!(w, "static void {}({} *this) {{", cancel_fn_name(state, Some(TIMER_MEMBER)), state.type_name())?;
+            writeln!(w, "    Timer_cancel(&this->nested_tasks.{});", TIMER_MEMBER)?;
+            writeln!(w, "    {}(this);", cancel_fn_name(state, None))?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        for (member, children) in joins.chain(selects) {
+            writeln!(w, "static void {}({} *this) {{", cancel_fn_name(state, Some(&member)), state.type_name())?;
+            for (child, child_member) in children {
+                write_child_cancel(w, child, &format!("nested_tasks.{}.{}", member, child_member))?;
+            }
+            writeln!(w, "    {}(this);", cancel_fn_name(state, None))?;
+            writeln!(w, "}}")?;
+            writeln!(w)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a child task, e.g. of a `join` or `select`, completing into
+    /// `continuation`. Async functions of this program run in the given slot,
+    /// a member path of the task state such as `nested_tasks.join0.task1`.
+    fn write_child_start(
+        &self, w: &mut Write, state: &TaskState, task: &Expression, slot: Option<String>, continuation: &str,
+    ) -> Result<(), Error> {
+        let (target, args) = match task {
+            &Expression::FnCall { ref target, ref args } => (target, args),
+            _ => unreachable!("only calls can be started concurrently"),
+        };
+        if timers::is_sleep(target, self.symbols) {
+            let slot = slot.expect("a `sleep_ms` started concurrently has a timer of its own");
+            return writeln!(
+                w, "    Timer_sleep(&this->{}, {}, {});",
+                slot, self.expression(Scope::Task(state), &args[0]), continuation,
+            );
+        }
+        let callee = self.symbols.function(target).expect("calls are checked before code generation");
+        let args = args.iter()
+            .map(|arg| format!(", {}", self.expression(Scope::Task(state), arg)))
+            .collect::<String>();
+
+        match slot {
+            Some(slot) => writeln!(
+                w, "    {}(&this->{}, {}{});",
+                self.start_fn_name(&callee.c_name), slot, continuation, args,
+            ),
+            None if callee.async || callee.takes_implicit_continuation() => {
+                writeln!(w, "    {}({}{});", callee.c_name, continuation, args)
+            }
+            None => writeln!(w, "#error \"`{}` cannot be awaited\"", callee.c_name),
+        }
+    }
+
+    fn write_statement(&self, w: &mut Write, scope: Scope, x: &Statement, indent: usize) -> Result<(), Error> {
+        let prefix = "    ".repeat(indent);
+
+        match x {
+            &Statement::Let { ref var, ref expr } => {
+                let target = match scope {
+                    Scope::Task(state) if state.has_local(&var.name) => format!("this->locals.{}", var.name),
+                    _ => c_declaration(&var.type_ref, &var.name, self.symbols),
+                };
+                writeln!(w, "{}{} = {};", prefix, target, self.expression(scope, expr))
+            }
+            &Statement::Assignment { ref target, ref expr } => {
+                writeln!(w, "{}{} = {};", prefix, self.expression(scope, target), self.expression(scope, expr))
+            }
+            &Statement::FnCall { ref target, ref args } => {
+                if let Some(call) = channels::channel_call(target, args, self.symbols) {
+                    return writeln!(w, "{}{};", prefix, self.channel_expression(scope, &call));
+                }
+                if let Some(call) = locks::lock_call(target, args, self.symbols) {
+                    return writeln!(w, "{}{}({});", prefix, lock_fn_name(&call), lock_handle(call.lock, call.kind));
+                }
+                if timers::is_tick(target, self.symbols) {
+                    return writeln!(w, "{}Timer_tick();", prefix);
+                }
+                writeln!(w, "{}{}({});", prefix, self.expression(scope, target), self.arguments(scope, args))
+            }
+            &Statement::Await(..) if scope.is_closure() => {
+                writeln!(w, "#error \"closures cannot await\"")
+            }
+            &Statement::Await(Expression::FnCall { .. }) => {
+                writeln!(w, "#error \"calls can only be awaited inside an async function\"")
+            }
+            &Statement::Await(ref expr) if scope.is_deferred() => {
+                writeln!(w, "{}Continuation_schedule({});", prefix, self.continuation(scope, expr))
+            }
+            &Statement::Await(ref expr) => {
+                writeln!(w, "{}Continuation_invoke({});", prefix, self.continuation(scope, expr))
+            }
+            &Statement::LetAwait { .. } => {
+                writeln!(w, "#error \"awaited values can only be bound inside an async function\"")
+            }
+            &Statement::Yield(..) => writeln!(w, "#error \"`yield` can only be used inside a generator\""),
+            &Statement::ForAwait { .. } => {
+                writeln!(w, "#error \"`for await` can only be used inside an async function\"")
+            }
+            &Statement::Select(..) => {
+                writeln!(w, "#error \"`select` can only be used inside an async function\"")
+            }
+            &Statement::Spawn { ref task, priority } => self.write_spawn(w, scope, task, priority, x, &prefix),
+            &Statement::Defer(..) => match scope {
+                Scope::Task(state) => {
+                    let index = state.defer_index(x).expect("every `defer` is collected before code generation");
+                    writeln!(w, "{}this->deferred = {};", prefix, index + 1)
+                }
+                _ => writeln!(w, "#error \"`defer` can only be used inside an async function\""),
+            },
+            &Statement::Suspend { ref body, .. } => match scope {
+                Scope::Function(..) => {
+                    for statement in body.iter() {
+                        self.write_statement(w, scope, statement, indent)?;
+                    }
+                    Ok(())
+                }
+                _ => writeln!(w, "#error \"`suspend` can only be used inside a function\""),
+            },
+            &Statement::Atomic(ref statements) => {
+                writeln!(w, "{}ATOMIC_ENTER();", prefix)?;
+                for statement in statements.iter() {
+                    self.write_statement(w, scope, statement, indent)?;
+                }
+                writeln!(w, "{}ATOMIC_EXIT();", prefix)
+            }
+            &Statement::Loop(ref statements) => {
+                writeln!(w, "{}for (;;) {{", prefix)?;
+                for statement in statements.iter() {
+                    self.write_statement(w, scope, statement, indent + 1)?;
+                }
+                writeln!(w, "{}}}", prefix)
+            }
+            &Statement::Return(ref expr) => match scope {
+                Scope::Task(..) => writeln!(w, "{}this->result = {};", prefix, self.expression(scope, expr)),
+                Scope::Function(..) => writeln!(w, "{}return {};", prefix, self.expression(scope, expr)),
+                _ => writeln!(w, "#error \"`return` can only be used inside a function\""),
+            },
+        }
+    }
+
+    /// An awaited continuation. One stored in a variable is taken out of it,
+    /// so that a runtime built with `CONTINUATION_DEBUG` traps when it is
+    /// invoked again.
+    fn continuation(&self, scope: Scope, x: &Expression) -> String {
+        match x {
+            &Expression::Identifier(..) if self.constant_value(x).is_none() && self.symbols.function(x).is_none() => {
+                format!("Continuation_take(&{})", self.expression(scope, x))
+            }
+            _ => self.expression(scope, x),
+        }
+    }
+
+    fn expression(&self, scope: Scope, x: &Expression) -> String {
+        match x {
+            &Expression::Literal(Literal::Boolean(b)) => format!("{}", b),
+            &Expression::Literal(Literal::Integer(i)) => format!("{}", i),
+            &Expression::Literal(Literal::Float(f)) => format!("{:?}", f),
+            &Expression::Literal(Literal::String(ref s)) => string_literal(s),
+            &Expression::Identifier(..) | &Expression::Path(..) if self.constant_value(x).is_some() => {
+                self.constant_value(x).unwrap().to_c_literal()
+            }
+            &Expression::Identifier(ref name) => {
+                let is_global = self.symbols.globals.get(name.as_str())
+                    .map(|g| g.external == false)
+                    .unwrap_or(false);
+
+                let is_task_local = match scope {
+                    Scope::Task(state) => state.has_local(name),
+                    _ => false,
+                };
+                let is_capture = match scope {
+                    Scope::Closure(closure) => closure.captures_local(name),
+                    _ => false,
+                };
+
+                if is_task_local {
+                    format!("this->locals.{}", name)
+                } else if is_capture {
+                    format!("(*this->{})", name)
+                } else if is_global {
+                    format!("globals.{}", name)
+                } else if let Some(f) = self.symbols.function(x) {
+                    f.c_name.clone()
+                } else {
+                    name.clone()
+                }
+            }
+            &Expression::Path(ref segments) => {
+                match self.symbols.function(x) {
+                    Some(f) => f.c_name.clone(),
+                    None => segments.join("_"),
+                }
+            }
+            &Expression::MemberOf { ref structure, ref member } => {
+                format!("{}.{}", self.expression(scope, structure), member)
+            }
+            &Expression::BinOp { ref left, operator: ref op, ref right } => {
+                format!("({} {} {})", self.expression(scope, left), operator(op), self.expression(scope, right))
+            }
+            &Expression::FnCall { ref target, ref args } => {
+                if let Some(call) = channels::channel_call(target, args, self.symbols) {
+                    return self.channel_expression(scope, &call);
+                }
+                if let Some(call) = locks::lock_call(target, args, self.symbols) {
+                    return format!("{}({})", lock_fn_name(&call), lock_handle(call.lock, call.kind));
+                }
+                match (scope, &**target) {
+                    (Scope::Task(_), &Expression::Identifier(ref name)) if name == "task_current" => "this->core".into(),
+                    _ => format!("{}({})", self.expression(scope, target), self.arguments(scope, args)),
+                }
+            }
+            &Expression::Closure { .. } => {
+                let closure = self.closure(x).expect("every closure is collected before code generation");
+                let context = match scope {
+                    Scope::Task(..) if closure.has_context() => format!("&this->closures.{}", closure.member_name()),
+                    _ => "NULL".into(),
+                };
+                let priority = match scope {
+                    Scope::Task(..) => "this->core.priority".into(),
+                    _ => DEFAULT_PRIORITY.to_string(),
+                };
+                format!("((Continuation){{ (TaskFn){}, {}, {} }})", closure.fn_name(), context, priority)
+            }
+            &Expression::Join { .. } => unreachable!("`join` can only be awaited"),
+            &Expression::Boxed { .. } => unreachable!("`box` can only be awaited"),
+        }
+    }
+
+    /// A channel method that does not suspend, passing the value through a
+    /// compound literal so that any expression can be sent.
+    fn channel_expression(&self, scope: Scope, call: &ChannelCall) -> String {
+        match call.method {
+            channels::TRY_SEND => format!(
+                "Channel_try_send(CHANNEL_ARGS(globals.{}), &({}){{ {} }})",
+                call.channel.name, c_type(call.element), self.expression(scope, &call.args[0]),
+            ),
+            _ => unreachable!("`send` and `recv` must be awaited, which is checked before code generation"),
+        }
+    }
+
+    fn all_closures(&self) -> Vec<&Closure<'b>> {
+        self.states.iter()
+            .flat_map(|state| state.closures.iter())
+            .chain(self.plain_closures.iter())
+            .collect()
+    }
+
+    fn closure(&self, expr: &Expression) -> Option<&Closure<'b>> {
+        self.all_closures()
+            .into_iter()
+            .find(|closure| ::std::ptr::eq(closure.expr, expr))
+    }
+
+    fn constant_value(&self, x: &Expression) -> Option<ConstValue> {
+        self.symbols.constant(x).and_then(|c| c.value)
+    }
+
+    fn arguments(&self, scope: Scope, args: &[Expression]) -> String {
+        args.iter()
+            .map(|arg| self.expression(scope, arg))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The context in which statements and expressions are being emitted, which
+/// determines where locals live.
+#[derive(Copy, Clone)]
+enum Scope<'s, 'a: 's> {
+    /// A plain function, whose locals are C locals. It resumes tasks as the
+    /// interrupts it runs in do.
+    Function(Dispatch),
+    /// An interrupt handler, which resumes tasks as its dispatch says.
+    Interrupt(Dispatch),
+    /// A block of an async function, whose locals live in the task state.
+    Task(&'s TaskState<'a>),
+    /// A closure, which reaches captured locals through its context.
+    Closure(&'s Closure<'a>),
+}
+
+impl<'s, 'a> Scope<'s, 'a> {
+    fn is_deferred(&self) -> bool {
+        match self {
+            &Scope::Function(Dispatch::Deferred) | &Scope::Interrupt(Dispatch::Deferred) => true,
+            _ => false,
+        }
+    }
+
+    fn is_closure(&self) -> bool {
+        match self {
+            &Scope::Closure(..) => true,
+            _ => false,
+        }
+    }
+}
+
+fn closure_signature(closure: &Closure) -> String {
+    if closure.has_context() {
+        format!("void {}({} *this)", closure.fn_name(), closure.context_type_name())
+    } else {
+        format!("void {}(void *this)", closure.fn_name())
+    }
+}
+
+fn join_done_fn_name(state: &TaskState, join: &Join) -> String {
+    format!("join_{}{}_done", state.c_name, join.index)
+}
+
+/// The priority of tasks started outside of any other task, unless their
+/// function or `spawn` says otherwise.
+const DEFAULT_PRIORITY: u8 = 0;
+
+/// Ignores a completion arriving after the task was cancelled, which C
+/// functions that cannot be stopped may still deliver.
+/// Cancels the child of a `join` or `select` kept in `slot`.
+fn write_child_cancel(w: &mut Write, child: &Child, slot: &str) -> Result<(), Error> {
+    match child {
+        &Child::Task(..) => writeln!(w, "    Task_cancel(&this->{}.core);", slot),
+        &Child::Timer => writeln!(w, "    Timer_cancel(&this->{});", slot),
+    }
+}
+
+/// Continues a task with the block `next_block`, directly unless the jump
+/// leads back to the start of a loop, whose next pass is scheduled instead.
+fn write_jump(w: &mut Write, state: &TaskState, next_block: &str, back: bool) -> Result<(), Error> {
+    if back == false {
+        return writeln!(w, "    {}(this);", next_block);
+    }
+    writeln!(w, "    Continuation resume;")?;
+    writeln!(w, "    Continuation_init(&resume, (TaskFn){}, this, this->core.priority);", next_block)?;
+    writeln!(w, "    this->core.cancel = (TaskFn){};", cancel_fn_name(state, None))?;
+    writeln!(w, "    Continuation_schedule(resume);")
+}
+
+fn write_cancelled_guard(w: &mut Write) -> Result<(), Error> {
+    writeln!(w, "    if (this->core.cancelled) {{")?;
+    writeln!(w, "        return;")?;
+    writeln!(w, "    }}")
+}
+
+/// The members of the `nested_tasks` union that have a cancel hook of their
+/// own, preceded by `None` for the hook of await points without one.
+fn cancel_members(state: &TaskState) -> Vec<Option<String>> {
+    let mut result = vec![None];
+    result.extend(state.nested_tasks.iter().cloned().map(Some));
+    result.extend(state.joins.iter().map(|join| Some(join.member_name())));
+    result.extend(state.selects.iter().map(|select| Some(select.member_name())));
+    result.extend(state.channels.iter().map(|channel| Some(state.channel_member_name(channel))));
+    result.extend(state.locks.iter().map(|lock| Some(state.lock_member_name(lock))));
+    if state.sleeps {
+        result.push(Some(TIMER_MEMBER.into()));
+    }
+    result.extend(state.timeouts.iter().map(|timeout| Some(timeout.member_name())));
+    result.extend(state.boxes.iter().map(|boxed| Some(boxed.member_name())));
+    result
+}
+
+fn cancel_fn_name(state: &TaskState, member: Option<&str>) -> String {
+    match member {
+        Some(member) => format!("cancel_{}_{}", state.c_name, member),
+        None => format!("cancel_{}", state.c_name),
+    }
+}
+
+/// The runtime function implementing a method of a lock, e.g. `Mutex_lock`.
+fn lock_fn_name(call: &LockCall) -> String {
+    format!("{}_{}", call.kind.name(), call.method)
+}
+
+/// The pointer the runtime's lock functions take for a global lock.
+fn lock_handle(lock: &VarDecl, kind: LockKind) -> String {
+    match kind {
+        LockKind::Mutex => format!("&globals.{}.core", lock.name),
+        LockKind::Semaphore => format!("&globals.{}", lock.name),
+    }
+}
+
+/// How the race of a `timeout` can end: the task completes, or the timer
+/// expires first.
+const TIMEOUT_OUTCOMES: [&str; 2] = ["completed", "expired"];
+
+fn stream_next_fn_name(state: &TaskState, stream: &Stream) -> String {
+    format!("for_{}{}_next", state.c_name, stream.index)
+}
+
+fn timeout_fn_name(state: &TaskState, timeout: &Timeout, outcome: &str) -> String {
+    format!("timeout_{}{}_{}", state.c_name, timeout.index, outcome)
+}
+
+fn defer_fn_name(state: &TaskState, index: usize) -> String {
+    format!("defer_{}{}", state.c_name, index)
+}
+
+fn select_arm_fn_name(state: &TaskState, select: &Select, arm: usize) -> String {
+    format!("select_{}{}_arm{}", state.c_name, select.index, arm)
+}
+
+fn block_fn_name(state: &TaskState, idx: NodeIndex) -> String {
+    format!("task_{}{}", state.c_name, idx.index())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+    use super::super::super::options::{Dispatch, Options};
+
+    #[test]
+    fn returned_values_are_stored_as_the_task_result() {
+        let output = compile(vec![
+            async_fn("measure", vec![var("base", ty("u32"))], ty("u32"), vec![
+                Statement::Return(add(ident("base"), int(1))),
+            ]),
+            plain_fn("twice", vec![var("x", ty("u32"))], ty("u32"), vec![
+                Statement::Return(add(ident("x"), ident("x"))),
+            ]),
+            async_fn("main", vec![], unit(), vec![
+                let_await("level", ty("u32"), call("measure", vec![int(2)])),
+                let_("doubled", ty("u32"), call("twice", vec![ident("level")])),
+            ]),
+        ]);
+        assert!(output.source.contains("    this->result = (this->locals.base + 1);"), "{}", output.source);
+        assert!(output.function("twice").contains("return (x + x);"), "{}", output.source);
+        assert!(output.source.contains("= this->nested_tasks.measure.result;"), "{}", output.source);
+    }
+
+    #[test]
+    fn select_arms_store_what_they_await_in_their_own_blocks() {
+        let output = compile(vec![
+            async_fn("h", vec![], ty("u32"), vec![Statement::Return(int(1))]),
+            async_fn("k", vec![], ty("u32"), vec![Statement::Return(int(2))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Select(vec![
+                    SelectArm {
+                        binding: Some(var("first", ty("u32"))),
+                        task: call("h", vec![]),
+                        body: vec![let_await("a", ty("u32"), call("h", vec![]))],
+                    },
+                    SelectArm {
+                        binding: Some(var("second", ty("u32"))),
+                        task: call("k", vec![]),
+                        body: vec![let_await("b", ty("u32"), call("k", vec![]))],
+                    },
+                ]),
+            ]),
+        ]);
+        assert!(output.source.contains("this->locals.first = this->nested_tasks."), "{}", output.source);
+        assert!(output.source.contains("this->locals.second = this->nested_tasks."), "{}", output.source);
+        assert!(output.source.contains("    this->locals.a = this->nested_tasks.h.result;"), "{}", output.source);
+        assert!(output.source.contains("    this->locals.b = this->nested_tasks.k.result;"), "{}", output.source);
+    }
+
+    #[test]
+    fn timeouts_hand_on_the_result_of_a_task_completed_in_time() {
+        let output = compile(vec![
+            async_fn("measure", vec![], ty("u32"), vec![Statement::Return(int(7))]),
+            async_fn("main", vec![], unit(), vec![
+                let_await(
+                    "level",
+                    TypeRef::Named { name: "Timeout".into(), type_params: vec![ty("u32")] },
+                    call("timeout", vec![int(100), call("measure", vec![])]),
+                ),
+            ]),
+        ]);
+        assert!(output.source.contains("    this->result = 7;"), "{}", output.source);
+        assert!(output.source.contains("    this->locals.level.status = TimedOut;"), "{}", output.source);
+        assert!(output.source.contains("    this->locals.level.value = this->nested_tasks."), "{}", output.source);
+    }
+
+    #[test]
+    fn boxed_tasks_hand_on_their_result_and_report_a_full_pool() {
+        let output = compile(vec![
+            async_fn("measure", vec![], ty("u32"), vec![Statement::Return(int(7))]),
+            async_fn("main", vec![], unit(), vec![
+                let_await("level", ty("u32"), Expression::Boxed {
+                    task: Box::new(call("measure", vec![])),
+                    slots: Box::new(int(2)),
+                }),
+            ]),
+        ]);
+        assert!(output.source.contains("        box_failed(\"measure\");\n"), "{}", output.source);
+        assert!(output.source.contains("        Task_cancel(&this->core);\n"), "{}", output.source);
+        assert!(output.source.contains("spawn_failed") == false, "{}", output.source);
+        assert!(output.source.contains("    this->locals.level = "), "{}", output.source);
+    }
+
+    #[test]
+    fn pool_slots_are_claimed_and_released_in_critical_sections() {
+        let output = compile(vec![
+            async_fn("blink", vec![], unit(), vec![]),
+            plain_fn("init", vec![], unit(), vec![Statement::Spawn { task: call("blink", vec![]), priority: None }]),
+            async_fn("main", vec![], unit(), vec![Statement::Spawn { task: call("blink", vec![]), priority: None }]),
+        ]);
+        let release = output.function("pool_blink_release");
+        assert!(release.contains("    CRITICAL_ENTER();\n    pool_blink.used[task - pool_blink.tasks] = false;\n    CRITICAL_EXIT();"), "{}", release);
+        let acquire = output.function("pool_blink_acquire");
+        assert!(acquire.contains("    CRITICAL_ENTER();\n    for (size_t i = 1; i < 2; i++) {"), "{}", acquire);
+        assert!(acquire.contains("            CRITICAL_EXIT();\n            return &pool_blink.tasks[i];"), "{}", acquire);
+        assert!(acquire.contains("    CRITICAL_EXIT();\n    return NULL;"), "{}", acquire);
+    }
+
+    #[test]
+    fn continuations_carry_the_priority_of_their_task() {
+        let output = compile(vec![
+            TopLevelNode::FnDecl {
+                name: "motor".into(), params: vec![], returns: unit(), body: vec![],
+                async: true, public: false, priority: Some(3), generator: false,
+            },
+            async_fn("log", vec![], unit(), vec![]),
+            plain_fn("init", vec![], unit(), vec![
+                Statement::Spawn { task: call("motor", vec![]), priority: None },
+                Statement::Spawn { task: call("log", vec![]), priority: Some(1) },
+            ]),
+        ]);
+        assert!(output.function("motor_start").contains("    state->core.priority = 3;"), "{}", output.source);
+        assert!(output.function("log_start").contains("    state->core.priority = on_done.priority;"), "{}", output.source);
+        let init = output.function("init");
+        assert!(init.contains("(TaskFn)pool_motor_release, &pool_motor.tasks[0], 3 })"), "{}", output.source);
+        assert!(init.contains("(TaskFn)pool_log_release, &pool_log.tasks[0], 1 })"), "{}", output.source);
+        assert!(output.header.contains("#if PRIORITY_LEVELS <= 3\n"), "{}", output.header);
+    }
+
+    #[test]
+    fn generators_yield_into_for_await_loops() {
+        let output = compile(vec![
+            TopLevelNode::GlobalDecl(var("total", ty("u32"))),
+            TopLevelNode::FnDecl {
+                name: "samples".into(), params: vec![], returns: ty("u32"),
+                body: vec![Statement::Yield(int(1)), Statement::Yield(int(2))],
+                async: true, public: false, priority: None, generator: true,
+            },
+            async_fn("main", vec![], unit(), vec![
+                Statement::ForAwait {
+                    var: var("sample", ty("u32")),
+                    stream: call("samples", vec![]),
+                    body: vec![Statement::Assignment { target: ident("total"), expr: add(ident("total"), ident("sample")) }],
+                },
+            ]),
+        ]);
+        assert!(output.header.contains("    uint32_t item;\n    Continuation next;\n    bool finished;\n"), "{}", output.header);
+        let first = output.function("task_samples1");
+        assert!(first.contains("    this->item = 1;\n    Continuation_init(&this->next, (TaskFn)task_samples2, this, this->core.priority);"), "{}", output.source);
+        assert!(output.function("task_samples4").contains("    this->finished = true;"), "{}", output.source);
+        let next = output.function("for_main0_next");
+        assert!(next.contains("    if (this->streams.stream0.finished) {\n        task_main4(this);\n        return;\n    }"), "{}", output.source);
+        assert!(next.contains("    this->locals.sample = this->streams.stream0.item;\n    task_main3(this);"), "{}", output.source);
+        assert!(output.function("task_main3").contains("    Continuation_schedule(this->streams.stream0.next);"), "{}", output.source);
+        assert!(output.function("cancel_main").contains("    Task_cancel(&this->streams.stream0.core);"), "{}", output.source);
+    }
+
+    #[test]
+    fn suspending_functions_hand_out_the_continuation_of_their_caller() {
+        let output = compile(vec![
+            TopLevelNode::GlobalDecl(var("ready", ty("Continuation"))),
+            extern_fn("start_timer", vec![var("ms", ty("u32"))], unit(), false),
+            plain_fn("delay", vec![var("ms", ty("u32"))], unit(), vec![
+                Statement::Suspend { continuation: "k".into(), body: vec![
+                    Statement::Assignment { target: ident("ready"), expr: ident("k") },
+                    call_statement("start_timer", vec![ident("ms")]),
+                ] },
+            ]),
+            async_fn("main", vec![], unit(), vec![Statement::Await(call("delay", vec![int(10)]))]),
+        ]);
+        let delay = output.function("delay");
+        assert!(delay.starts_with("void delay(Continuation k, uint32_t ms) {\n    globals.ready = k;\n    start_timer(ms);"), "{}", output.source);
+        let awaits = output.function("task_main1");
+        assert!(awaits.contains("    Continuation_init(&resume, (TaskFn)task_main2, this, this->core.priority);"), "{}", output.source);
+        assert!(awaits.contains("    delay(resume, 10);"), "{}", output.source);
+    }
+
+    #[test]
+    fn semaphores_start_with_their_declared_count() {
+        let output = compile(vec![
+            TopLevelNode::GlobalDecl(var("permits", TypeRef::Semaphore { initial: Box::new(int(3)) })),
+            TopLevelNode::GlobalDecl(var("ready", TypeRef::Semaphore { initial: Box::new(int(0)) })),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Await(method_call("permits", "acquire", vec![])),
+                Statement::Await(method_call("ready", "acquire", vec![])),
+            ]),
+        ]);
+        assert!(output.source.contains("} globals = {\n    .permits.count = 3,\n};"), "{}", output.source);
+
+        let errors = errors(vec![
+            TopLevelNode::GlobalDecl(var("ready", ty("Semaphore"))),
+            TopLevelNode::GlobalDecl(var("huge", TypeRef::Semaphore { initial: Box::new(int(70000)) })),
+        ]);
+        assert!(mentions(&errors, "semaphore `ready` must give the count it starts with"), "{:?}", errors);
+        assert!(mentions(&errors, "invalid constant in the type of `huge`: a semaphore counts to at most 65535"), "{:?}", errors);
+    }
+
+    #[test]
+    fn selects_in_loops_cancel_the_arms_that_lose_each_pass() {
+        let output = compile(vec![
+            async_fn("h", vec![], ty("u32"), vec![Statement::Return(int(1))]),
+            async_fn("k", vec![], ty("u32"), vec![Statement::Return(int(2))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Loop(vec![
+                    Statement::Select(vec![
+                        SelectArm { binding: None, task: call("h", vec![]), body: vec![] },
+                        SelectArm { binding: None, task: call("k", vec![]), body: vec![] },
+                    ]),
+                ]),
+            ]),
+        ]);
+        let select = output.function("task_main4");
+        assert!(select.contains("    this->nested_tasks.select0.winner = 0;"), "{}", output.source);
+        let first = output.function("select_main0_arm0");
+        assert!(first.contains("    Continuation_init(&resume, (TaskFn)task_main4, this, this->core.priority);"), "{}", output.source);
+        assert!(first.contains("    Continuation_schedule(resume);"), "{}", output.source);
+        assert!(first.contains("    Task_cancel(&this->nested_tasks.select0.task1.core);"), "{}", output.source);
+        let second = output.function("select_main0_arm1");
+        assert!(second.contains("    Task_cancel(&this->nested_tasks.select0.task0.core);"), "{}", output.source);
+    }
+
+    #[test]
+    fn sleeps_raced_or_joined_get_a_timer_of_their_own() {
+        let output = compile(vec![
+            async_fn("read", vec![], ty("u32"), vec![Statement::Return(int(1))]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Select(vec![
+                    SelectArm { binding: None, task: call("read", vec![]), body: vec![] },
+                    SelectArm { binding: None, task: call("sleep_ms", vec![int(100)]), body: vec![] },
+                ]),
+                Statement::Await(Expression::Join { tasks: vec![call("read", vec![]), call("sleep_ms", vec![int(5)])] }),
+            ]),
+        ]);
+        assert!(output.header.contains("            Timer task1;"), "{}", output.header);
+        assert!(output.source.contains("    Timer_sleep(&this->nested_tasks.select0.task1, 100, "), "{}", output.source);
+        assert!(output.source.contains("    Timer_sleep(&this->nested_tasks.join0.task1, 5, "), "{}", output.source);
+        let first = output.function("select_main0_arm0");
+        assert!(first.contains("    Timer_cancel(&this->nested_tasks.select0.task1);"), "{}", output.source);
+        let second = output.function("select_main0_arm1");
+        assert!(second.contains("    Task_cancel(&this->nested_tasks.select0.task0.core);"), "{}", output.source);
+    }
+
+    #[test]
+    fn functions_called_from_deferred_interrupts_schedule_what_they_resume() {
+        let mut options = Options::default();
+        options.dispatch.insert("uart_rx".into(), Dispatch::Deferred);
+        let output = compile_with(vec![
+            TopLevelNode::GlobalDecl(var("ready", ty("Continuation"))),
+            plain_fn("wake", vec![], unit(), vec![Statement::Await(ident("ready"))]),
+            plain_fn("notify", vec![], unit(), vec![call_statement("wake", vec![])]),
+            plain_fn("poke", vec![], unit(), vec![Statement::Await(ident("ready"))]),
+            TopLevelNode::InterruptDecl { name: "uart_rx".into(), body: vec![call_statement("notify", vec![])] },
+        ], &options);
+        let wake = output.function("wake");
+        assert!(wake.contains("    Continuation_schedule("), "{}", output.source);
+        let poke = output.function("poke");
+        assert!(poke.contains("    Continuation_invoke("), "{}", output.source);
+    }
+
+    #[test]
+    fn cancelling_a_task_cancels_what_it_awaits_and_runs_its_defers() {
+        let output = compile(vec![
+            TopLevelNode::GlobalDecl(var("count", ty("u32"))),
+            async_fn("poll", vec![], unit(), vec![]),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Defer(vec![Statement::Assignment { target: ident("count"), expr: int(0) }]),
+                Statement::Await(call("poll", vec![])),
+            ]),
+        ]);
+        let cancel = output.function("cancel_main_poll");
+        assert!(cancel.contains("    Task_cancel(&this->nested_tasks.poll.core);\n    cancel_main(this);"), "{}", output.source);
+        let cleanup = output.function("cancel_main");
+        assert!(cleanup.contains("    if (this->deferred > 0) {\n        defer_main0(this);\n    }"), "{}", output.source);
+        let awaits = output.function("task_main1");
+        assert!(awaits.contains("    this->deferred = 1;"), "{}", output.source);
+        assert!(awaits.contains("    this->core.cancel = (TaskFn)cancel_main_poll;"), "{}", output.source);
+        let resumed = output.function("task_main2");
+        assert!(resumed.contains("    if (this->core.cancelled) {\n        return;\n    }"), "{}", output.source);
+    }
+
+    #[test]
+    fn loops_run_each_further_pass_from_the_ready_queue() {
+        let output = compile(vec![
+            TopLevelNode::GlobalDecl(var("count", ty("u32"))),
+            async_fn("main", vec![], unit(), vec![
+                Statement::Loop(vec![
+                    Statement::Assignment { target: ident("count"), expr: add(ident("count"), int(1)) },
+                ]),
+            ]),
+        ]);
+        let pass = output.function("task_main4");
+        assert!(pass.contains("    if (this->core.cancelled) {"), "{}", output.source);
+        assert!(pass.contains("    Continuation_init(&resume, (TaskFn)task_main4, this, this->core.priority);"), "{}", output.source);
+        assert!(pass.contains("    Continuation_schedule(resume);"), "{}", output.source);
+    }
+}
@@ -0,0 +1,101 @@
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+use super::super::check::{closures, spawns};
+use super::super::check::consteval::array_length;
+use super::super::check::symbols::SymbolTable;
+use super::super::module::Module;
+
+/// The statically allocated slots of one spawned task. Spawns that run
+/// exactly once each own a fixed slot, and every other `spawn` statement adds
+/// a slot that is claimed at runtime and released when the task completes.
+/// Each `box(n)` await of the task adds `n` such slots.
+pub struct TaskPool<'a> {
+    /// The C name of the spawned task.
+    pub c_name: String,
+    pub fixed: Vec<&'a Statement>,
+    pub dynamic: usize,
+}
+
+impl<'a> TaskPool<'a> {
+    pub fn size(&self) -> usize {
+        self.fixed.len() + self.dynamic
+    }
+
+    pub fn type_name(&self) -> String {
+        format!("TaskState_{}", self.c_name)
+    }
+
+    pub fn var_name(&self) -> String {
+        format!("pool_{}", self.c_name)
+    }
+
+    pub fn acquire_fn_name(&self) -> String {
+        format!("pool_{}_acquire", self.c_name)
+    }
+
+    pub fn release_fn_name(&self) -> String {
+        format!("pool_{}_release", self.c_name)
+    }
+
+    /// The fixed slot of a spawn that runs exactly once.
+    pub fn fixed_slot(&self, statement: &Statement) -> Option<usize> {
+        self.fixed.iter().position(|spawn| ::std::ptr::eq(*spawn, statement))
+    }
+}
+
+/// Sizes a pool for every task spawned or awaited with `box` in a module,
+/// including spawns inside closures.
+pub fn collect<'a>(module: &'a Module, symbols: &SymbolTable<'a>) -> Vec<TaskPool<'a>> {
+    let once = spawns::static_spawns(module);
+    let mut result: Vec<TaskPool> = Vec::new();
+
+    for node in module.nodes.iter() {
+        let body = match node {
+            &TopLevelNode::FnDecl { ref body, .. } | &TopLevelNode::InterruptDecl { ref body, .. } => body,
+            _ => continue,
+        };
+
+        let mut bodies = vec![&body[..]];
+        for closure in closures::closures(body) {
+            if let &Expression::Closure { ref body } = closure {
+                bodies.push(body);
+            }
+        }
+
+        for body in bodies {
+            walk::statements(body, &mut |statement| {
+                let (target, slots) = match (statement, walk::awaited(statement)) {
+                    (&Statement::Spawn { task: Expression::FnCall { ref target, .. }, .. }, _) => (target, None),
+                    (_, Some(&Expression::Boxed { ref task, ref slots })) => match **task {
+                        Expression::FnCall { ref target, .. } => (target, Some(slots)),
+                        _ => return,
+                    },
+                    _ => return,
+                };
+                let c_name = match symbols.function(target) {
+                    Some(callee) => callee.c_name.clone(),
+                    None => return,
+                };
+
+                let index = match result.iter().position(|pool| pool.c_name == c_name) {
+                    Some(index) => index,
+                    None => {
+                        result.push(TaskPool { c_name, fixed: vec![], dynamic: 0 });
+                        result.len() - 1
+                    }
+                };
+
+                if let Some(slots) = slots {
+                    let slots = array_length(slots, symbols).expect("`box` slots are checked before code generation");
+                    result[index].dynamic += slots as usize;
+                } else if once.iter().any(|spawn| ::std::ptr::eq(*spawn, statement)) {
+                    result[index].fixed.push(statement);
+                } else {
+                    result[index].dynamic += 1;
+                }
+            });
+        }
+    }
+
+    result
+}
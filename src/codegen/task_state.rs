@@ -0,0 +1,819 @@
+use std::collections::HashSet;
+use std::io::{Error, Write};
+
+use super::closures::{self, Closure};
+use super::ctypes::{c_declaration, is_unit};
+use super::layout::{Layout, Layouts};
+use super::super::ast::nodes::*;
+use super::super::ast::walk;
+use super::super::cfg::{builder::Builder, interference::Interference, liveness::{self, Liveness}};
+use super::super::check::{channels, locks, timers};
+use super::super::check::symbols::SymbolTable;
+use super::super::module::Module;
+
+/// The member of the `nested_tasks` union holding the timer of a sleeping
+/// task.
+pub const TIMER_MEMBER: &str = "timer";
+
+/// The statically allocated state of one async function: the runtime core,
+/// a union holding the state of whichever callee is currently being awaited,
+/// and the locals that must survive across suspension points.
+pub struct TaskState<'a> {
+    pub name: &'a str,
+    pub c_name: String,
+    pub public: bool,
+    /// The declared priority, without which the task inherits the priority
+    /// of the continuation it completes into.
+    pub priority: Option<u8>,
+    /// Whether the function is an `async gen`. Instead of a result, its state
+    /// holds the latest value it yielded and the continuation that resumes it.
+    pub generator: bool,
+    pub params: &'a [VarDecl],
+    pub returns: &'a TypeRef,
+    pub body: &'a [Statement],
+    /// The C names of the tasks awaited by this one, which may belong to
+    /// other modules.
+    pub nested_tasks: Vec<String>,
+    /// The parameters and `let` bindings that must survive suspension
+    /// points. Other locals are C locals of the block function using them.
+    pub locals: Vec<&'a VarDecl>,
+    /// The stored locals grouped by the storage they share. The locals of a
+    /// slot are never live at the same time, and overlay each other in an
+    /// anonymous union.
+    pub slots: Vec<Vec<&'a VarDecl>>,
+    pub closures: Vec<Closure<'a>>,
+    pub joins: Vec<Join<'a>>,
+    pub selects: Vec<Select<'a>>,
+    /// The `defer` statements of the task, which all sit at the top level of
+    /// its body, in source order.
+    pub defers: Vec<&'a Statement>,
+    /// The global channels the task awaits `send` or `recv` on. While it
+    /// waits, the task is parked in the channel through a slot of the
+    /// `nested_tasks` union.
+    pub channels: Vec<&'a VarDecl>,
+    /// The global mutexes and semaphores the task awaits. A waiting task is
+    /// linked into the lock's wait list through its slot of `nested_tasks`.
+    pub locks: Vec<&'a VarDecl>,
+    /// Whether the task awaits `sleep_ms`, which links a timer in its
+    /// `nested_tasks` union into the runtime's timer list.
+    pub sleeps: bool,
+    pub timeouts: Vec<Timeout<'a>>,
+    /// The calls awaited as `box(n) f(...)`, whose states come from a pool.
+    /// Only a pointer to the state is kept in the `nested_tasks` union.
+    pub boxes: Vec<Boxed<'a>>,
+    /// The generators consumed by `for await` loops. Their states are kept
+    /// outside the `nested_tasks` union, since the body of the loop awaits
+    /// other things while the generator is suspended.
+    pub streams: Vec<Stream<'a>>,
+}
+
+/// What a child of a `join` or `select` keeps in its slot.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Child {
+    /// The state of a task of this program, by its C name.
+    Task(String),
+    /// The timer of a `sleep_ms`.
+    Timer,
+}
+
+/// A `join` awaited by a task. Its children run concurrently, so unlike other
+/// awaited tasks each needs its own slot, kept alongside a count of the
+/// children that have yet to complete.
+pub struct Join<'a> {
+    pub expr: &'a Expression,
+    pub index: usize,
+    /// What each child keeps in its slot, or `None` for C functions that
+    /// keep their own state.
+    pub children: Vec<Option<Child>>,
+}
+
+impl<'a> Join<'a> {
+    pub fn member_name(&self) -> String {
+        format!("join{}", self.index)
+    }
+
+    pub fn child_member_name(&self, child: usize) -> String {
+        format!("task{}", child)
+    }
+}
+
+/// A `select` in a task. Like a `join` its arms run concurrently in slots of
+/// their own, next to the number of the arm that completed first, or zero
+/// while none has.
+pub struct Select<'a> {
+    pub statement: &'a Statement,
+    pub arms: &'a [SelectArm],
+    pub index: usize,
+    /// What each arm keeps in its slot, or `None` for C functions that keep
+    /// their own state.
+    pub children: Vec<Option<Child>>,
+}
+
+impl<'a> Select<'a> {
+    pub fn member_name(&self) -> String {
+        format!("select{}", self.index)
+    }
+
+    pub fn child_member_name(&self, arm: usize) -> String {
+        format!("task{}", arm)
+    }
+}
+
+/// A `timeout` awaited by a task. The timer and the task it races against
+/// share a slot, together with a flag set by whichever finishes first.
+pub struct Timeout<'a> {
+    pub expr: &'a Expression,
+    pub index: usize,
+    /// The C name of the task given the timeout.
+    pub task: String,
+}
+
+impl<'a> Timeout<'a> {
+    pub fn member_name(&self) -> String {
+        format!("timeout{}", self.index)
+    }
+}
+
+/// A call awaited as `box(n) f(...)`.
+pub struct Boxed<'a> {
+    pub expr: &'a Expression,
+    pub index: usize,
+    /// The C name of the task whose state is taken from a pool.
+    pub task: String,
+}
+
+impl<'a> Boxed<'a> {
+    pub fn member_name(&self) -> String {
+        format!("box{}", self.index)
+    }
+}
+
+/// A `for await` loop in a task, and the generator it consumes.
+pub struct Stream<'a> {
+    pub statement: &'a Statement,
+    pub index: usize,
+    /// The C name of the generator.
+    pub task: String,
+}
+
+impl<'a> Stream<'a> {
+    pub fn member_name(&self) -> String {
+        format!("stream{}", self.index)
+    }
+}
+
+impl<'a> TaskState<'a> {
+    pub fn type_name(&self) -> String {
+        format!("TaskState_{}", self.c_name)
+    }
+
+    pub fn has_local(&self, name: &str) -> bool {
+        self.locals.iter().any(|local| local.name == name)
+    }
+
+    pub fn join(&self, expr: &Expression) -> Option<&Join<'a>> {
+        self.joins.iter().find(|join| ::std::ptr::eq(join.expr, expr))
+    }
+
+    pub fn timeout(&self, expr: &Expression) -> Option<&Timeout<'a>> {
+        self.timeouts.iter().find(|timeout| ::std::ptr::eq(timeout.expr, expr))
+    }
+
+    pub fn boxed(&self, expr: &Expression) -> Option<&Boxed<'a>> {
+        self.boxes.iter().find(|boxed| ::std::ptr::eq(boxed.expr, expr))
+    }
+
+    pub fn stream(&self, statement: &Statement) -> Option<&Stream<'a>> {
+        self.streams.iter().find(|stream| ::std::ptr::eq(stream.statement, statement))
+    }
+
+    /// The position of a `defer` among those of the task.
+    pub fn defer_index(&self, statement: &Statement) -> Option<usize> {
+        self.defers.iter().position(|defer| ::std::ptr::eq(*defer, statement))
+    }
+
+    pub fn select(&self, statement: &Statement) -> Option<&Select<'a>> {
+        self.selects.iter().find(|select| ::std::ptr::eq(select.statement, statement))
+    }
+
+    pub fn channel_member_name(&self, channel: &VarDecl) -> String {
+        format!("channel_{}", channel.name)
+    }
+
+    pub fn lock_member_name(&self, lock: &VarDecl) -> String {
+        format!("lock_{}", lock.name)
+    }
+
+    /// Every task whose state is embedded in this one.
+    pub fn dependencies(&self) -> Vec<&str> {
+        let joined = self.joins.iter()
+            .flat_map(|join| join.children.iter())
+            .chain(self.selects.iter().flat_map(|select| select.children.iter()))
+            .filter_map(|child| match child {
+                &Some(Child::Task(ref task)) => Some(task),
+                _ => None,
+            });
+
+        self.nested_tasks.iter()
+            .chain(joined)
+            .chain(self.timeouts.iter().map(|timeout| &timeout.task))
+            .chain(self.streams.iter().map(|stream| &stream.task))
+            .map(|name| name.as_str())
+            .collect()
+    }
+
+    pub fn write_typedef(&self, w: &mut Write, symbols: &SymbolTable) -> Result<(), Error> {
+        for closure in self.closures.iter().filter(|c| c.has_context()) {
+            closure.write_context_typedef(w, symbols)?;
+            writeln!(w)?;
+        }
+
+        writeln!(w, "typedef struct _{} {{", self.type_name())?;
+        writeln!(w, "    TaskStateCore core;")?;
+        if self.defers.is_empty() == false {
+            writeln!(w, "    unsigned deferred;")?;
+        }
+
+        let has_nested = self.nested_tasks.is_empty() == false || self.joins.is_empty() == false
+            || self.selects.is_empty() == false || self.channels.is_empty() == false
+            || self.locks.is_empty() == false || self.sleeps || self.timeouts.is_empty() == false
+            || self.boxes.is_empty() == false;
+        if has_nested {
+            writeln!(w, "    union {{")?;
+            for nested in self.nested_tasks.iter() {
+                writeln!(w, "        TaskState_{} {};", nested, nested)?;
+            }
+            for join in self.joins.iter() {
+                writeln!(w, "        struct {{")?;
+                for (i, child) in join.children.iter().enumerate() {
+                    write_child_member(w, child, &join.child_member_name(i))?;
+                }
+                writeln!(w, "            unsigned pending;")?;
+                writeln!(w, "        }} {};", join.member_name())?;
+            }
+            for select in self.selects.iter() {
+                writeln!(w, "        struct {{")?;
+                for (i, child) in select.children.iter().enumerate() {
+                    write_child_member(w, child, &select.child_member_name(i))?;
+                }
+                writeln!(w, "            unsigned winner;")?;
+                writeln!(w, "        }} {};", select.member_name())?;
+            }
+            for channel in self.channels.iter() {
+                let element = match channel.type_ref {
+                    TypeRef::Channel { ref element, .. } => element,
+                    _ => unreachable!(),
+                };
+                writeln!(w, "        struct {{")?;
+                writeln!(w, "            ChannelWaiter waiter;")?;
+                writeln!(w, "            {};", c_declaration(element, "value", symbols))?;
+                writeln!(w, "        }} {};", self.channel_member_name(channel))?;
+            }
+            for lock in self.locks.iter() {
+                writeln!(w, "        Waiter {};", self.lock_member_name(lock))?;
+            }
+            if self.sleeps {
+                writeln!(w, "        Timer {};", TIMER_MEMBER)?;
+            }
+            for timeout in self.timeouts.iter() {
+                writeln!(w, "        struct {{")?;
+                writeln!(w, "            TaskState_{} task;", timeout.task)?;
+                writeln!(w, "            Timer timer;")?;
+                writeln!(w, "            bool finished;")?;
+                writeln!(w, "        }} {};", timeout.member_name())?;
+            }
+            for boxed in self.boxes.iter() {
+                writeln!(w, "        struct _TaskState_{} *{};", boxed.task, boxed.member_name())?;
+            }
+            writeln!(w, "    }} nested_tasks;")?;
+        }
+
+        if self.slots.is_empty() == false {
+            writeln!(w, "    struct {{")?;
+            for slot in self.slots.iter() {
+                if slot.len() == 1 {
+                    writeln!(w, "        {};", c_declaration(&slot[0].type_ref, &slot[0].name, symbols))?;
+                    continue;
+                }
+                writeln!(w, "        union {{")?;
+                for local in slot.iter() {
+                    writeln!(w, "            {};", c_declaration(&local.type_ref, &local.name, symbols))?;
+                }
+                writeln!(w, "        }};")?;
+            }
+            writeln!(w, "    }} locals;")?;
+        }
+
+        if self.streams.is_empty() == false {
+            writeln!(w, "    struct {{")?;
+            for stream in self.streams.iter() {
+                writeln!(w, "        TaskState_{} {};", stream.task, stream.member_name())?;
+            }
+            writeln!(w, "    }} streams;")?;
+        }
+
+        if self.closures.iter().any(|c| c.has_context()) {
+            writeln!(w, "    struct {{")?;
+            for closure in self.closures.iter().filter(|c| c.has_context()) {
+                writeln!(w, "        {} {};", closure.context_type_name(), closure.member_name())?;
+            }
+            writeln!(w, "    }} closures;")?;
+        }
+
+        if self.generator {
+            writeln!(w, "    {};", c_declaration(self.returns, "item", symbols))?;
+            writeln!(w, "    Continuation next;")?;
+            writeln!(w, "    bool finished;")?;
+        } else if is_unit(self.returns) == false {
+            writeln!(w, "    {};", c_declaration(self.returns, "result", symbols))?;
+        }
+
+        writeln!(w, "}} {};", self.type_name())
+    }
+
+    /// The layout of the struct holding the stored locals, either with the
+    /// locals of each slot overlaid or, to tell what that saves, one after
+    /// another in source order.
+    pub fn locals_layout(&self, layouts: &mut Layouts, symbols: &SymbolTable, overlay: bool) -> Layout {
+        if overlay == false {
+            let locals = self.locals.iter()
+                .map(|local| layouts.type_layout(&local.type_ref, symbols))
+                .collect::<Vec<_>>();
+            return Layout::structure(&locals);
+        }
+
+        let slots = self.slots.iter()
+            .map(|slot| {
+                let locals = slot.iter()
+                    .map(|local| layouts.type_layout(&local.type_ref, symbols))
+                    .collect::<Vec<_>>();
+                Layout::union(&locals)
+            })
+            .collect::<Vec<_>>();
+        Layout::structure(&slots)
+    }
+
+    /// The layout of the struct `write_typedef` declares. The states of the
+    /// tasks it embeds must already be in `layouts`.
+    pub fn layout(&self, layouts: &mut Layouts, symbols: &SymbolTable) -> Layout {
+        let mut members = vec![layouts.task_state_core()];
+        if self.defers.is_empty() == false {
+            members.push(layouts.int());
+        }
+
+        let mut nested = Vec::new();
+        for task in self.nested_tasks.iter() {
+            nested.push(layouts.task_state(task));
+        }
+        for join in self.joins.iter() {
+            let mut children = join.children.iter()
+                .filter_map(|child| child.as_ref())
+                .map(|child| child_layout(layouts, child))
+                .collect::<Vec<_>>();
+            children.push(layouts.int());
+            nested.push(Layout::structure(&children));
+        }
+        for select in self.selects.iter() {
+            let mut children = select.children.iter()
+                .filter_map(|child| child.as_ref())
+                .map(|child| child_layout(layouts, child))
+                .collect::<Vec<_>>();
+            children.push(layouts.int());
+            nested.push(Layout::structure(&children));
+        }
+        for channel in self.channels.iter() {
+            let element = match channel.type_ref {
+                TypeRef::Channel { ref element, .. } => element,
+                _ => unreachable!(),
+            };
+            let value = layouts.type_layout(element, symbols);
+            nested.push(Layout::structure(&[layouts.channel_waiter(), value]));
+        }
+        for _ in self.locks.iter() {
+            nested.push(layouts.waiter());
+        }
+        if self.sleeps {
+            nested.push(layouts.timer());
+        }
+        for timeout in self.timeouts.iter() {
+            let task = layouts.task_state(&timeout.task);
+            nested.push(Layout::structure(&[task, layouts.timer(), layouts.scalar(1)]));
+        }
+        for _ in self.boxes.iter() {
+            nested.push(layouts.pointer());
+        }
+        if nested.is_empty() == false {
+            members.push(Layout::union(&nested));
+        }
+
+        if self.slots.is_empty() == false {
+            members.push(self.locals_layout(layouts, symbols, true));
+        }
+
+        if self.streams.is_empty() == false {
+            let streams = self.streams.iter()
+                .map(|stream| layouts.task_state(&stream.task))
+                .collect::<Vec<_>>();
+            members.push(Layout::structure(&streams));
+        }
+
+        if self.closures.iter().any(|c| c.has_context()) {
+            let contexts = self.closures.iter()
+                .filter(|c| c.has_context())
+                .map(|c| layouts.pointer().array(c.captures.len()))
+                .collect::<Vec<_>>();
+            members.push(Layout::structure(&contexts));
+        }
+
+        if self.generator {
+            members.push(layouts.type_layout(self.returns, symbols));
+            members.push(layouts.continuation());
+            members.push(layouts.scalar(1));
+        } else if is_unit(self.returns) == false {
+            members.push(layouts.type_layout(self.returns, symbols));
+        }
+
+        Layout::structure(&members)
+    }
+}
+
+/// Collects the state of every async function, ordered so that each state
+/// appears after the states of the callees it embeds.
+pub fn collect<'a>(module: &'a Module, symbols: &SymbolTable<'a>) -> Vec<TaskState<'a>> {
+    let mut unordered = Vec::new();
+
+    for node in module.nodes.iter() {
+        if let &TopLevelNode::FnDecl { ref name, ref params, ref returns, ref body, async: true, public, priority, generator } = node {
+            let c_name = module.mangle(name);
+            let mut locals = params.iter().collect::<Vec<_>>();
+            locals.extend(walk::let_bindings(body));
+            let closures = closures::collect(&c_name, &locals, body);
+            let slots = stored_locals(name, body, &locals, &closures);
+            locals.retain(|local| slots.iter().any(|slot| slot.iter().any(|other| ::std::ptr::eq(*other, *local))));
+
+            unordered.push(TaskState {
+                name,
+                public,
+                priority,
+                generator,
+                params,
+                returns,
+                body,
+                nested_tasks: awaited_tasks(body, symbols),
+                closures,
+                joins: joins(body, symbols),
+                selects: selects(body, symbols),
+                defers: body.iter()
+                    .filter(|statement| match statement {
+                        &&Statement::Defer(..) => true,
+                        _ => false,
+                    })
+                    .collect(),
+                channels: awaited_channels(body, symbols),
+                locks: awaited_locks(body, symbols),
+                sleeps: sleeps(body, symbols),
+                timeouts: timeouts(body, symbols),
+                boxes: boxes(body, symbols),
+                streams: streams(body, symbols),
+                locals,
+                slots,
+                c_name,
+            });
+        }
+    }
+
+    let mut ordered = Vec::new();
+    let mut visited = HashSet::new();
+    while let Some(state) = unordered.pop() {
+        order_state(state, &mut unordered, &mut ordered, &mut visited);
+    }
+    ordered
+}
+
+fn order_state<'a>(
+    state: TaskState<'a>,
+    unordered: &mut Vec<TaskState<'a>>,
+    ordered: &mut Vec<TaskState<'a>>,
+    visited: &mut HashSet<String>,
+) {
+    visited.insert(state.c_name.clone());
+
+    for nested in state.dependencies() {
+        if visited.contains(nested) {
+            continue;
+        }
+        if let Some(position) = unordered.iter().position(|s| s.c_name == nested) {
+            let nested_state = unordered.remove(position);
+            order_state(nested_state, unordered, ordered, visited);
+        }
+    }
+
+    ordered.push(state);
+}
+
+/// The locals a task keeps in its state, grouped into slots. Each block of
+/// the task is a C function of its own, so these are the locals live on
+/// entry to any block, those stored by awaits, and those assigned in more
+/// than one block, which no single C function can declare. Not only awaits
+/// suspend: yields, `select` arms, `for await` loops and further loop passes
+/// all resume the task in a new C function, so every block boundary counts.
+///
+/// Locals that are never live at the same time share a slot, which is
+/// found by greedily colouring the interference graph in source order.
+/// Locals captured by closures, bound by `select` arms and `for await` loops
+/// or used by `defer` blocks are reached outside the control flow graph, so
+/// each keeps a slot of its own.
+fn stored_locals<'a>(
+    name: &'a str, body: &'a [Statement], locals: &[&'a VarDecl], closures: &[Closure<'a>],
+) -> Vec<Vec<&'a VarDecl>> {
+    let mut cfg = Builder::new(name).build(body);
+    cfg.tidy_graph();
+    let liveness = Liveness::analyze(&cfg);
+    let interference = Interference::analyze(&cfg, &liveness);
+
+    let mut stored = cfg.graph.node_indices()
+        .flat_map(|idx| liveness.live_in(idx).iter().cloned())
+        .collect::<HashSet<_>>();
+    let mut assigned = HashSet::new();
+    for idx in cfg.graph.node_indices() {
+        let mut uses = HashSet::new();
+        let mut defs = HashSet::new();
+        for statement in cfg.graph[idx].statements.iter() {
+            liveness::transfer(statement, &mut uses, &mut defs);
+        }
+        for def in defs {
+            if assigned.insert(def) == false {
+                stored.insert(def);
+            }
+        }
+    }
+    let mut pinned = closures.iter()
+        .flat_map(|c| c.captures.iter())
+        .map(|local| local.name.as_str())
+        .collect::<HashSet<_>>();
+
+    walk::statements(body, &mut |statement| {
+        match statement {
+            &Statement::LetAwait { ref var, .. } => {
+                stored.insert(&var.name);
+            }
+            &Statement::ForAwait { ref var, .. } => {
+                pinned.insert(&var.name);
+            }
+            &Statement::Select(ref arms) => {
+                pinned.extend(arms.iter().filter_map(|arm| arm.binding.as_ref()).map(|var| var.name.as_str()));
+            }
+            &Statement::Defer(ref nested) => walk::expressions(nested, &mut |expr| {
+                if let &Expression::Identifier(ref name) = expr {
+                    pinned.insert(name);
+                }
+            }),
+            _ => {}
+        }
+    });
+
+    let mut slots: Vec<Vec<&'a VarDecl>> = Vec::new();
+    for &local in locals.iter() {
+        let name = local.name.as_str();
+        if pinned.contains(name) {
+            slots.push(vec![local]);
+            continue;
+        }
+        if stored.contains(name) == false {
+            continue;
+        }
+
+        let free = slots.iter_mut().find(|slot| slot.iter().all(|other| {
+            pinned.contains(other.name.as_str()) == false && interference.interferes(name, &other.name) == false
+        }));
+        match free {
+            Some(slot) => slot.push(local),
+            None => slots.push(vec![local]),
+        }
+    }
+    slots
+}
+
+/// Finds the async functions awaited by a function body, each of which needs
+/// a slot in the `nested_tasks` union.
+pub fn awaited_tasks(body: &[Statement], symbols: &SymbolTable) -> Vec<String> {
+    let mut result = Vec::new();
+
+    walk::statements(body, &mut |statement| {
+        if let Some(&Expression::FnCall { ref target, .. }) = walk::awaited(statement) {
+            if let Some(f) = symbols.function(target) {
+                if f.async && f.external == false && result.contains(&f.c_name) == false {
+                    result.push(f.c_name.clone());
+                }
+            }
+        }
+    });
+
+    result
+}
+
+/// Finds the global channels a function body awaits `send` or `recv` on.
+fn awaited_channels<'a>(body: &'a [Statement], symbols: &SymbolTable<'a>) -> Vec<&'a VarDecl> {
+    let mut result: Vec<&'a VarDecl> = Vec::new();
+
+    walk::statements(body, &mut |statement| {
+        if let Some(call) = channels::awaited_channel_call(statement, symbols) {
+            if result.iter().any(|channel| ::std::ptr::eq(*channel, call.channel)) == false {
+                result.push(call.channel);
+            }
+        }
+    });
+
+    result
+}
+
+/// Finds the global mutexes and semaphores a function body awaits.
+fn awaited_locks<'a>(body: &'a [Statement], symbols: &SymbolTable<'a>) -> Vec<&'a VarDecl> {
+    let mut result: Vec<&'a VarDecl> = Vec::new();
+
+    walk::statements(body, &mut |statement| {
+        if let Some(call) = locks::awaited_lock_call(statement, symbols) {
+            if result.iter().any(|lock| ::std::ptr::eq(*lock, call.lock)) == false {
+                result.push(call.lock);
+            }
+        }
+    });
+
+    result
+}
+
+fn sleeps(body: &[Statement], symbols: &SymbolTable) -> bool {
+    let mut result = false;
+    walk::statements(body, &mut |statement| {
+        result = result || timers::awaits_sleep(statement, symbols);
+    });
+    result
+}
+
+fn timeouts<'a>(body: &'a [Statement], symbols: &SymbolTable<'a>) -> Vec<Timeout<'a>> {
+    let mut result = Vec::new();
+
+    walk::statements(body, &mut |statement| {
+        let expr = match walk::awaited(statement) {
+            Some(expr) => expr,
+            None => return,
+        };
+        if let Some((_, &Expression::FnCall { ref target, .. })) = timers::timeout_call(expr, symbols) {
+            let task = symbols.function(target).expect("timeouts are checked before code generation");
+            result.push(Timeout { expr, index: result.len(), task: task.c_name.clone() });
+        }
+    });
+
+    result
+}
+
+fn boxes<'a>(body: &'a [Statement], symbols: &SymbolTable<'a>) -> Vec<Boxed<'a>> {
+    let mut result = Vec::new();
+
+    walk::statements(body, &mut |statement| {
+        if let Some(expr @ &Expression::Boxed { .. }) = walk::awaited(statement) {
+            let target = match expr {
+                &Expression::Boxed { ref task, .. } => match **task {
+                    Expression::FnCall { ref target, .. } => target,
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            };
+            let task = symbols.function(target).expect("boxed calls are checked before code generation");
+            result.push(Boxed { expr, index: result.len(), task: task.c_name.clone() });
+        }
+    });
+
+    result
+}
+
+fn streams<'a>(body: &'a [Statement], symbols: &SymbolTable<'a>) -> Vec<Stream<'a>> {
+    let mut result = Vec::new();
+
+    walk::statements(body, &mut |statement| {
+        if let Some(&Expression::FnCall { ref target, .. }) = walk::streamed(statement) {
+            let task = symbols.function(target).expect("generators are checked before code generation");
+            result.push(Stream { statement, index: result.len(), task: task.c_name.clone() });
+        }
+    });
+
+    result
+}
+
+fn joins<'a>(body: &'a [Statement], symbols: &SymbolTable<'a>) -> Vec<Join<'a>> {
+    let mut result = Vec::new();
+
+    walk::statements(body, &mut |statement| {
+        if let Some(expr @ &Expression::Join { .. }) = walk::awaited(statement) {
+            let tasks = match expr {
+                &Expression::Join { ref tasks } => tasks,
+                _ => unreachable!(),
+            };
+
+            result.push(Join {
+                expr,
+                index: result.len(),
+                children: tasks.iter().map(|task| child(task, symbols)).collect(),
+            });
+        }
+    });
+
+    result
+}
+
+fn selects<'a>(body: &'a [Statement], symbols: &SymbolTable<'a>) -> Vec<Select<'a>> {
+    let mut result = Vec::new();
+
+    walk::statements(body, &mut |statement| {
+        if let &Statement::Select(ref arms) = statement {
+            result.push(Select {
+                statement,
+                arms,
+                index: result.len(),
+                children: arms.iter().map(|arm| child(&arm.task, symbols)).collect(),
+            });
+        }
+    });
+
+    result
+}
+
+/// What a child of a `join` or `select` keeps in its slot: the state of an
+/// async function of this program, or the timer of a `sleep_ms`.
+fn child(task: &Expression, symbols: &SymbolTable) -> Option<Child> {
+    match task {
+        &Expression::FnCall { ref target, .. } if timers::is_sleep(target, symbols) => Some(Child::Timer),
+        &Expression::FnCall { ref target, .. } => symbols.function(target)
+            .filter(|f| f.async && f.external == false)
+            .map(|f| Child::Task(f.c_name.clone())),
+        _ => None,
+    }
+}
+
+fn write_child_member(w: &mut Write, child: &Option<Child>, member: &str) -> Result<(), Error> {
+    match child {
+        &Some(Child::Task(ref task)) => writeln!(w, "            TaskState_{} {};", task, member),
+        &Some(Child::Timer) => writeln!(w, "            Timer {};", member),
+        &None => Ok(()),
+    }
+}
+
+fn child_layout(layouts: &mut Layouts, child: &Child) -> Layout {
+    match child {
+        &Child::Task(ref task) => layouts.task_state(task),
+        &Child::Timer => layouts.timer(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ast::nodes::*;
+    use super::super::super::testing::*;
+
+    fn sleep(ms: Expression) -> Statement {
+        Statement::Await(call("sleep_ms", vec![ms]))
+    }
+
+    fn program() -> Vec<TopLevelNode> {
+        vec![
+            async_fn("measure", vec![], ty("u32"), vec![Statement::Return(int(7))]),
+            async_fn("main", vec![], unit(), vec![
+                let_("a", ty("u32"), int(1)),
+                sleep(ident("a")),
+                let_await("b", ty("u32"), call("measure", vec![])),
+                sleep(ident("b")),
+                let_await("c", ty("u32"), call("measure", vec![])),
+                sleep(ident("c")),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn locals_not_live_across_an_await_stay_in_c() {
+        let output = compile(program());
+        assert!(output.source.contains("    uint32_t a = 1;\n"), "{}", output.source);
+        assert!(output.header.contains("uint32_t a;") == false, "{}", output.header);
+    }
+
+    #[test]
+    fn locals_assigned_in_another_block_are_stored() {
+        let output = compile(vec![
+            async_fn("main", vec![], unit(), vec![
+                let_("a", ty("u32"), int(1)),
+                sleep(ident("a")),
+                Statement::Assignment { target: ident("a"), expr: int(2) },
+                sleep(ident("a")),
+            ]),
+        ]);
+        assert!(output.header.contains("uint32_t a;"), "{}", output.header);
+        assert!(output.source.contains("    this->locals.a = 1;\n"), "{}", output.source);
+        assert!(output.source.contains("    this->locals.a = 2;\n"), "{}", output.source);
+    }
+
+    #[test]
+    fn locals_live_at_different_times_share_storage() {
+        let output = compile(program());
+        assert!(output.header.contains("        union {\n            uint32_t b;\n            uint32_t c;\n        };\n"), "{}", output.header);
+        assert!(output.memory_report.contains("locals                                4 bytes saved by overlay"), "{}", output.memory_report);
+    }
+}
@@ -2,43 +2,135 @@ extern crate petgraph;
 
 pub mod ast;
 pub mod cfg;
+pub mod check;
+pub mod codegen;
+pub mod module;
+pub mod options;
+#[cfg(test)]
+pub mod testing;
 
 use ast::{format::FormatAst, visitable::Visitable};
 
 fn main() {
+    use std::collections::HashMap;
     use std::io::Write;
 
-    let ast = build_test_ast();
-
-    let mut stdout = std::io::stdout();
-    let mut printer = FormatAst::new(&mut stdout);
+    let options = match options::Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            std::process::exit(2);
+        }
+    };
 
-    for node in ast.iter() {
-        node.visit_mut(&mut printer);
-        writeln!(std::io::stdout()).unwrap();
+    let modules = vec![module::Module::root(build_test_ast())];
+    for diagnostic in options.check(&modules) {
+        eprintln!("{}", diagnostic);
     }
+    let module_graph = match module::graph::ModuleGraph::build(&modules) {
+        Ok(module_graph) => module_graph,
+        Err(diagnostics) => {
+            for diagnostic in diagnostics.iter() {
+                eprintln!("{}", diagnostic);
+            }
+            std::process::exit(1);
+        }
+    };
 
-    for node in ast.iter() {
-        let result = match node {
-            &ast::nodes::TopLevelNode::FnDecl { ref name, ref body, .. } => Some((name, body)),
-            &ast::nodes::TopLevelNode::InterruptDecl { ref name, ref body, .. } => Some((name, body)),
-            _ => None,
-        };
+    let mut symbol_tables = HashMap::new();
+    let mut layouts = codegen::layout::Layouts::new(&options);
+    let mut ram = 0;
+    // the C files are written only once the program is known to fit its RAM
+    // budget, so that a failed build leaves none behind
+    let mut c_files = Vec::new();
 
-        match result {
-            Some((name, statements)) => {
-                let builder = cfg::builder::Builder::new(&name);
-                let mut cfg = builder.build(statements);
+    for module in module_graph.compile_order() {
+        let ast = &module.nodes;
 
-                let filepath = format!("cfg_dotfiles/{}.dot", name);
-                cfg.to_dotfile(filepath).unwrap();
+        let mut stdout = std::io::stdout();
+        let mut printer = FormatAst::new(&mut stdout);
 
-                cfg.tidy_graph();
-                let filepath = format!("cfg_dotfiles/{}.tidy.dot", name);
-                cfg.to_dotfile(filepath).unwrap();
-            },
-            _ => {},
+        for node in ast.iter() {
+            node.visit_mut(&mut printer);
+            writeln!(std::io::stdout()).unwrap();
+        }
+
+        let (mut symbols, mut diagnostics) = check::symbols::SymbolTable::build(module);
+        for import in module.imports() {
+            let imported = modules.iter().find(|m| m.path == import).unwrap();
+            diagnostics.extend(symbols.import(imported, &symbol_tables[&imported.path]));
+        }
+        diagnostics.extend(check::check_module(module, &mut symbols));
+        for diagnostic in diagnostics.iter() {
+            eprintln!("{}", diagnostic);
+        }
+        if diagnostics.iter().any(|d| d.is_error()) {
+            std::process::exit(1);
+        }
+
+        for node in ast.iter() {
+            let result = match node {
+                &ast::nodes::TopLevelNode::FnDecl { ref name, ref body, .. } => Some((name, body)),
+                &ast::nodes::TopLevelNode::InterruptDecl { ref name, ref body, .. } => Some((name, body)),
+                _ => None,
+            };
+
+            match result {
+                Some((name, statements)) => {
+                    let builder = cfg::builder::Builder::new(&name);
+                    let mut cfg = builder.build(statements);
+
+                    let filepath = format!("cfg_dotfiles/{}.dot", module.mangle(name));
+                    cfg.to_dotfile(filepath).unwrap();
+
+                    cfg.tidy_graph();
+                    let filepath = format!("cfg_dotfiles/{}.tidy.dot", module.mangle(name));
+                    cfg.to_dotfile(filepath).unwrap();
+                },
+                _ => {},
+            }
+        }
+
+        {
+            let generator = codegen::Generator::new(module, &symbols, &options);
+
+            let mut header = Vec::new();
+            generator.write_header(&mut header).unwrap();
+            c_files.push((generator.header_name(), header));
+
+            let mut source = Vec::new();
+            generator.write_source(&mut source).unwrap();
+            c_files.push((generator.source_name(), source));
+
+            ram += generator.write_memory_report(&mut std::io::stdout(), &mut layouts).unwrap();
         }
+
+        {
+            let (depths, diagnostics) = check::stack::analyze(ast, &symbols, &options);
+            for diagnostic in diagnostics.iter() {
+                eprintln!("{}", diagnostic);
+            }
+            check::stack::write_report(&mut std::io::stdout(), &module.name(), &depths).unwrap();
+        }
+
+        symbol_tables.insert(&module.path, symbols);
+    }
+
+    println!("the program needs {} bytes of RAM in total", ram);
+    if layouts.unknown.is_empty() == false {
+        let names = layouts.unknown.iter().map(|name| format!("`{}`", name)).collect::<Vec<_>>();
+        let diagnostic = check::Diagnostic::warning(format!("the size of {} is not known", names.join(", ")))
+            .with_help("the memory report counts them as empty, so the totals are lower bounds".into());
+        eprintln!("{}", diagnostic);
+    }
+    if let Some(diagnostic) = options.check_ram(ram) {
+        eprintln!("{}", diagnostic);
+        std::process::exit(1);
+    }
+
+    std::fs::create_dir_all("c_output").unwrap();
+    for (name, contents) in c_files {
+        std::fs::write(format!("c_output/{}", name), contents).unwrap();
     }
 }
 
@@ -46,6 +138,40 @@ fn build_test_ast() -> Vec<ast::nodes::TopLevelNode> {
     use ast::nodes::*;
 
     vec![
+        TopLevelNode::ExternFnDecl {
+            async: false,
+            name: "init_timerX".into(),
+            params: vec![
+                VarDecl {
+                    name: "period_ms".into(),
+                    type_ref: TypeRef::Named {
+                        name: "u32".into(),
+                        type_params: vec![],
+                    },
+                },
+            ],
+            returns: TypeRef::Tuple {
+                type_refs: vec![],
+            },
+            header: Some("hal/timer.h".into()),
+        },
+        TopLevelNode::ExternFnDecl {
+            async: true,
+            name: "println".into(),
+            params: vec![
+                VarDecl {
+                    name: "text".into(),
+                    type_ref: TypeRef::Named {
+                        name: "str".into(),
+                        type_params: vec![],
+                    },
+                },
+            ],
+            returns: TypeRef::Tuple {
+                type_refs: vec![],
+            },
+            header: Some("hal/uart.h".into()),
+        },
         TopLevelNode::GlobalDecl(VarDecl {
             name: "timerx_continuation".into(),
             type_ref: TypeRef::Named {
@@ -55,15 +181,31 @@ fn build_test_ast() -> Vec<ast::nodes::TopLevelNode> {
         }),
         TopLevelNode::FnDecl {
             async: false,
+            public: false,
+            priority: None,
+            generator: false,
             name: "init".into(),
             params: vec![],
             returns: TypeRef::Tuple {
                 type_refs: vec![],
             },
-            body: vec![],
+            body: vec![
+                Statement::Spawn {
+                    task: Expression::FnCall {
+                        target: Box::new(Expression::Identifier("periodic".into())),
+                        args: vec![
+                            Expression::Literal(Literal::Integer(500)),
+                        ],
+                    },
+                    priority: None,
+                },
+            ],
         },
         TopLevelNode::FnDecl {
             async: false,
+            public: false,
+            priority: None,
+            generator: false,
             name: "idle".into(),
             params: vec![],
             returns: TypeRef::Tuple {
@@ -73,15 +215,11 @@ fn build_test_ast() -> Vec<ast::nodes::TopLevelNode> {
         },
         TopLevelNode::FnDecl {
             async: false,
+            public: false,
+            priority: None,
+            generator: false,
             name: "delay".into(),
             params: vec![
-                VarDecl {
-                    name: "continuation".into(),
-                    type_ref: TypeRef::Named {
-                        name: "Continuation".into(),
-                        type_params: vec![],
-                    },
-                },
                 VarDecl {
                     name: "period_ms".into(),
                     type_ref: TypeRef::Named {
@@ -94,26 +232,30 @@ fn build_test_ast() -> Vec<ast::nodes::TopLevelNode> {
                 type_refs: vec![],
             },
             body: vec![
-                Statement::Assignment {
-                    target: Expression::Identifier("timerx_continuation".into()),
-                    expr: Expression::MemberOf {
-                        structure: Box::new(Expression::FnCall {
-                            target: Box::new(Expression::Identifier("task_current".into())),
-                            args: vec![],
-                        }),
-                        member: "continuesWith".into(),
-                    },
-                },
-                Statement::FnCall {
-                    target: Expression::Identifier("init_timerX".into()),
-                    args: vec![
-                        Expression::Identifier("period_ms".into()),
+                Statement::Suspend {
+                    continuation: "k".into(),
+                    body: vec![
+                        Statement::Atomic(vec![
+                            Statement::Assignment {
+                                target: Expression::Identifier("timerx_continuation".into()),
+                                expr: Expression::Identifier("k".into()),
+                            },
+                        ]),
+                        Statement::FnCall {
+                            target: Expression::Identifier("init_timerX".into()),
+                            args: vec![
+                                Expression::Identifier("period_ms".into()),
+                            ],
+                        },
                     ],
                 },
             ],
         },
         TopLevelNode::FnDecl {
             async: true,
+            public: true,
+            priority: None,
+            generator: false,
             name: "periodic".into(),
             params: vec![
                 VarDecl {
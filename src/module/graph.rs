@@ -0,0 +1,164 @@
+use petgraph::{Graph, algo::{tarjan_scc, toposort}, graph::NodeIndex};
+
+use std::collections::{HashMap, VecDeque};
+
+use super::Module;
+use super::super::ast::nodes::TopLevelNode;
+use super::super::check::Diagnostic;
+
+/// The import relationships between modules; an edge points from the
+/// importing module to the module it imports.
+pub struct ModuleGraph<'a> {
+    graph: Graph<&'a Module, ()>,
+}
+
+impl<'a> ModuleGraph<'a> {
+    pub fn build(modules: &'a [Module]) -> Result<Self, Vec<Diagnostic>> {
+        let mut graph = Graph::new();
+        let mut diagnostics = Vec::new();
+
+        let indices = modules.iter()
+            .map(|module| graph.add_node(module))
+            .collect::<Vec<_>>();
+
+        for (importer, module) in indices.iter().zip(modules.iter()) {
+            for import in module.imports() {
+                match modules.iter().position(|m| m.path == import) {
+                    Some(imported) => {
+                        graph.add_edge(*importer, indices[imported], ());
+                    }
+                    None => diagnostics.push(Diagnostic::error(format!(
+                        "module `{}` imports `{}`, which does not exist",
+                        module.name(), import.join("::"),
+                    ))),
+                }
+            }
+        }
+
+        diagnostics.extend(check_c_names(modules));
+
+        for component in tarjan_scc(&graph) {
+            let is_cycle = component.len() > 1 || graph.find_edge(component[0], component[0]).is_some();
+            if is_cycle {
+                diagnostics.push(Diagnostic::error(format!("import cycle: {}", cycle_path(&graph, &component))));
+            }
+        }
+
+        if diagnostics.is_empty() {
+            Ok(ModuleGraph { graph })
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    /// Modules ordered so that every module comes after the modules it imports.
+    pub fn compile_order(&self) -> Vec<&'a Module> {
+        let mut order = toposort(&self.graph, None)
+            .expect("module graph was checked for cycles");
+        order.reverse();
+        order.into_iter()
+            .map(|idx| self.graph[idx])
+            .collect()
+    }
+}
+
+/// Reports modules that would be generated into the same files, and items of
+/// different modules that would get the same name in C. Mangled names of
+/// different modules never clash, but those of the root module are left as
+/// they are and can clash with them.
+fn check_c_names(modules: &[Module]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut stems: HashMap<String, &Module> = HashMap::new();
+    let mut c_names: HashMap<String, (&Module, String)> = HashMap::new();
+
+    for module in modules.iter() {
+        let stem = module.file_stem();
+        if let Some(other) = stems.get(&stem) {
+            diagnostics.push(Diagnostic::error(format!(
+                "modules `{}` and `{}` would both be generated as `{}.c`", other.name(), module.name(), stem,
+            )));
+        }
+        stems.entry(stem).or_insert(module);
+
+        for node in module.nodes.iter() {
+            let name = match node {
+                &TopLevelNode::FnDecl { ref name, .. } | &TopLevelNode::ConstDecl { ref name, .. } => name,
+                _ => continue,
+            };
+            let qualified = if module.path.is_empty() {
+                name.clone()
+            } else {
+                format!("{}::{}", module.name(), name)
+            };
+            let c_name = module.mangle(name);
+            // Names declared twice in one module are reported with its symbols.
+            match c_names.get(&c_name) {
+                Some(&(other, _)) if ::std::ptr::eq(other, module) => {}
+                Some(&(_, ref other)) => diagnostics.push(Diagnostic::error(format!(
+                    "`{}` and `{}` would both be named `{}` in C", other, qualified, c_name,
+                )).with_help("rename one of them".into())),
+                None => {
+                    c_names.insert(c_name, (module, qualified));
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
+
+fn cycle_path(graph: &Graph<&Module, ()>, component: &[NodeIndex]) -> String {
+    let start = component[0];
+    let mut predecessors = HashMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(start);
+
+    while let Some(current) = queue.pop_front() {
+        for next in graph.neighbors(current).filter(|n| component.contains(n)) {
+            if next == start {
+                let mut path = vec![start];
+                let mut node = current;
+                while node != start {
+                    path.push(node);
+                    node = predecessors[&node];
+                }
+                path.push(start);
+
+                return path.iter()
+                    .rev()
+                    .map(|idx| graph[*idx].name())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+            }
+
+            if predecessors.contains_key(&next) == false {
+                predecessors.insert(next, current);
+                queue.push_back(next);
+            }
+        }
+    }
+
+    unreachable!("strongly connected component without a cycle")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ModuleGraph;
+    use super::super::Module;
+    use super::super::super::testing::*;
+
+    #[test]
+    fn clashing_c_names_are_reported() {
+        let modules = vec![
+            Module::root(vec![plain_fn("uart__read", vec![], unit(), vec![])]),
+            Module { path: vec!["uart".into()], nodes: vec![plain_fn("read", vec![], unit(), vec![])] },
+            Module { path: vec!["main".into()], nodes: vec![] },
+        ];
+        let messages = match ModuleGraph::build(&modules) {
+            Ok(..) => vec![],
+            Err(diagnostics) => diagnostics.into_iter().map(|d| d.message).collect(),
+        };
+        assert!(mentions(&messages, "`uart__read` and `uart::read` would both be named `uart__read` in C"), "{:?}", messages);
+        assert!(mentions(&messages, "modules `main` and `main` would both be generated as `main.c`"), "{:?}", messages);
+    }
+}
@@ -0,0 +1,91 @@
+
+pub mod graph;
+
+use super::ast::nodes::TopLevelNode;
+
+/// One module of the program, holding what one `.al` file declares. There is
+/// no parser yet, so modules are put together in memory; the root module has
+/// an empty path and its items keep their names in C, so the runtime can
+/// find `init` and `idle`.
+pub struct Module {
+    pub path: Vec<String>,
+    pub nodes: Vec<TopLevelNode>,
+}
+
+impl Module {
+    pub fn root(nodes: Vec<TopLevelNode>) -> Self {
+        Module {
+            path: Vec::new(),
+            nodes,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        if self.path.is_empty() {
+            "main".into()
+        } else {
+            self.path.join("::")
+        }
+    }
+
+    /// The stem of the `.c`/`.h` pair generated for this module.
+    pub fn file_stem(&self) -> String {
+        file_stem(&self.path)
+    }
+
+    /// The C name of an item declared in this module.
+    pub fn mangle(&self, item: &str) -> String {
+        mangle(&self.path, item)
+    }
+
+    pub fn imports(&self) -> Vec<&[String]> {
+        self.nodes.iter()
+            .filter_map(|node| match node {
+                &TopLevelNode::Import { ref path } => Some(&path[..]),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Joins the segments of a module path with `__`, escaping each `_` within a
+/// segment as `_u`, so that different paths never give the same name.
+pub fn file_stem(path: &[String]) -> String {
+    if path.is_empty() {
+        "main".into()
+    } else {
+        path.iter().map(|segment| escape(segment)).collect::<Vec<_>>().join("__")
+    }
+}
+
+/// The C name of an item of the module with the given path, escaped like
+/// the path so that `a_b::c` and `a::b_c` stay apart.
+pub fn mangle(path: &[String], item: &str) -> String {
+    if path.is_empty() {
+        item.into()
+    } else {
+        format!("{}__{}", file_stem(path), escape(item))
+    }
+}
+
+fn escape(name: &str) -> String {
+    name.replace("_", "_u")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_stem, mangle};
+
+    fn path(segments: &[&str]) -> Vec<String> {
+        segments.iter().map(|segment| segment.to_string()).collect()
+    }
+
+    #[test]
+    fn paths_with_underscores_are_mangled_apart() {
+        assert_eq!(mangle(&path(&["drivers", "uart"]), "read"), "drivers__uart__read");
+        assert!(mangle(&path(&["a_b"]), "c") != mangle(&path(&["a", "b_c"]), ""));
+        assert!(mangle(&path(&["a_b"]), "c") != mangle(&path(&["a"]), "b_c"));
+        assert!(file_stem(&path(&["a_b", "c"])) != file_stem(&path(&["a", "b_c"])));
+        assert_eq!(mangle(&[], "init"), "init");
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use super::check::Diagnostic;
+use super::ast::nodes::TopLevelNode;
+use super::module::Module;
+
+/// How an interrupt handler resumes the tasks it awaits on behalf of.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Dispatch {
+    /// The task runs inside the handler, until it next suspends.
+    Direct,
+    /// The task is put on the runtime's ready queue and runs from the main
+    /// loop once the handler has returned.
+    Deferred,
+}
+
+/// Settings given to the compiler on the command line.
+#[derive(Debug)]
+pub struct Options {
+    /// The dispatch chosen for individual interrupts, by name.
+    pub dispatch: HashMap<String, Dispatch>,
+    /// The dispatch of interrupts not named in `dispatch`.
+    pub default_dispatch: Dispatch,
+    /// The size of a pointer on the target, in bytes.
+    pub pointer_size: usize,
+    /// The largest alignment the target requires of any type, in bytes.
+    pub max_align: usize,
+    /// The most RAM, in bytes, that the globals and tasks of the program may
+    /// take up.
+    pub ram_budget: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            dispatch: HashMap::new(),
+            default_dispatch: Dispatch::Direct,
+            pointer_size: 4,
+            max_align: 4,
+            ram_budget: None,
+        }
+    }
+}
+
+impl Options {
+    /// Parses the command line arguments, not including the program name.
+    ///
+    /// `--dispatch <interrupt>=<direct|deferred>` chooses how an interrupt
+    /// resumes tasks, and `*` in place of the name sets the default.
+    /// `--pointer-size <bytes>` and `--align <bytes>` describe the target for
+    /// the memory report, and `--ram-budget <bytes>` fails the build when the
+    /// program needs more RAM.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut result = Options::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dispatch" => {
+                    let value = args.next().ok_or("`--dispatch` expects `<interrupt>=<direct|deferred>`")?;
+                    let (name, dispatch) = parse_dispatch(&value)?;
+                    if name == "*" {
+                        result.default_dispatch = dispatch;
+                    } else {
+                        result.dispatch.insert(name.into(), dispatch);
+                    }
+                }
+                "--pointer-size" => result.pointer_size = parse_size(&arg, args.next())?,
+                "--align" => result.max_align = parse_size(&arg, args.next())?,
+                "--ram-budget" => result.ram_budget = Some(parse_size(&arg, args.next())?),
+                _ => return Err(format!("unknown option `{}`", arg)),
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn dispatch(&self, interrupt: &str) -> Dispatch {
+        self.dispatch.get(interrupt).cloned().unwrap_or(self.default_dispatch)
+    }
+
+    /// Warns about options naming interrupts that no module declares.
+    pub fn check(&self, modules: &[Module]) -> Vec<Diagnostic> {
+        let mut names = self.dispatch.keys().collect::<Vec<_>>();
+        names.sort();
+
+        names.into_iter()
+            .filter(|name| {
+                modules.iter().flat_map(|module| module.nodes.iter()).any(|node| match node {
+                    &TopLevelNode::InterruptDecl { name: ref declared, .. } => declared == *name,
+                    _ => false,
+                }) == false
+            })
+            .map(|name| Diagnostic::warning(format!("`--dispatch` names `{}`, which is not an interrupt", name)))
+            .collect()
+    }
+
+    /// Fails the build if the program needs more than `--ram-budget` bytes.
+    pub fn check_ram(&self, ram: usize) -> Option<Diagnostic> {
+        match self.ram_budget {
+            Some(budget) if ram > budget => Some(
+                Diagnostic::error(format!("the program needs {} bytes of RAM, over the budget of {}", ram, budget))
+                    .with_help("spawn fewer tasks at once, keep less state across `await`s, or shrink globals and channel buffers".into())
+            ),
+            _ => None,
+        }
+    }
+}
+
+fn parse_dispatch(value: &str) -> Result<(&str, Dispatch), String> {
+    let mut parts = value.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let dispatch = match parts.next() {
+        Some("direct") => Dispatch::Direct,
+        Some("deferred") => Dispatch::Deferred,
+        _ => return Err(format!("invalid dispatch `{}`, expected `<interrupt>=<direct|deferred>`", value)),
+    };
+
+    if name.is_empty() {
+        return Err(format!("invalid dispatch `{}`, the interrupt name is missing", value));
+    }
+    Ok((name, dispatch))
+}
+
+fn parse_size(option: &str, value: Option<String>) -> Result<usize, String> {
+    let value = value.ok_or(format!("`{}` expects a number of bytes", option))?;
+    match value.parse::<usize>() {
+        Ok(0) => Err(format!("`{}` must be at least one byte", option)),
+        Ok(bytes) if option != "--ram-budget" && bytes.is_power_of_two() == false => {
+            Err(format!("`{}` must be a power of two, not {}", option, bytes))
+        }
+        Ok(bytes) => Ok(bytes),
+        Err(_) => Err(format!("invalid number of bytes `{}` for `{}`", value, option)),
+    }
+}
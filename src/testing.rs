@@ -0,0 +1,165 @@
+//! Shorthands for building small programs in tests, which have no parser to
+//! write them in source form, and for running them through the compiler.
+
+use super::ast::nodes::*;
+use super::check::{self, symbols::SymbolTable};
+use super::codegen::{Generator, layout::Layouts};
+use super::module::Module;
+use super::options::Options;
+
+pub fn ty(name: &str) -> TypeRef {
+    TypeRef::Named { name: name.into(), type_params: vec![] }
+}
+
+pub fn unit() -> TypeRef {
+    TypeRef::Tuple { type_refs: vec![] }
+}
+
+pub fn var(name: &str, type_ref: TypeRef) -> VarDecl {
+    VarDecl { name: name.into(), type_ref }
+}
+
+pub fn ident(name: &str) -> Expression {
+    Expression::Identifier(name.into())
+}
+
+pub fn int(value: i64) -> Expression {
+    Expression::Literal(Literal::Integer(value))
+}
+
+pub fn add(left: Expression, right: Expression) -> Expression {
+    Expression::BinOp { left: Box::new(left), operator: Operator::Add, right: Box::new(right) }
+}
+
+pub fn call(name: &str, args: Vec<Expression>) -> Expression {
+    Expression::FnCall { target: Box::new(ident(name)), args }
+}
+
+/// A call to an item of another module, e.g. `path_call(&["uart", "read"], ...)`.
+pub fn path_call(segments: &[&str], args: Vec<Expression>) -> Expression {
+    let segments = segments.iter().map(|segment| segment.to_string()).collect();
+    Expression::FnCall { target: Box::new(Expression::Path(segments)), args }
+}
+
+/// A call to a built-in method of a global, e.g. `bytes.send(1)`.
+pub fn method_call(global: &str, method: &str, args: Vec<Expression>) -> Expression {
+    Expression::FnCall {
+        target: Box::new(Expression::MemberOf { structure: Box::new(ident(global)), member: method.into() }),
+        args,
+    }
+}
+
+pub fn call_statement(name: &str, args: Vec<Expression>) -> Statement {
+    Statement::FnCall { target: ident(name), args }
+}
+
+pub fn let_(name: &str, type_ref: TypeRef, expr: Expression) -> Statement {
+    Statement::Let { var: var(name, type_ref), expr }
+}
+
+pub fn let_await(name: &str, type_ref: TypeRef, expr: Expression) -> Statement {
+    Statement::LetAwait { var: var(name, type_ref), expr }
+}
+
+pub fn async_fn(name: &str, params: Vec<VarDecl>, returns: TypeRef, body: Vec<Statement>) -> TopLevelNode {
+    TopLevelNode::FnDecl {
+        name: name.into(), params, returns, body, async: true, public: false, priority: None, generator: false,
+    }
+}
+
+pub fn plain_fn(name: &str, params: Vec<VarDecl>, returns: TypeRef, body: Vec<Statement>) -> TopLevelNode {
+    TopLevelNode::FnDecl {
+        name: name.into(), params, returns, body, async: false, public: false, priority: None, generator: false,
+    }
+}
+
+/// Marks a function built by `async_fn` or `plain_fn` as `pub`.
+pub fn public(mut node: TopLevelNode) -> TopLevelNode {
+    if let TopLevelNode::FnDecl { ref mut public, .. } = node {
+        *public = true;
+    }
+    node
+}
+
+pub fn extern_fn(name: &str, params: Vec<VarDecl>, returns: TypeRef, async: bool) -> TopLevelNode {
+    TopLevelNode::ExternFnDecl { name: name.into(), params, returns, async, header: None }
+}
+
+/// Runs every check on a program of one module, returning the messages of
+/// the errors and warnings it produces.
+pub fn diagnostics(nodes: Vec<TopLevelNode>) -> (Vec<String>, Vec<String>) {
+    let module = Module::root(nodes);
+    let (mut symbols, mut diagnostics) = SymbolTable::build(&module);
+    diagnostics.extend(check::check_module(&module, &mut symbols));
+
+    let (errors, warnings): (Vec<_>, Vec<_>) = diagnostics.into_iter().partition(|d| d.is_error());
+    (errors.into_iter().map(|d| d.message).collect(), warnings.into_iter().map(|d| d.message).collect())
+}
+
+pub fn errors(nodes: Vec<TopLevelNode>) -> Vec<String> {
+    diagnostics(nodes).0
+}
+
+pub fn warnings(nodes: Vec<TopLevelNode>) -> Vec<String> {
+    diagnostics(nodes).1
+}
+
+/// Whether any of `messages` contains `part`.
+pub fn mentions(messages: &[String], part: &str) -> bool {
+    messages.iter().any(|message| message.contains(part))
+}
+
+/// The C header and source generated for a program of one module, which
+/// must pass every check.
+pub struct Output {
+    pub header: String,
+    pub source: String,
+    pub memory_report: String,
+    /// The bytes of RAM the memory report counts.
+    pub ram: usize,
+}
+
+impl Output {
+    /// The definition of the generated C function with the given name.
+    pub fn function(&self, name: &str) -> String {
+        let signatures = [format!(" {}(", name), format!("*{}(", name)];
+        let defines = |line: &str| line.ends_with("{") && signatures.iter().any(|signature| line.contains(signature.as_str()));
+        let mut lines = Vec::new();
+        for line in self.source.lines().skip_while(|line| defines(line) == false) {
+            lines.push(line);
+            if line == "}" {
+                break;
+            }
+        }
+        assert!(lines.is_empty() == false, "no function `{}` in:\n{}", name, self.source);
+        lines.join("\n")
+    }
+}
+
+pub fn compile(nodes: Vec<TopLevelNode>) -> Output {
+    compile_with(nodes, &Options::default())
+}
+
+pub fn compile_with(nodes: Vec<TopLevelNode>, options: &Options) -> Output {
+    let module = Module::root(nodes);
+    let (mut symbols, mut diagnostics) = SymbolTable::build(&module);
+    diagnostics.extend(check::check_module(&module, &mut symbols));
+    let errors = diagnostics.iter().filter(|d| d.is_error()).map(|d| d.to_string()).collect::<Vec<_>>();
+    assert!(errors.is_empty(), "the program does not check:\n{}", errors.join("\n"));
+
+    let generator = Generator::new(&module, &symbols, options);
+    let mut header = Vec::new();
+    let mut source = Vec::new();
+    let mut memory_report = Vec::new();
+    generator.write_header(&mut header).unwrap();
+    generator.write_source(&mut source).unwrap();
+    let mut layouts = Layouts::new(options);
+    let ram = generator.write_memory_report(&mut memory_report, &mut layouts).unwrap();
+
+    Output {
+        header: String::from_utf8(header).unwrap(),
+        source: String::from_utf8(source).unwrap(),
+        memory_report: String::from_utf8(memory_report).unwrap(),
+        ram,
+    }
+}